@@ -0,0 +1,498 @@
+use crate::{now_ms, recv};
+use axum::extract::{Path, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use xb_types::{
+    Balance, Direction, Exchange, ExchangeOrderExecutor, OrderRequest, OrderUpdate, OrderbookState,
+    PauseSwitch, PendingOrder, Strategy,
+};
+
+// The number of submitted orders kept for the orders endpoint
+const RECENT_ORDERS: usize = 100;
+// The number of price levels shown on each side of the books
+const BOOK_DEPTH: usize = 20;
+
+// A small HTTP API for watching and controlling the service while it runs. Every request must
+// carry the configured bearer token.
+pub struct Admin {
+    token: String,
+    order_sender: Sender<Arc<OrderRequest>>,
+    processors: BTreeMap<&'static str, PauseSwitch>,
+    exchanges: BTreeMap<Exchange, Box<dyn ExchangeOrderExecutor>>,
+    state: Mutex<AdminState>,
+}
+
+// Built up from the orderbook, order request and order update channels
+#[derive(Default)]
+struct AdminState {
+    books: HashMap<Exchange, Arc<OrderbookState>>,
+    // Newest last
+    recent_orders: VecDeque<OrderRecord>,
+    positions: HashMap<Strategy, Position>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct OrderRecord {
+    timestamp_ms: u64,
+    strategy: Strategy,
+    id: u64,
+    exchange: Exchange,
+    direction: Direction,
+    // None for market orders
+    price: Option<Decimal>,
+    amount: Decimal,
+    filled_amount: Decimal,
+    filled_value: Decimal,
+    is_open: bool,
+}
+
+// The fills for a strategy since startup
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+struct Position {
+    bought_amount: Decimal,
+    bought_value: Decimal,
+    sold_amount: Decimal,
+    sold_value: Decimal,
+}
+
+#[derive(Serialize)]
+struct BookSummary {
+    timestamp_ms: u64,
+    // The book can't be trusted until the exchange sends the next snapshot
+    invalidated: bool,
+    // Best first
+    bids: Vec<(Decimal, Decimal)>,
+    asks: Vec<(Decimal, Decimal)>,
+}
+
+#[derive(Serialize)]
+struct ProcessorStatus {
+    name: &'static str,
+    paused: bool,
+}
+
+#[derive(Serialize)]
+struct StrategyPnl {
+    #[serde(flatten)]
+    position: Position,
+    // The net amount bought, valued at the mid price across all exchanges
+    pnl: Option<Decimal>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ExchangeBalances {
+    Balances(BTreeMap<String, Balance>),
+    Error { error: String },
+}
+
+impl Admin {
+    pub fn new(token: String, order_sender: Sender<Arc<OrderRequest>>) -> Admin {
+        Admin {
+            token,
+            order_sender,
+            processors: BTreeMap::new(),
+            exchanges: BTreeMap::new(),
+            state: Mutex::new(AdminState::default()),
+        }
+    }
+
+    // Allows the processor to be paused and resumed by name, and pauses it on the kill switch
+    pub fn with_processor(mut self, name: &'static str, pause: PauseSwitch) -> Self {
+        self.processors.insert(name, pause);
+        self
+    }
+
+    // Used to look up balances, separately from the clients used by the order executor
+    pub fn with_exchange(
+        mut self,
+        exchange: Exchange,
+        client: Box<dyn ExchangeOrderExecutor>,
+    ) -> Self {
+        self.exchanges.insert(exchange, client);
+        self
+    }
+
+    pub fn run(
+        self,
+        bind: SocketAddr,
+        orderbook_updates: Receiver<Arc<OrderbookState>>,
+        order_requests: Receiver<Arc<OrderRequest>>,
+        order_updates: Receiver<Arc<OrderUpdate>>,
+        cancellation_token: CancellationToken,
+    ) -> JoinHandle<()> {
+        let admin = Arc::new(self);
+        tokio::spawn(async move {
+            let listener = match TcpListener::bind(bind).await {
+                Ok(listener) => listener,
+                Err(error) => {
+                    error!("Admin: Failed to bind to {bind}: {error}");
+                    return;
+                }
+            };
+            info!("Admin: Listening on {bind}");
+
+            let router = Router::new()
+                .route("/books", get(books))
+                .route("/processors", get(processors))
+                .route("/processors/:name/pause", post(pause))
+                .route("/processors/:name/resume", post(resume))
+                .route("/orders", get(orders))
+                .route("/orders/cancel-all", post(cancel_all))
+                .route("/balances", get(balances))
+                .route("/pnl", get(pnl))
+                .route("/kill", post(kill))
+                .layer(middleware::from_fn_with_state(admin.clone(), authorize))
+                .with_state(admin.clone());
+
+            let server = axum::serve(listener, router)
+                .with_graceful_shutdown(cancellation_token.clone().cancelled_owned());
+
+            select! {
+                result = server => {
+                    if let Err(error) = result {
+                        error!("Admin: Server failed: {error}");
+                    }
+                }
+                _ = admin.track(orderbook_updates, order_requests, order_updates, cancellation_token) => {}
+            }
+
+            info!("Admin: Stopped");
+        })
+    }
+
+    async fn track(
+        &self,
+        mut orderbook_updates: Receiver<Arc<OrderbookState>>,
+        mut order_requests: Receiver<Arc<OrderRequest>>,
+        mut order_updates: Receiver<Arc<OrderUpdate>>,
+        cancellation_token: CancellationToken,
+    ) {
+        loop {
+            select! {
+                book = recv(&mut orderbook_updates) => {
+                    self.state.lock().unwrap().books.insert(book.exchange, book);
+                }
+                request = recv(&mut order_requests) => {
+                    self.state.lock().unwrap().on_order_request(&request, now_ms());
+                }
+                update = recv(&mut order_updates) => {
+                    self.state.lock().unwrap().on_order_update(&update);
+                }
+                _ = cancellation_token.cancelled() => break,
+            }
+        }
+    }
+
+    fn cancel_all_orders(&self) {
+        if self
+            .order_sender
+            .send(Arc::new(OrderRequest::CancelAll))
+            .is_err()
+        {
+            warn!("Admin: No order executor to cancel orders");
+        }
+    }
+}
+
+impl AdminState {
+    fn on_order_request(&mut self, request: &OrderRequest, timestamp_ms: u64) {
+        match request {
+            OrderRequest::Submit(order) => {
+                if self.recent_orders.len() == RECENT_ORDERS {
+                    self.recent_orders.pop_front();
+                }
+                self.recent_orders.push_back(OrderRecord {
+                    timestamp_ms,
+                    strategy: order.strategy(),
+                    id: order.id(),
+                    exchange: order.exchange(),
+                    direction: order.direction(),
+                    price: match order {
+                        PendingOrder::Limit(o) => Some(o.price),
+                        PendingOrder::Market(_) => None,
+                    },
+                    amount: order.amount(),
+                    filled_amount: Decimal::ZERO,
+                    filled_value: Decimal::ZERO,
+                    is_open: true,
+                });
+            }
+            OrderRequest::Replace(replace) => {
+                if let Some(record) = self.find_order(replace.strategy, replace.id) {
+                    record.price = Some(replace.price);
+                    record.amount = replace.amount;
+                }
+            }
+            OrderRequest::Cancel(_) | OrderRequest::CancelAll => {}
+        }
+    }
+
+    fn on_order_update(&mut self, update: &OrderUpdate) {
+        if let Some(record) = self.find_order(update.strategy, update.id) {
+            record.filled_amount += update.filled_amount;
+            record.filled_value += update.filled_value;
+            record.is_open = update.is_open;
+        }
+
+        let position = self.positions.entry(update.strategy).or_default();
+        if update.direction.is_buy() {
+            position.bought_amount += update.filled_amount;
+            position.bought_value += update.filled_value;
+        } else {
+            position.sold_amount += update.filled_amount;
+            position.sold_value += update.filled_value;
+        }
+    }
+
+    fn find_order(&mut self, strategy: Strategy, id: u64) -> Option<&mut OrderRecord> {
+        self.recent_orders
+            .iter_mut()
+            .rev()
+            .find(|o| o.strategy == strategy && o.id == id)
+    }
+
+    // The average of the mid prices across exchanges with both sides of the book
+    fn mid_price(&self) -> Option<Decimal> {
+        let mids: Vec<_> = self.books.values().filter_map(|b| b.mid_price()).collect();
+        if mids.is_empty() {
+            return None;
+        }
+        Some(mids.iter().sum::<Decimal>() / Decimal::from(mids.len()))
+    }
+
+    fn pnl(&self) -> BTreeMap<Strategy, StrategyPnl> {
+        let mid_price = self.mid_price();
+        self.positions
+            .iter()
+            .map(|(strategy, p)| {
+                let net_amount = p.bought_amount - p.sold_amount;
+                let pnl = mid_price.map(|m| p.sold_value - p.bought_value + net_amount * m);
+                let strategy_pnl = StrategyPnl {
+                    position: p.clone(),
+                    pnl,
+                };
+                (*strategy, strategy_pnl)
+            })
+            .collect()
+    }
+}
+
+async fn authorize(State(admin): State<Arc<Admin>>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|t| t == admin.token);
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+async fn books(State(admin): State<Arc<Admin>>) -> Json<BTreeMap<Exchange, BookSummary>> {
+    let state = admin.state.lock().unwrap();
+    Json(
+        state
+            .books
+            .iter()
+            .map(|(exchange, book)| {
+                let summary = BookSummary {
+                    timestamp_ms: book.timestamp_ms,
+                    invalidated: book.invalidated,
+                    bids: book
+                        .bids
+                        .iter()
+                        .rev()
+                        .take(BOOK_DEPTH)
+                        .map(|(p, a)| (*p, *a))
+                        .collect(),
+                    asks: book
+                        .asks
+                        .iter()
+                        .take(BOOK_DEPTH)
+                        .map(|(p, a)| (*p, *a))
+                        .collect(),
+                };
+                (*exchange, summary)
+            })
+            .collect(),
+    )
+}
+
+async fn processors(State(admin): State<Arc<Admin>>) -> Json<Vec<ProcessorStatus>> {
+    Json(
+        admin
+            .processors
+            .iter()
+            .map(|(name, pause)| ProcessorStatus {
+                name,
+                paused: pause.is_paused(),
+            })
+            .collect(),
+    )
+}
+
+async fn pause(State(admin): State<Arc<Admin>>, Path(name): Path<String>) -> StatusCode {
+    match admin.processors.get(name.as_str()) {
+        Some(pause) => {
+            info!("Admin: Pausing {name}");
+            pause.pause();
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn resume(State(admin): State<Arc<Admin>>, Path(name): Path<String>) -> StatusCode {
+    match admin.processors.get(name.as_str()) {
+        Some(pause) => {
+            info!("Admin: Resuming {name}");
+            pause.resume();
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+// Newest first
+async fn orders(State(admin): State<Arc<Admin>>) -> Json<Vec<OrderRecord>> {
+    let state = admin.state.lock().unwrap();
+    Json(state.recent_orders.iter().rev().cloned().collect())
+}
+
+async fn cancel_all(State(admin): State<Arc<Admin>>) -> StatusCode {
+    info!("Admin: Cancelling all orders");
+    admin.cancel_all_orders();
+    StatusCode::ACCEPTED
+}
+
+async fn balances(State(admin): State<Arc<Admin>>) -> Json<BTreeMap<Exchange, ExchangeBalances>> {
+    let mut balances = BTreeMap::new();
+    for (exchange, client) in &admin.exchanges {
+        let result = match client.get_balances().await {
+            Ok(b) => ExchangeBalances::Balances(b.into_iter().collect()),
+            Err(error) => ExchangeBalances::Error { error },
+        };
+        balances.insert(*exchange, result);
+    }
+    Json(balances)
+}
+
+async fn pnl(State(admin): State<Arc<Admin>>) -> Json<BTreeMap<Strategy, StrategyPnl>> {
+    Json(admin.state.lock().unwrap().pnl())
+}
+
+// Stops all trading, pausing every processor and the order executor before cancelling whatever is
+// left on the books. Each needs resuming individually afterwards.
+async fn kill(State(admin): State<Arc<Admin>>) -> StatusCode {
+    warn!("Admin: Kill switch triggered");
+    for pause in admin.processors.values() {
+        pause.pause();
+    }
+    admin.cancel_all_orders();
+    StatusCode::ACCEPTED
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use xb_types::{PendingLimitOrder, ReplaceOrder, TimeInForce};
+
+    const LBANK: Exchange = Exchange::new("lbank");
+
+    #[test]
+    fn tracks_orders_and_pnl() {
+        let mut state = AdminState::default();
+        state.books.insert(
+            LBANK,
+            Arc::new(OrderbookState {
+                exchange: LBANK,
+                timestamp_ms: 0,
+                asks: BTreeMap::from([(dec("0.32"), dec("1000"))]),
+                bids: BTreeMap::from([(dec("0.30"), dec("1000"))]),
+                invalidated: false,
+                trace: Default::default(),
+            }),
+        );
+
+        state.on_order_request(&submit(1, Direction::Sell, "0.33"), 0);
+        state.on_order_request(
+            &OrderRequest::Replace(ReplaceOrder {
+                strategy: Strategy::Cashout,
+                id: 1,
+                amount: dec("800"),
+                price: dec("0.32"),
+            }),
+            0,
+        );
+        state.on_order_update(&update(1, Direction::Sell, "500", "160", true));
+        state.on_order_request(&submit(2, Direction::Buy, "0.30"), 0);
+        state.on_order_update(&update(2, Direction::Buy, "200", "60", false));
+
+        let first = &state.recent_orders[0];
+        assert_eq!(first.price, Some(dec("0.32")));
+        assert_eq!(first.amount, dec("800"));
+        assert_eq!(first.filled_amount, dec("500"));
+        assert!(first.is_open);
+        assert!(!state.recent_orders[1].is_open);
+
+        // Sold 500 for 160, bought 200 for 60, leaving 300 short valued at the mid of 0.31
+        let pnl = state.pnl();
+        assert_eq!(pnl[&Strategy::Cashout].pnl, Some(dec("7")));
+    }
+
+    fn submit(id: u64, direction: Direction, price: &str) -> OrderRequest {
+        OrderRequest::Submit(PendingOrder::Limit(PendingLimitOrder {
+            id,
+            strategy: Strategy::Cashout,
+            exchange: LBANK,
+            direction,
+            amount: dec("1000"),
+            price: dec(price),
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: false,
+            trace: Default::default(),
+        }))
+    }
+
+    fn update(
+        id: u64,
+        direction: Direction,
+        filled_amount: &str,
+        filled_value: &str,
+        is_open: bool,
+    ) -> OrderUpdate {
+        OrderUpdate {
+            strategy: Strategy::Cashout,
+            id,
+            exchange: LBANK,
+            direction,
+            filled_amount: dec(filled_amount),
+            filled_value: dec(filled_value),
+            is_open,
+            fills_unknown: false,
+        }
+    }
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+}
@@ -0,0 +1,528 @@
+use crate::config::AlertsConfig;
+use crate::{now_ms, recv};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::broadcast::Receiver;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+use xb_types::{Alert, Exchange, OrderUpdate, OrderbookState, Strategy};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const ONE_DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+// The JSON expected by the chat service the webhook posts to
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    #[default]
+    Slack,
+    Discord,
+    // The bot token is part of the webhook url, eg. https://api.telegram.org/bot<token>/sendMessage
+    Telegram,
+}
+
+// Posts alerts to a webhook. Alongside the alerts the components publish, the rules watch the
+// books for exchanges going stale and the fills for large arbs and the daily PnL. Repeats of the
+// same alert are held back for a while to avoid flooding the channel.
+pub struct Alerting {
+    webhook: Webhook,
+    state: AlertState,
+}
+
+struct Webhook {
+    client: Client,
+    url: String,
+    format: WebhookFormat,
+    telegram_chat_id: Option<String>,
+}
+
+struct AlertState {
+    muted: HashSet<String>,
+    repeat_after: Duration,
+    stale_after: Duration,
+    large_arb_value: Option<Decimal>,
+    daily_pnl_below: Option<Decimal>,
+    daily_pnl_above: Option<Decimal>,
+    last_book_update: HashMap<Exchange, Instant>,
+    stale: HashSet<Exchange>,
+    books: HashMap<Exchange, Arc<OrderbookState>>,
+    daily: DailyPosition,
+    pnl_below_alerted: bool,
+    pnl_above_alerted: bool,
+    // When each alert was last sent and how many repeats have been held back since
+    sent: HashMap<String, (Instant, u64)>,
+}
+
+// The fills across all strategies since midnight UTC
+#[derive(Debug, Default)]
+struct DailyPosition {
+    // Days since the unix epoch
+    day: u64,
+    bought_amount: Decimal,
+    bought_value: Decimal,
+    sold_amount: Decimal,
+    sold_value: Decimal,
+}
+
+impl Alerting {
+    // The exchanges are expected to publish books from the start, so they're alerted on if they
+    // never do
+    pub fn new(config: &AlertsConfig, exchanges: &[Exchange]) -> Alerting {
+        let now = Instant::now();
+        Alerting {
+            webhook: Webhook {
+                client: Client::builder()
+                    .timeout(WEBHOOK_TIMEOUT)
+                    .build()
+                    .unwrap_or_default(),
+                url: config.webhook_url.clone().unwrap_or_default(),
+                format: config.format,
+                telegram_chat_id: config.telegram_chat_id.clone(),
+            },
+            state: AlertState {
+                muted: config.muted.iter().cloned().collect(),
+                repeat_after: Duration::from_secs(config.repeat_after_secs),
+                stale_after: Duration::from_secs(config.stale_after_secs),
+                large_arb_value: config.large_arb_value,
+                daily_pnl_below: config.daily_pnl_below,
+                daily_pnl_above: config.daily_pnl_above,
+                last_book_update: exchanges.iter().map(|e| (*e, now)).collect(),
+                stale: HashSet::new(),
+                books: HashMap::new(),
+                daily: DailyPosition::default(),
+                pnl_below_alerted: false,
+                pnl_above_alerted: false,
+                sent: HashMap::new(),
+            },
+        }
+    }
+
+    pub fn run(
+        self,
+        alerts: Receiver<Arc<Alert>>,
+        orderbook_updates: Receiver<Arc<OrderbookState>>,
+        order_updates: Receiver<Arc<OrderUpdate>>,
+        cancellation_token: CancellationToken,
+    ) -> JoinHandle<()> {
+        tokio::spawn(self.run_async(alerts, orderbook_updates, order_updates, cancellation_token))
+    }
+
+    async fn run_async(
+        mut self,
+        mut alerts: Receiver<Arc<Alert>>,
+        mut orderbook_updates: Receiver<Arc<OrderbookState>>,
+        mut order_updates: Receiver<Arc<OrderUpdate>>,
+        cancellation_token: CancellationToken,
+    ) {
+        info!("Alerting started");
+
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+        loop {
+            let triggered = select! {
+                alert = recv(&mut alerts) => vec![(*alert).clone()],
+                book = recv(&mut orderbook_updates) => {
+                    self.state.on_orderbook_update(book, Instant::now())
+                }
+                update = recv(&mut order_updates) => {
+                    self.state.on_order_update(&update, now_ms())
+                }
+                _ = interval.tick() => self.state.check(Instant::now(), now_ms()),
+                _ = cancellation_token.cancelled() => break,
+            };
+            self.notify(triggered).await;
+        }
+
+        // Components stopping on shutdown may have published alerts which haven't been seen yet
+        let mut remaining = Vec::new();
+        while let Ok(alert) = alerts.try_recv() {
+            remaining.push((*alert).clone());
+        }
+        self.notify(remaining).await;
+
+        info!("Alerting stopped");
+    }
+
+    async fn notify(&mut self, alerts: Vec<Alert>) {
+        for alert in alerts {
+            let Some(message) = self.state.message(&alert, Instant::now()) else {
+                continue;
+            };
+            info!("Alert: {message}");
+            if let Err(error) = self.webhook.send(&message).await {
+                error!("Failed to send alert: {error}");
+            }
+        }
+    }
+}
+
+impl Webhook {
+    async fn send(&self, message: &str) -> Result<(), String> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(self.payload(message).to_string())
+            .send()
+            .await
+            .map_err(|e| format!("Webhook request failed: {e}"))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let content = response.text().await.unwrap_or_default();
+            return Err(format!("Webhook returned {status}: {content}"));
+        }
+        Ok(())
+    }
+
+    fn payload(&self, message: &str) -> Value {
+        match self.format {
+            WebhookFormat::Slack => json!({ "text": message }),
+            WebhookFormat::Discord => json!({ "content": message }),
+            WebhookFormat::Telegram => json!({
+                "chat_id": self.telegram_chat_id,
+                "text": message,
+            }),
+        }
+    }
+}
+
+impl AlertState {
+    fn on_orderbook_update(&mut self, book: Arc<OrderbookState>, now: Instant) -> Vec<Alert> {
+        let exchange = book.exchange;
+        // A gap in the book doesn't count as an update, so it turns stale if no snapshot follows
+        if book.invalidated {
+            self.books.remove(&exchange);
+            return Vec::new();
+        }
+        self.last_book_update.insert(exchange, now);
+        self.books.insert(exchange, book);
+
+        if self.stale.remove(&exchange) {
+            vec![Alert::ExchangeRecovered { exchange }]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn on_order_update(&mut self, update: &OrderUpdate, now_ms: u64) -> Vec<Alert> {
+        if update.filled_amount.is_zero() {
+            return Vec::new();
+        }
+
+        let mut alerts = Vec::new();
+        if let Some(large_arb_value) = self.large_arb_value {
+            if update.strategy == Strategy::ArbFinder && update.filled_value >= large_arb_value {
+                alerts.push(Alert::LargeArbFill {
+                    exchange: update.exchange,
+                    direction: update.direction,
+                    amount: update.filled_amount,
+                    value: update.filled_value,
+                });
+            }
+        }
+
+        self.roll_over(now_ms);
+        if update.direction.is_buy() {
+            self.daily.bought_amount += update.filled_amount;
+            self.daily.bought_value += update.filled_value;
+        } else {
+            self.daily.sold_amount += update.filled_amount;
+            self.daily.sold_value += update.filled_value;
+        }
+        alerts.extend(self.check_pnl());
+        alerts
+    }
+
+    // Runs the rules which depend on time passing rather than on any update
+    fn check(&mut self, now: Instant, now_ms: u64) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for (exchange, last_update) in &self.last_book_update {
+            let age = now.saturating_duration_since(*last_update);
+            if age >= self.stale_after && self.stale.insert(*exchange) {
+                alerts.push(Alert::ExchangeStale {
+                    exchange: *exchange,
+                    age,
+                });
+            }
+        }
+
+        self.roll_over(now_ms);
+        alerts.extend(self.check_pnl());
+        alerts
+    }
+
+    // Alerts once each time the PnL crosses a threshold, and again only after crossing back
+    fn check_pnl(&mut self) -> Vec<Alert> {
+        let Some(pnl) = self.daily_pnl() else {
+            return Vec::new();
+        };
+
+        let mut alerts = Vec::new();
+        if let Some(threshold) = self.daily_pnl_below {
+            let below = pnl < threshold;
+            if below && !self.pnl_below_alerted {
+                alerts.push(Alert::DailyPnlBelow { pnl, threshold });
+            }
+            self.pnl_below_alerted = below;
+        }
+        if let Some(threshold) = self.daily_pnl_above {
+            let above = pnl > threshold;
+            if above && !self.pnl_above_alerted {
+                alerts.push(Alert::DailyPnlAbove { pnl, threshold });
+            }
+            self.pnl_above_alerted = above;
+        }
+        alerts
+    }
+
+    // The net amount bought today is valued at the average mid price across the exchanges
+    fn daily_pnl(&self) -> Option<Decimal> {
+        let mids: Vec<_> = self.books.values().filter_map(|b| b.mid_price()).collect();
+        if mids.is_empty() {
+            return None;
+        }
+        let mid_price = mids.iter().sum::<Decimal>() / Decimal::from(mids.len());
+        let daily = &self.daily;
+        let net_amount = daily.bought_amount - daily.sold_amount;
+        Some(daily.sold_value - daily.bought_value + net_amount * mid_price)
+    }
+
+    fn roll_over(&mut self, now_ms: u64) {
+        let today = now_ms / ONE_DAY_MS;
+        if self.daily.day != today {
+            self.daily = DailyPosition {
+                day: today,
+                ..Default::default()
+            };
+        }
+    }
+
+    // The message to send for the alert, or None if it's muted or a repeat which is held back
+    fn message(&mut self, alert: &Alert, now: Instant) -> Option<String> {
+        if self.muted.contains(alert.kind()) {
+            return None;
+        }
+
+        match self.sent.get_mut(&alert.key()) {
+            Some((last_sent, held_back)) if now.duration_since(*last_sent) < self.repeat_after => {
+                *held_back += 1;
+                None
+            }
+            Some((last_sent, held_back)) => {
+                let message = match *held_back {
+                    0 => alert.to_string(),
+                    n => format!("{alert} ({n} repeats held back)"),
+                };
+                *last_sent = now;
+                *held_back = 0;
+                Some(message)
+            }
+            None => {
+                self.sent.insert(alert.key(), (now, 0));
+                Some(alert.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+    use xb_types::{Direction, LatencyTrace};
+
+    const BITRUE: Exchange = Exchange::new("bitrue");
+    const LBANK: Exchange = Exchange::new("lbank");
+
+    #[test]
+    fn holds_back_repeats() {
+        let mut state = state();
+        let alert = rejected("Order executor paused");
+        let start = Instant::now();
+
+        assert!(state.message(&alert, start).is_some());
+        assert!(state
+            .message(&alert, start + Duration::from_secs(1))
+            .is_none());
+        assert!(state
+            .message(&alert, start + Duration::from_secs(2))
+            .is_none());
+        assert_eq!(
+            state.message(&alert, start + Duration::from_secs(60)),
+            Some(format!("{alert} (2 repeats held back)"))
+        );
+
+        // Alerts for a different exchange or strategy aren't repeats
+        let other = Alert::OrderRejected {
+            exchange: BITRUE,
+            strategy: Strategy::Cashout,
+            reason: "Order executor paused".to_string(),
+        };
+        assert!(state
+            .message(&other, start + Duration::from_secs(61))
+            .is_some());
+
+        state.muted.insert("order_rejected".to_string());
+        assert!(state
+            .message(&other, start + Duration::from_secs(200))
+            .is_none());
+    }
+
+    #[test]
+    fn alerts_on_stale_and_recovered_books() {
+        let mut state = state();
+        let start = Instant::now();
+        state.last_book_update.insert(LBANK, start);
+
+        assert!(state.check(start + Duration::from_secs(10), 0).is_empty());
+        // A gap in the book isn't a sign of life
+        assert!(state
+            .on_orderbook_update(
+                Arc::new(OrderbookState::invalidated(LBANK, 0)),
+                start + Duration::from_secs(20)
+            )
+            .is_empty());
+        assert_eq!(
+            state.check(start + Duration::from_secs(30), 0),
+            vec![Alert::ExchangeStale {
+                exchange: LBANK,
+                age: Duration::from_secs(30)
+            }]
+        );
+        // Only once until it recovers
+        assert!(state.check(start + Duration::from_secs(40), 0).is_empty());
+        assert_eq!(
+            state.on_orderbook_update(book(LBANK), start + Duration::from_secs(41)),
+            vec![Alert::ExchangeRecovered { exchange: LBANK }]
+        );
+    }
+
+    #[test]
+    fn alerts_on_large_arbs_and_daily_pnl() {
+        let mut state = state();
+        state.large_arb_value = Some(dec("100"));
+        state.daily_pnl_below = Some(dec("-10"));
+        state.on_orderbook_update(book(LBANK), Instant::now());
+
+        // Bought 1000 at 0.52 with the mid at 0.50 is a loss of 20
+        let alerts = state.on_order_update(&fill(Strategy::ArbFinder, "1000", "520"), 0);
+        assert_eq!(
+            alerts,
+            vec![
+                Alert::LargeArbFill {
+                    exchange: LBANK,
+                    direction: Direction::Buy,
+                    amount: dec("1000"),
+                    value: dec("520"),
+                },
+                Alert::DailyPnlBelow {
+                    pnl: dec("-20"),
+                    threshold: dec("-10"),
+                },
+            ]
+        );
+
+        // Still below, so no repeat, and small fills aren't large arbs
+        let alerts = state.on_order_update(&fill(Strategy::ArbFinder, "10", "5.1"), 0);
+        assert!(alerts.is_empty());
+
+        // A new day starts afresh
+        assert!(state.check(Instant::now(), ONE_DAY_MS).is_empty());
+        assert_eq!(state.daily_pnl(), Some(Decimal::ZERO));
+    }
+
+    #[tokio::test]
+    async fn posts_to_webhook() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let router =
+            Router::new()
+                .route(
+                    "/hook",
+                    post(
+                        |State(received): State<Arc<Mutex<Vec<Value>>>>,
+                         Json(body): Json<Value>| async move {
+                            received.lock().unwrap().push(body);
+                        },
+                    ),
+                )
+                .with_state(received.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let config = AlertsConfig {
+            webhook_url: Some(format!("http://{address}/hook")),
+            format: WebhookFormat::Discord,
+            ..Default::default()
+        };
+        let alerting = Alerting::new(&config, &[]);
+        let alert = rejected("Insufficient balance");
+        alerting.webhook.send(&alert.to_string()).await.unwrap();
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![json!({ "content": "Order rejected on lbank for Cashout: Insufficient balance" })]
+        );
+    }
+
+    fn state() -> AlertState {
+        let config = AlertsConfig {
+            repeat_after_secs: 60,
+            stale_after_secs: 30,
+            ..Default::default()
+        };
+        Alerting::new(&config, &[]).state
+    }
+
+    fn rejected(reason: &str) -> Alert {
+        Alert::OrderRejected {
+            exchange: LBANK,
+            strategy: Strategy::Cashout,
+            reason: reason.to_string(),
+        }
+    }
+
+    fn book(exchange: Exchange) -> Arc<OrderbookState> {
+        Arc::new(OrderbookState {
+            exchange,
+            timestamp_ms: 0,
+            asks: BTreeMap::from([(dec("0.51"), dec("100"))]),
+            bids: BTreeMap::from([(dec("0.49"), dec("100"))]),
+            invalidated: false,
+            trace: LatencyTrace::default(),
+        })
+    }
+
+    fn fill(strategy: Strategy, amount: &str, value: &str) -> OrderUpdate {
+        OrderUpdate {
+            strategy,
+            id: 1,
+            exchange: LBANK,
+            direction: Direction::Buy,
+            filled_amount: dec(amount),
+            filled_value: dec(value),
+            is_open: false,
+            fills_unknown: false,
+        }
+    }
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+}
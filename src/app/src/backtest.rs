@@ -0,0 +1,181 @@
+use crate::config::Config;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{channel, Receiver};
+use tokio_util::sync::CancellationToken;
+use xb_arb_finder::ArbFinder;
+use xb_types::{
+    Exchange, OrderRequest, OrderbookState, OrderbookStateProcessor, PendingOrder, TimeInForce,
+};
+
+// The simulated fills on one exchange
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExchangeTotals {
+    pub orders: u64,
+    pub bought: Decimal,
+    pub sold: Decimal,
+    // The USDT paid for what was bought and received for what was sold
+    pub spent: Decimal,
+    pub received: Decimal,
+}
+
+#[derive(Debug, Default)]
+pub struct BacktestResult {
+    pub states: usize,
+    pub per_exchange: BTreeMap<Exchange, ExchangeTotals>,
+    // Orders which would have rested on the book, which can't be simulated without the trades
+    pub unfilled: u64,
+}
+
+// Runs the arb finder over the recorded states as fast as it can process them. Each order is
+// assumed to fill in full at its limit price, or at its expected return for market orders, so the
+// result is the best case. The trading calendar is ignored as it follows the wall clock.
+pub async fn run(config: &Config, states: Vec<OrderbookState>) -> BacktestResult {
+    let (state_tx, state_rx) = channel(1024);
+    let (order_tx, order_rx) = channel(1024);
+    let token = CancellationToken::new();
+
+    // The arb finder holds the only order sender, so the fills are complete once it stops
+    let fills = tokio::spawn(simulate_fills(order_rx));
+    let arb_finder = ArbFinder::new(order_tx)
+        .with_parameters(config.arb_finder.parameters())
+        .run(state_rx, token.clone());
+
+    let mut result = BacktestResult {
+        states: states.len(),
+        ..Default::default()
+    };
+    for state in states {
+        state_tx.send(Arc::new(state)).unwrap();
+        // Wait for each state to be picked up rather than risk the arb finder lagging
+        while !state_tx.is_empty() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    token.cancel();
+    arb_finder.await.unwrap();
+    (result.per_exchange, result.unfilled) = fills.await.unwrap();
+    result
+}
+
+async fn simulate_fills(
+    mut order_rx: Receiver<Arc<OrderRequest>>,
+) -> (BTreeMap<Exchange, ExchangeTotals>, u64) {
+    let mut per_exchange = BTreeMap::<_, ExchangeTotals>::new();
+    let mut unfilled = 0;
+
+    loop {
+        let request = match order_rx.recv().await {
+            Ok(request) => request,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+        let OrderRequest::Submit(order) = request.as_ref() else {
+            continue;
+        };
+
+        let Some((amount, value)) = simulate_fill(order) else {
+            unfilled += 1;
+            continue;
+        };
+        let totals = per_exchange.entry(order.exchange()).or_default();
+        totals.orders += 1;
+        if order.direction().is_buy() {
+            totals.bought += amount;
+            totals.spent += value;
+        } else {
+            totals.sold += amount;
+            totals.received += value;
+        }
+    }
+
+    (per_exchange, unfilled)
+}
+
+// The amount and value of the fill, if the order would have been filled straight away
+fn simulate_fill(order: &PendingOrder) -> Option<(Decimal, Decimal)> {
+    match order {
+        PendingOrder::Limit(o) if o.time_in_force == TimeInForce::GoodTillCancelled => None,
+        PendingOrder::Limit(o) => Some((o.amount, o.amount * o.price)),
+        PendingOrder::Market(o) => Some((o.amount, o.expected_return)),
+    }
+}
+
+impl Display for BacktestResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Orderbook states: {}", self.states)?;
+        let mut net_base = Decimal::ZERO;
+        let mut net_quote = Decimal::ZERO;
+        for (exchange, totals) in &self.per_exchange {
+            writeln!(
+                f,
+                "{exchange:?}: {} orders. Bought {} CHAT for {} USDT. Sold {} CHAT for {} USDT",
+                totals.orders, totals.bought, totals.spent, totals.sold, totals.received
+            )?;
+            net_base += totals.bought - totals.sold;
+            net_quote += totals.received - totals.spent;
+        }
+        if self.unfilled > 0 {
+            writeln!(f, "Resting orders not simulated: {}", self.unfilled)?;
+        }
+        write!(f, "Net: {net_base} CHAT, {net_quote} USDT")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use test_case::test_case;
+    use xb_types::{
+        Direction, LatencyTrace, PendingLimitOrder, PendingMarketOrder, Strategy, TimeInForce,
+    };
+
+    const BITRUE: Exchange = Exchange::new("bitrue");
+    const LBANK: Exchange = Exchange::new("lbank");
+
+    #[test_case(TimeInForce::ImmediateOrCancel, Some(("10", "5")))]
+    #[test_case(TimeInForce::FillOrKill, Some(("10", "5")))]
+    #[test_case(TimeInForce::GoodTillCancelled, None)]
+    fn simulate_limit_fill(time_in_force: TimeInForce, expected: Option<(&str, &str)>) {
+        let order = PendingOrder::Limit(PendingLimitOrder {
+            id: 1,
+            strategy: Strategy::ArbFinder,
+            exchange: LBANK,
+            direction: Direction::Buy,
+            amount: dec("10"),
+            price: dec("0.5"),
+            time_in_force,
+            post_only: false,
+            trace: LatencyTrace::default(),
+        });
+
+        assert_eq!(
+            simulate_fill(&order),
+            expected.map(|(a, v)| (dec(a), dec(v)))
+        );
+    }
+
+    #[test]
+    fn simulate_market_fill() {
+        let order = PendingOrder::Market(PendingMarketOrder {
+            id: 1,
+            strategy: Strategy::Cashout,
+            exchange: BITRUE,
+            direction: Direction::Sell,
+            amount: dec("10"),
+            expected_return: dec("4.9"),
+            trace: LatencyTrace::default(),
+        });
+
+        assert_eq!(simulate_fill(&order), Some((dec("10"), dec("4.9"))));
+    }
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+}
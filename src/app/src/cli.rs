@@ -0,0 +1,103 @@
+use clap::{Parser, Subcommand};
+use rust_decimal::Decimal;
+use std::path::PathBuf;
+use xb_types::{Direction, Exchange};
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+#[derive(Debug, Parser)]
+#[command(about = "Trades CHAT across LBank and Bitrue")]
+pub struct Cli {
+    #[arg(
+        long,
+        env = "CONFIG_PATH",
+        default_value = DEFAULT_CONFIG_PATH,
+        help = "The config file, used for the strategies and exchange credentials"
+    )]
+    pub config: PathBuf,
+    // Running the service is the default when no subcommand is given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Run the service with the components enabled in the config")]
+    Run,
+    #[command(about = "Record the orderbooks to a file, one JSON state per line, until ctrl-c")]
+    Record {
+        output: PathBuf,
+        #[arg(
+            long = "exchange",
+            help = "The exchanges to record, may be given more than once, defaulting to all of them"
+        )]
+        exchanges: Vec<Exchange>,
+    },
+    #[command(
+        about = "Replay a recording through the arb finder at the recorded pace, logging the orders it would place"
+    )]
+    Replay {
+        input: PathBuf,
+        #[arg(
+            long,
+            default_value_t = 1.0,
+            help = "How many times faster than recorded to replay"
+        )]
+        speed: f64,
+    },
+    #[command(
+        about = "Run the arb finder over a recording as fast as possible, filling its orders in full at their prices"
+    )]
+    Backtest { input: PathBuf },
+    #[command(about = "Show the balances on each exchange with credentials")]
+    Balances,
+    #[command(about = "List or cancel open orders")]
+    Orders {
+        #[command(subcommand)]
+        command: OrdersCommand,
+    },
+    #[command(about = "Place a limit order, asking for confirmation first")]
+    PlaceOrder {
+        exchange: Exchange,
+        direction: Direction,
+        amount: Decimal,
+        price: Decimal,
+        #[arg(long, help = "Place the order without asking for confirmation")]
+        yes: bool,
+    },
+    #[command(about = "Show the current orderbook of an exchange")]
+    Book {
+        exchange: Exchange,
+        #[arg(
+            long,
+            default_value_t = 10,
+            help = "The number of levels to show each side"
+        )]
+        depth: usize,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum OrdersCommand {
+    #[command(about = "List the open orders on each exchange with credentials")]
+    List {
+        #[arg(long, help = "Only list the orders on this exchange")]
+        exchange: Option<Exchange>,
+    },
+    #[command(about = "Cancel an order by the id the exchange gave it")]
+    Cancel {
+        exchange: Exchange,
+        order_id: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_cli() {
+        Cli::command().debug_assert();
+    }
+}
@@ -0,0 +1,185 @@
+use crate::config::Config;
+use crate::{client, has_credentials, registry};
+use rust_decimal::Decimal;
+use std::io::Write;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use xb_subscriber::{Subscriber, SubscriptionManager};
+use xb_types::{
+    Direction, Exchange, ExchangeOrderExecutor, LatencyTrace, PendingLimitOrder, PendingOrder,
+    Strategy, TimeInForce,
+};
+
+// The one-off commands operators run by hand, printing their results to stdout
+
+const BOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Clients = Vec<(Exchange, Box<dyn ExchangeOrderExecutor>)>;
+
+pub async fn balances(config: &Config) -> Result<(), String> {
+    for (exchange, client) in clients(config, None)? {
+        let mut balances: Vec<_> = client
+            .get_balances()
+            .await?
+            .into_iter()
+            .filter(|(_, b)| !(b.free + b.locked).is_zero())
+            .collect();
+        balances.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        println!("{exchange:?}:");
+        for (asset, balance) in balances {
+            println!(
+                "  {asset}: {} free, {} locked",
+                balance.free, balance.locked
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn list_orders(config: &Config, exchange: Option<Exchange>) -> Result<(), String> {
+    for (exchange, client) in clients(config, exchange)? {
+        let orders = client.get_open_orders().await?;
+
+        println!("{exchange:?}: {} open orders", orders.len());
+        for order in orders {
+            println!(
+                "  {}: {:?} {} CHAT at {} USDT, {} filled",
+                order.exchange_order_id,
+                order.direction,
+                order.amount,
+                order.price,
+                order.filled_amount
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn cancel_order(
+    config: &Config,
+    exchange: Exchange,
+    order_id: &str,
+) -> Result<(), String> {
+    let (_, client) = clients(config, Some(exchange))?.remove(0);
+    client.cancel_order(order_id).await?;
+    println!("Cancelled order {order_id} on {exchange:?}");
+    Ok(())
+}
+
+// Places a good till cancelled limit order once the operator has confirmed it, unless already
+// confirmed on the command line
+pub async fn place_order(
+    config: &Config,
+    exchange: Exchange,
+    direction: Direction,
+    amount: Decimal,
+    price: Decimal,
+    confirmed: bool,
+) -> Result<(), String> {
+    let (_, client) = clients(config, Some(exchange))?.remove(0);
+    let order = PendingOrder::Limit(PendingLimitOrder {
+        id: 0,
+        strategy: Strategy::Manual,
+        exchange,
+        direction,
+        amount,
+        price,
+        time_in_force: TimeInForce::GoodTillCancelled,
+        post_only: false,
+        trace: LatencyTrace::default(),
+    });
+    client.check_order_supported(&order)?;
+
+    let summary = format!(
+        "{direction:?} {amount} CHAT at {price} USDT on {exchange:?}, worth {} USDT",
+        amount * price
+    );
+    if !confirmed && !confirm(&format!("Place order: {summary}?")).await? {
+        println!("Not placed");
+        return Ok(());
+    }
+
+    let order_id = client.submit_order(order).await?;
+    println!("Placed order {order_id}: {summary}");
+    Ok(())
+}
+
+// Subscribes to the exchange and prints the first orderbook received
+pub async fn book(exchange: Exchange, depth: usize) -> Result<(), String> {
+    let subscription_manager = SubscriptionManager::new();
+    let mut updates = subscription_manager.subscribe_orderbook_state();
+    let token = CancellationToken::new();
+    let registration = *registry().get(exchange)?;
+    let handle = Subscriber::new(vec![registration]).run(&subscription_manager, token.clone());
+
+    let state = tokio::time::timeout(BOOK_TIMEOUT, updates.recv()).await;
+    token.cancel();
+    handle.await.map_err(|e| e.to_string())?;
+
+    let state = match state {
+        Ok(Ok(state)) => state,
+        Ok(Err(error)) => return Err(format!("Failed to receive the {exchange:?} book: {error}")),
+        Err(_) => {
+            return Err(format!(
+                "No {exchange:?} book received within {BOOK_TIMEOUT:?}"
+            ))
+        }
+    };
+
+    println!(
+        "{exchange:?} {} at {}",
+        registration.symbol, state.timestamp_ms
+    );
+    println!("{:>16} {:>16}", "Price", "Amount");
+    for (price, amount) in state.asks.iter().take(depth).rev() {
+        println!("{price:>16} {amount:>16}");
+    }
+    println!("{:-^33}", "");
+    for (price, amount) in state.bids.iter().rev().take(depth) {
+        println!("{price:>16} {amount:>16}");
+    }
+    Ok(())
+}
+
+// The clients of the exchanges with credentials configured, or only the given exchange, which must
+// then have credentials
+fn clients(config: &Config, only: Option<Exchange>) -> Result<Clients, String> {
+    let registry = registry();
+    let mut clients: Clients = Vec::new();
+    for exchange in registry.exchanges() {
+        if only.is_some_and(|e| e != exchange) {
+            continue;
+        }
+        match config.exchanges.get(exchange) {
+            Some(exchange_config) if has_credentials(exchange_config) => {
+                let registration = registry.get(exchange)?;
+                clients.push((exchange, client(registration, exchange_config)));
+            }
+            _ if only.is_some() => {
+                return Err(format!("No credentials configured for {exchange:?}"));
+            }
+            _ => {}
+        }
+    }
+
+    if clients.is_empty() {
+        return Err("No exchange credentials configured".to_string());
+    }
+    Ok(clients)
+}
+
+async fn confirm(prompt: &str) -> Result<bool, String> {
+    print!("{prompt} [y/N] ");
+    std::io::stdout().flush().map_err(|e| e.to_string())?;
+
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).map(|_| answer)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| format!("Failed to read confirmation: {e}"))?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
@@ -0,0 +1,857 @@
+use crate::alerts::WebhookFormat;
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use toml::{Table, Value};
+use xb_arb_finder::ArbFinderParameters;
+use xb_cashout::{
+    CashoutAllocation, CashoutParameters, CashoutSchedule, CashoutTarget, GuardConfig,
+    LadderConfig, PercentOfVolumeSchedule, PoissonSchedule, TwapSchedule, VwapSchedule,
+};
+use xb_order_executor::RiskLimits;
+use xb_types::{
+    Alert, Direction, Exchange, Exclusion, ReconnectPolicy, TimeOfDay, TradingCalendar,
+    TradingWindow,
+};
+
+// Environment variables starting with this override values in the config file, with the path to
+// the value separated by double underscores, eg. XB_CASHOUT__MIN_PRICE=0.3 sets min_price in the
+// [cashout] section. Values are parsed as TOML, falling back to a plain string if that fails, so a
+// string which looks like a number needs quoting.
+const ENV_PREFIX: &str = "XB_";
+
+#[derive(Debug, Default)]
+pub struct Config {
+    pub exchanges: ExchangesConfig,
+    pub arb_finder: ArbFinderConfig,
+    pub cashout: CashoutConfig,
+    pub accumulate: CashoutConfig,
+    pub order_executor: OrderExecutorConfig,
+    pub risk: RiskConfig,
+    pub admin: AdminConfig,
+    pub metrics: MetricsConfig,
+    pub shutdown: ShutdownConfig,
+    pub alerts: AlertsConfig,
+    pub reconnect: ReconnectConfig,
+}
+
+// Keyed by the exchange's name, eg. [exchanges.lbank]
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct ExchangesConfig(BTreeMap<String, ExchangeConfig>);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExchangeConfig {
+    pub enabled: bool,
+    pub api_key: Option<String>,
+    pub secret_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArbFinderConfig {
+    pub enabled: bool,
+    pub min_profit_bps: Decimal,
+    pub trading_windows: Vec<TradingWindow>,
+    pub excluded_windows: Vec<Exclusion>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CashoutConfig {
+    pub enabled: bool,
+    pub amount_per_day: Option<Decimal>,
+    // Defaults to 1% of the daily amount
+    pub amount_per_iteration: Option<Decimal>,
+    // The minimum price when selling or the maximum price when buying
+    #[serde(alias = "min_price", alias = "max_price")]
+    pub price_limit: Option<Decimal>,
+    pub state_path: Option<PathBuf>,
+    pub allocation: CashoutAllocation,
+    pub target: Option<CashoutTarget>,
+    pub schedule: ScheduleConfig,
+    // Trades passively through a ladder of limit orders when present
+    pub passive: Option<PassiveConfig>,
+    pub guards: GuardsConfig,
+    pub trading_windows: Vec<TradingWindow>,
+    pub excluded_windows: Vec<Exclusion>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ScheduleConfig {
+    #[default]
+    Poisson,
+    // Sells the amount evenly over a window starting at the same time (UTC) each day
+    Twap {
+        start: TimeOfDay,
+        duration_secs: u64,
+        amount: Decimal,
+    },
+    Vwap {
+        // 24 values, one per UTC hour
+        profile: Vec<Decimal>,
+    },
+    Pov {
+        participation: Decimal,
+        #[serde(default = "default_pov_interval_secs")]
+        interval_secs: u64,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PassiveConfig {
+    pub levels: usize,
+    // Defaults to the amount per iteration
+    pub amount_per_level: Option<Decimal>,
+    pub spacing_bps: Decimal,
+    pub refresh_threshold_bps: Decimal,
+    pub catch_up_tolerance: Decimal,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GuardsConfig {
+    pub max_spread_bps: Option<Decimal>,
+    pub max_volatility_bps: Option<Decimal>,
+    pub volatility_window_secs: u64,
+    pub moving_average_window_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrderExecutorConfig {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiskConfig {
+    // The fraction by which market orders may fill worse than expected
+    pub max_slippage: Option<Decimal>,
+    // The largest amount of CHAT any single order may be for
+    pub max_order_amount: Option<Decimal>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    // Must be a loopback address, the API is only meant to be reached locally or via a tunnel
+    pub bind: SocketAddr,
+    // Requests must send this as a bearer token
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub bind: SocketAddr,
+}
+
+// How long each phase of the shutdown may take before moving on to the next
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    // Handling the order requests queued before the strategies stopped
+    pub drain_timeout_secs: u64,
+    // Cancelling the limit orders still open
+    pub cancel_timeout_secs: u64,
+    // The strategies writing their final state
+    pub snapshot_timeout_secs: u64,
+    // Closing the websockets
+    pub websocket_timeout_secs: u64,
+}
+
+// How the exchange websockets are reconnected after a failure
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectConfig {
+    // Doubled with each consecutive failure, up to the maximum
+    pub initial_delay_ms: u64,
+    pub max_delay_secs: u64,
+    // Consecutive failures after which the exchange is marked as down
+    pub circuit_threshold: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertsConfig {
+    pub enabled: bool,
+    pub webhook_url: Option<String>,
+    pub format: WebhookFormat,
+    // Required by the telegram format, the webhook url holds the bot token
+    pub telegram_chat_id: Option<String>,
+    // Repeats of an alert within this are held back and counted in the next one sent
+    pub repeat_after_secs: u64,
+    // How long an enabled exchange may go without an orderbook update
+    pub stale_after_secs: u64,
+    // The USDT value of an arb fill worth alerting on
+    pub large_arb_value: Option<Decimal>,
+    // The USDT PnL of the day's fills, valued at the mid price, worth alerting on
+    pub daily_pnl_below: Option<Decimal>,
+    pub daily_pnl_above: Option<Decimal>,
+    // Kinds of alert not to send, eg. "exchange_recovered"
+    pub muted: Vec<String>,
+}
+
+impl Config {
+    // Loads the config file, applies any environment variable overrides and validates the result,
+    // returning every problem found rather than stopping at the first
+    pub fn load(path: &Path) -> Result<Config, Vec<String>> {
+        let mut table = match fs::read_to_string(path) {
+            Ok(contents) => contents
+                .parse::<Table>()
+                .map_err(|e| vec![format!("Failed to parse {path:?}: {e}")])?,
+            Err(error) => return Err(vec![format!("Failed to read {path:?}: {error}")]),
+        };
+
+        apply_env_overrides(&mut table, std::env::vars());
+
+        Config::from_table(table)
+    }
+
+    pub(crate) fn from_table(mut table: Table) -> Result<Config, Vec<String>> {
+        // Each section is read separately so that errors in one don't hide errors in another
+        let mut errors = Vec::new();
+        let config = Config {
+            exchanges: section(&mut table, "exchanges", &mut errors),
+            arb_finder: section(&mut table, "arb_finder", &mut errors),
+            cashout: section(&mut table, "cashout", &mut errors),
+            accumulate: section(&mut table, "accumulate", &mut errors),
+            order_executor: section(&mut table, "order_executor", &mut errors),
+            risk: section(&mut table, "risk", &mut errors),
+            admin: section(&mut table, "admin", &mut errors),
+            metrics: section(&mut table, "metrics", &mut errors),
+            shutdown: section(&mut table, "shutdown", &mut errors),
+            alerts: section(&mut table, "alerts", &mut errors),
+            reconnect: section(&mut table, "reconnect", &mut errors),
+        };
+        for key in table.keys() {
+            errors.push(format!("Unknown section: {key}"));
+        }
+
+        config.validate(&mut errors);
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn validate(&self, errors: &mut Vec<String>) {
+        let registry = crate::registry();
+        for name in self.exchanges.0.keys() {
+            let supported = Exchange::from_str(name)
+                .is_ok_and(|e| e.as_str() == name && registry.get(e).is_ok());
+            if !supported {
+                errors.push(format!("exchanges.{name} is not a supported exchange"));
+            }
+        }
+
+        // The executor trades on every enabled exchange
+        if self.order_executor.enabled {
+            for (exchange, config) in self.exchanges.enabled() {
+                let name = exchange.as_str();
+                if config.api_key.as_deref().unwrap_or_default().is_empty() {
+                    errors.push(format!("exchanges.{name}.api_key is required"));
+                }
+                if config.secret_key.as_deref().unwrap_or_default().is_empty() {
+                    errors.push(format!("exchanges.{name}.secret_key is required"));
+                }
+            }
+        }
+
+        if self.arb_finder.min_profit_bps < Decimal::ZERO {
+            errors.push("arb_finder.min_profit_bps can't be negative".to_string());
+        }
+
+        self.cashout.validate("cashout", errors);
+        self.accumulate.validate("accumulate", errors);
+
+        if let Some(max_slippage) = self.risk.max_slippage {
+            if max_slippage < Decimal::ZERO || max_slippage >= Decimal::ONE {
+                errors.push("risk.max_slippage must be at least 0 and less than 1".to_string());
+            }
+        }
+        if let Some(max_order_amount) = self.risk.max_order_amount {
+            if max_order_amount <= Decimal::ZERO {
+                errors.push("risk.max_order_amount must be positive".to_string());
+            }
+        }
+
+        if self.admin.enabled {
+            if self.admin.token.as_deref().unwrap_or_default().is_empty() {
+                errors.push("admin.token is required".to_string());
+            }
+            if !self.admin.bind.ip().is_loopback() {
+                errors.push("admin.bind must be a loopback address".to_string());
+            }
+        }
+
+        if self.alerts.enabled {
+            if self
+                .alerts
+                .webhook_url
+                .as_deref()
+                .unwrap_or_default()
+                .is_empty()
+            {
+                errors.push("alerts.webhook_url is required".to_string());
+            }
+            if self.alerts.format == WebhookFormat::Telegram
+                && self.alerts.telegram_chat_id.is_none()
+            {
+                errors.push("alerts.telegram_chat_id is required for telegram".to_string());
+            }
+        }
+        for kind in &self.alerts.muted {
+            if !Alert::KINDS.contains(&kind.as_str()) {
+                errors.push(format!(
+                    "alerts.muted has unknown kind {kind}, expected one of {}",
+                    Alert::KINDS.join(", ")
+                ));
+            }
+        }
+
+        let reconnect = self.reconnect.policy();
+        if reconnect.initial_delay.is_zero() {
+            errors.push("reconnect.initial_delay_ms must be positive".to_string());
+        }
+        if reconnect.max_delay < reconnect.initial_delay {
+            errors
+                .push("reconnect.max_delay_secs can't be less than the initial delay".to_string());
+        }
+        if reconnect.circuit_threshold == 0 {
+            errors.push("reconnect.circuit_threshold must be at least 1".to_string());
+        }
+    }
+}
+
+impl ArbFinderConfig {
+    pub fn parameters(&self) -> ArbFinderParameters {
+        ArbFinderParameters {
+            min_profit_bps: self.min_profit_bps,
+        }
+    }
+
+    pub fn calendar(&self) -> TradingCalendar {
+        calendar(&self.trading_windows, &self.excluded_windows)
+    }
+}
+
+impl CashoutConfig {
+    pub fn state_path(&self, default: &str) -> PathBuf {
+        self.state_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(default))
+    }
+
+    pub fn amount_per_iteration(&self) -> Decimal {
+        let amount_per_day = self.amount_per_day.unwrap_or_default();
+        self.amount_per_iteration
+            .unwrap_or(amount_per_day / Decimal::from(100))
+    }
+
+    pub fn parameters(&self) -> CashoutParameters {
+        CashoutParameters {
+            amount_per_day: self.amount_per_day.unwrap_or_default(),
+            amount_per_iteration: self.amount_per_iteration(),
+            price_limit: self.price_limit,
+        }
+    }
+
+    // The accumulation budget is in USDT unless specified otherwise
+    pub fn target(&self, direction: Direction) -> CashoutTarget {
+        self.target.unwrap_or(if direction.is_buy() {
+            CashoutTarget::Quote
+        } else {
+            CashoutTarget::Base
+        })
+    }
+
+    pub fn schedule(&self, target: CashoutTarget) -> Result<Box<dyn CashoutSchedule>, String> {
+        let amount_per_day = self.amount_per_day.unwrap_or_default();
+        let amount_per_iteration = self.amount_per_iteration();
+
+        Ok(match &self.schedule {
+            ScheduleConfig::Poisson => {
+                Box::new(PoissonSchedule::new(amount_per_day, amount_per_iteration))
+            }
+            ScheduleConfig::Twap {
+                start,
+                duration_secs,
+                amount,
+            } => Box::new(TwapSchedule::new(
+                start.since_midnight(),
+                Duration::from_secs(*duration_secs),
+                *amount,
+                amount_per_iteration,
+            )),
+            ScheduleConfig::Vwap { profile } => Box::new(VwapSchedule::new(
+                amount_per_day,
+                amount_per_iteration,
+                profile.clone(),
+            )?),
+            ScheduleConfig::Pov {
+                participation,
+                interval_secs,
+            } => Box::new(PercentOfVolumeSchedule::new(
+                *participation,
+                Duration::from_secs(*interval_secs),
+                amount_per_iteration,
+                target,
+            )),
+        })
+    }
+
+    pub fn ladder_config(&self) -> Option<LadderConfig> {
+        self.passive.as_ref().map(|p| LadderConfig {
+            levels: p.levels,
+            amount_per_level: p
+                .amount_per_level
+                .unwrap_or_else(|| self.amount_per_iteration()),
+            spacing_bps: p.spacing_bps,
+            refresh_threshold_bps: p.refresh_threshold_bps,
+            catch_up_tolerance: p.catch_up_tolerance,
+        })
+    }
+
+    pub fn guard_config(&self) -> GuardConfig {
+        GuardConfig {
+            max_spread_bps: self.guards.max_spread_bps,
+            max_volatility_bps: self.guards.max_volatility_bps,
+            volatility_window: Duration::from_secs(self.guards.volatility_window_secs),
+            moving_average_window: self
+                .guards
+                .moving_average_window_secs
+                .map(Duration::from_secs),
+        }
+    }
+
+    pub fn calendar(&self) -> TradingCalendar {
+        calendar(&self.trading_windows, &self.excluded_windows)
+    }
+
+    fn validate(&self, name: &str, errors: &mut Vec<String>) {
+        if self.enabled {
+            self.validate_parameters(name, errors);
+        }
+    }
+
+    // Checked regardless of whether it's enabled when reloading the parameters of a running strategy
+    pub fn validate_parameters(&self, name: &str, errors: &mut Vec<String>) {
+        let Some(amount_per_day) = self.amount_per_day else {
+            errors.push(format!("{name}.amount_per_day is required"));
+            return;
+        };
+        if amount_per_day <= Decimal::ZERO {
+            errors.push(format!("{name}.amount_per_day must be positive"));
+            return;
+        }
+        let amount_per_iteration = self.amount_per_iteration();
+        if amount_per_iteration <= Decimal::ZERO || amount_per_iteration > amount_per_day {
+            errors.push(format!(
+                "{name}.amount_per_iteration must be positive and no more than amount_per_day"
+            ));
+            return;
+        }
+
+        match &self.schedule {
+            ScheduleConfig::Twap { duration_secs, .. }
+                if *duration_secs == 0 || *duration_secs > 24 * 60 * 60 =>
+            {
+                errors.push(format!(
+                    "{name}.schedule.duration_secs must be positive and at most a day"
+                ));
+            }
+            ScheduleConfig::Twap { amount, .. }
+                if *amount < amount_per_iteration || *amount > amount_per_day =>
+            {
+                errors.push(format!(
+                    "{name}.schedule.amount must be at least amount_per_iteration and no more than amount_per_day"
+                ));
+            }
+            ScheduleConfig::Pov {
+                interval_secs: 0, ..
+            } => {
+                errors.push(format!("{name}.schedule.interval_secs must be positive"));
+            }
+            ScheduleConfig::Pov { participation, .. }
+                if *participation <= Decimal::ZERO || *participation > Decimal::ONE =>
+            {
+                errors.push(format!(
+                    "{name}.schedule.participation must be more than 0 and at most 1"
+                ));
+            }
+            _ => {
+                if let Err(error) = self.schedule(self.target.unwrap_or_default()) {
+                    errors.push(format!("{name}.schedule: {error}"));
+                }
+            }
+        }
+
+        if let Some(passive) = &self.passive {
+            if passive.levels == 0 {
+                errors.push(format!("{name}.passive.levels must be positive"));
+            }
+            if passive.amount_per_level.is_some_and(|a| a <= Decimal::ZERO) {
+                errors.push(format!("{name}.passive.amount_per_level must be positive"));
+            }
+            if passive.spacing_bps < Decimal::ZERO || passive.refresh_threshold_bps < Decimal::ZERO
+            {
+                errors.push(format!(
+                    "{name}.passive.spacing_bps and refresh_threshold_bps can't be negative"
+                ));
+            }
+        }
+    }
+}
+
+impl ExchangesConfig {
+    // The configured exchanges, skipping any unsupported which are reported when validating
+    pub fn iter(&self) -> impl Iterator<Item = (Exchange, &ExchangeConfig)> {
+        self.0
+            .iter()
+            .filter_map(|(name, config)| Some((Exchange::from_str(name).ok()?, config)))
+    }
+
+    pub fn enabled(&self) -> impl Iterator<Item = (Exchange, &ExchangeConfig)> {
+        self.iter().filter(|(_, config)| config.enabled)
+    }
+
+    pub fn get(&self, exchange: Exchange) -> Option<&ExchangeConfig> {
+        self.0.get(exchange.as_str())
+    }
+}
+
+impl RiskConfig {
+    pub fn limits(&self) -> RiskLimits {
+        RiskLimits {
+            max_slippage: self.max_slippage,
+            max_order_amount: self.max_order_amount,
+        }
+    }
+}
+
+impl Default for PassiveConfig {
+    fn default() -> Self {
+        PassiveConfig {
+            levels: 3,
+            amount_per_level: None,
+            spacing_bps: Decimal::from(10),
+            refresh_threshold_bps: Decimal::from(5),
+            catch_up_tolerance: Decimal::new(5, 2),
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            enabled: false,
+            bind: SocketAddr::from((Ipv4Addr::LOCALHOST, 8080)),
+            token: None,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            bind: SocketAddr::from((Ipv4Addr::LOCALHOST, 9100)),
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            drain_timeout_secs: 10,
+            cancel_timeout_secs: 30,
+            snapshot_timeout_secs: 5,
+            websocket_timeout_secs: 5,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    pub fn policy(&self) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(self.initial_delay_ms),
+            max_delay: Duration::from_secs(self.max_delay_secs),
+            circuit_threshold: self.circuit_threshold,
+        }
+    }
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_delay_ms: 500,
+            max_delay_secs: 60,
+            circuit_threshold: 10,
+        }
+    }
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        AlertsConfig {
+            enabled: false,
+            webhook_url: None,
+            format: WebhookFormat::default(),
+            telegram_chat_id: None,
+            repeat_after_secs: 10 * 60,
+            stale_after_secs: 30,
+            large_arb_value: None,
+            daily_pnl_below: None,
+            daily_pnl_above: None,
+            muted: Vec::new(),
+        }
+    }
+}
+
+impl Default for GuardsConfig {
+    fn default() -> Self {
+        GuardsConfig {
+            max_spread_bps: None,
+            max_volatility_bps: None,
+            volatility_window_secs: 5 * 60,
+            moving_average_window_secs: None,
+        }
+    }
+}
+
+fn default_pov_interval_secs() -> u64 {
+    60
+}
+
+fn section<T: DeserializeOwned + Default>(
+    table: &mut Table,
+    name: &str,
+    errors: &mut Vec<String>,
+) -> T {
+    match table.remove(name) {
+        Some(value) => value.try_into().unwrap_or_else(|e| {
+            let error = e.to_string().trim().replace('\n', " ");
+            errors.push(format!("[{name}] {error}"));
+            T::default()
+        }),
+        None => T::default(),
+    }
+}
+
+fn apply_env_overrides(table: &mut Table, vars: impl Iterator<Item = (String, String)>) {
+    for (key, value) in vars {
+        let Some(path) = key.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let path: Vec<_> = path.split("__").map(|s| s.to_lowercase()).collect();
+
+        let mut current = &mut *table;
+        for segment in &path[..path.len() - 1] {
+            let entry = current
+                .entry(segment.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+            current = entry.as_table_mut().unwrap();
+        }
+        current.insert(path[path.len() - 1].clone(), parse_value(&value));
+    }
+}
+
+fn parse_value(value: &str) -> Value {
+    format!("value = {value}")
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut t| t.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+fn calendar(windows: &[TradingWindow], exclusions: &[Exclusion]) -> TradingCalendar {
+    let calendar = windows
+        .iter()
+        .fold(TradingCalendar::new(), |c, w| c.with_window(w.clone()));
+    exclusions
+        .iter()
+        .fold(calendar, |c, e| c.with_exclusion(e.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BITRUE: Exchange = Exchange::new("bitrue");
+    const LBANK: Exchange = Exchange::new("lbank");
+
+    const CONFIG: &str = r#"
+        [exchanges.lbank]
+        enabled = true
+        api_key = "key"
+        secret_key = "secret"
+
+        [exchanges.bitrue]
+        enabled = true
+        api_key = "key"
+        secret_key = "secret"
+
+        [cashout]
+        enabled = true
+        amount_per_day = 10000
+        min_price = "0.3"
+        trading_windows = ["mon-fri 08:00-20:00"]
+
+        [cashout.schedule]
+        type = "twap"
+        start = "09:00"
+        duration_secs = 3600
+        amount = 2000
+
+        [order_executor]
+        enabled = true
+
+        [risk]
+        max_slippage = 0.01
+    "#;
+
+    #[test]
+    fn loads_valid_config() {
+        let config = Config::from_table(CONFIG.parse().unwrap()).unwrap();
+
+        assert_eq!(
+            config
+                .exchanges
+                .enabled()
+                .map(|(e, _)| e)
+                .collect::<Vec<_>>(),
+            vec![BITRUE, LBANK]
+        );
+        assert!(config.cashout.enabled);
+        assert_eq!(config.cashout.target(Direction::Sell), CashoutTarget::Base);
+        assert_eq!(
+            config.accumulate.target(Direction::Buy),
+            CashoutTarget::Quote
+        );
+        assert_eq!(config.cashout.price_limit, Some(Decimal::new(3, 1)));
+        assert_eq!(config.cashout.amount_per_iteration(), Decimal::from(100));
+        assert_eq!(config.risk.max_slippage, Some(Decimal::new(1, 2)));
+        assert!(!config.accumulate.enabled);
+    }
+
+    #[test]
+    fn env_overrides_take_precedence() {
+        let mut table = CONFIG.parse().unwrap();
+        apply_env_overrides(
+            &mut table,
+            [
+                ("XB_CASHOUT__MIN_PRICE", "0.35"),
+                ("XB_EXCHANGES__LBANK__API_KEY", "\"12345\""),
+                ("XB_ACCUMULATE__TARGET", "base"),
+                ("OTHER", "ignored"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        let config = Config::from_table(table).unwrap();
+
+        assert_eq!(config.cashout.price_limit, Some(Decimal::new(35, 2)));
+        assert_eq!(
+            config.exchanges.get(LBANK).unwrap().api_key.as_deref(),
+            Some("12345")
+        );
+        assert_eq!(
+            config.accumulate.target(Direction::Buy),
+            CashoutTarget::Base
+        );
+    }
+
+    #[test]
+    fn reports_all_errors() {
+        let table = r#"
+            [exchanges.lbank]
+            enabled = true
+            api_key = "key"
+
+            [exchanges.bitrue]
+            enabled = true
+
+            [exchanges.binance]
+
+            [arb_finder]
+            enabled = true
+            trading_windows = ["weekdays"]
+
+            [cashout]
+            enabled = true
+            amount_per_day = 100
+            amount_per_iteration = 1000
+
+            [accumulate]
+            enabled = true
+            amount_per_day = 100
+            schedule = { type = "vwap", profile = [1, 2, 3] }
+
+            [order_executor]
+            enabled = true
+
+            [risk]
+            max_slippage = 2
+
+            [admin]
+            enabled = true
+            bind = "0.0.0.0:8080"
+
+            [alerts]
+            enabled = true
+            format = "telegram"
+            muted = ["exchange_recovered", "everything"]
+
+            [reconnect]
+            initial_delay_ms = 0
+            circuit_threshold = 0
+
+            [unknown]
+        "#
+        .parse()
+        .unwrap();
+
+        let errors = Config::from_table(table).unwrap_err();
+
+        assert_eq!(
+            errors,
+            vec![
+                "[arb_finder] Invalid trading window: weekdays in `trading_windows`",
+                "Unknown section: unknown",
+                "exchanges.binance is not a supported exchange",
+                "exchanges.bitrue.api_key is required",
+                "exchanges.bitrue.secret_key is required",
+                "exchanges.lbank.secret_key is required",
+                "cashout.amount_per_iteration must be positive and no more than amount_per_day",
+                "accumulate.schedule: VWAP volume profile must have 24 hourly values, found 3",
+                "risk.max_slippage must be at least 0 and less than 1",
+                "admin.token is required",
+                "admin.bind must be a loopback address",
+                "alerts.webhook_url is required",
+                "alerts.telegram_chat_id is required for telegram",
+                "alerts.muted has unknown kind everything, expected one of order_rejected, risk_limit, daily_target_reached, component_failed, exchange_stale, exchange_recovered, large_arb_fill, daily_pnl_below, daily_pnl_above",
+                "reconnect.initial_delay_ms must be positive",
+                "reconnect.circuit_threshold must be at least 1",
+            ]
+        );
+    }
+}
@@ -1,109 +1,534 @@
-use rust_decimal::Decimal;
+use crate::admin::Admin;
+use crate::alerts::Alerting;
+use crate::cli::{Cli, Command, OrdersCommand};
+use crate::config::{CashoutConfig, Config, ExchangeConfig};
+use crate::reload::ParameterSenders;
+use crate::supervisor::Supervisor;
+use clap::Parser;
+use std::collections::HashSet;
 use std::io;
-use std::str::FromStr;
-use tokio::sync::broadcast::channel;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{error, info, warn, Level};
 use xb_arb_finder::ArbFinder;
-use xb_cashout::Cashout;
-use xb_exchanges_bitrue::BitrueClient;
-use xb_exchanges_lbank::LBankClient;
+use xb_cashout::{Cashout, CashoutParameters};
 use xb_order_executor::OrderExecutorBuilder;
-use xb_subscriber::Subscriber;
-use xb_types::{Exchange, OrderbookStateProcessor};
+use xb_subscriber::{Subscriber, SubscriptionManager};
+use xb_types::{
+    Direction, Exchange, ExchangeOrderExecutor, ExchangeRegistration, ExchangeRegistry,
+    OrderRequest, OrderUpdate, OrderbookStateProcessor, PauseSwitch,
+};
+
+mod admin;
+mod alerts;
+mod backtest;
+mod cli;
+mod commands;
+mod config;
+mod metrics;
+mod recording;
+mod reload;
+mod supervisor;
 
 #[tokio::main]
 async fn main() {
-    dotenv::dotenv().unwrap();
+    // Environment variable overrides can also be set in a .env file
+    dotenv::dotenv().ok();
 
-    tracing_subscriber::fmt().with_writer(io::stdout).init();
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Run);
 
-    info!("Service started");
+    // The one-off commands print their results, so only problems are logged alongside them
+    let level = match command {
+        Command::Run | Command::Record { .. } | Command::Replay { .. } => Level::INFO,
+        _ => Level::WARN,
+    };
+    tracing_subscriber::fmt()
+        .with_writer(io::stdout)
+        .with_max_level(level)
+        .init();
 
-    abort_on_panic();
+    let result = match command {
+        Command::Run => {
+            run(load_config(&cli.config), cli.config).await;
+            Ok(())
+        }
+        Command::Record { output, exchanges } => record(&output, exchanges).await,
+        Command::Replay { input, speed } => replay(&load_config(&cli.config), &input, speed).await,
+        Command::Backtest { input } => backtest(&load_config(&cli.config), &input).await,
+        Command::Balances => commands::balances(&load_config(&cli.config)).await,
+        Command::Orders {
+            command: OrdersCommand::List { exchange },
+        } => commands::list_orders(&load_config(&cli.config), exchange).await,
+        Command::Orders {
+            command: OrdersCommand::Cancel { exchange, order_id },
+        } => commands::cancel_order(&load_config(&cli.config), exchange, &order_id).await,
+        Command::PlaceOrder {
+            exchange,
+            direction,
+            amount,
+            price,
+            yes,
+        } => {
+            let config = load_config(&cli.config);
+            commands::place_order(&config, exchange, direction, amount, price, yes).await
+        }
+        Command::Book { exchange, depth } => commands::book(exchange, depth).await,
+    };
 
-    let shutdown = CancellationToken::new();
-    let mut exchanges = Vec::new();
-    if is_enabled("BITRUE") {
-        exchanges.push(Exchange::Bitrue);
+    if let Err(error) = result {
+        error!("{error}");
+        std::process::exit(1);
     }
-    if is_enabled("LBANK") {
-        exchanges.push(Exchange::LBank);
+}
+
+fn load_config(path: &Path) -> Config {
+    match Config::load(path) {
+        Ok(config) => config,
+        Err(errors) => {
+            error!("Invalid configuration in {path:?}:");
+            for error in errors {
+                error!("  {error}");
+            }
+            std::process::exit(1);
+        }
     }
+}
 
+async fn run(config: Config, config_path: PathBuf) {
+    info!("Service started");
+
+    let config = Arc::new(config);
+    let shutdown = CancellationToken::new();
+    let subscription_manager = Arc::new(SubscriptionManager::new());
     let mut handles = Vec::new();
+    // Sending fails harmlessly if alerting isn't enabled
+    let (alert_tx, _) = channel(1024);
 
-    let subscriber = Subscriber::new(exchanges);
-    let (subscription_manager, subscriber_handle) = subscriber.run(shutdown.clone());
-    handles.push(subscriber_handle);
+    // The components are grouped by the phase of the shutdown which stops them
+    let mut subscribers = Supervisor::new().with_alerts(alert_tx.clone());
+    let mut executor = Supervisor::new().with_alerts(alert_tx.clone());
+    let mut strategies = Supervisor::new().with_alerts(alert_tx.clone());
+    let mut strategy_pauses = Vec::new();
+
+    // Each exchange is subscribed to separately so a failing one doesn't take the others down
+    let registry = registry();
+    let reconnect_policy = config.reconnect.policy();
+    for (exchange, _) in config.exchanges.enabled() {
+        let registration = *registry
+            .get(exchange)
+            .expect("Exchanges are checked during validation");
+        let subscription_manager = subscription_manager.clone();
+        subscribers = subscribers.with_component(registration.subscriber_name, move |token| {
+            Subscriber::new(vec![registration])
+                .with_reconnect_policy(reconnect_policy)
+                .run(&subscription_manager, token)
+        });
+    }
 
     let (order_tx, order_rx) = channel(1024);
+    let (order_update_tx, _) = channel(1024);
 
-    if is_enabled("ARB_FINDER") {
-        let arb_finder = ArbFinder::new(order_tx.clone());
-        let handle = arb_finder.run(
+    // Alerting is stopped last so it can report the failures which caused the shutdown
+    let stop_alerting = CancellationToken::new();
+    let alerting = config.alerts.enabled.then(|| {
+        let exchanges: Vec<_> = config.exchanges.enabled().map(|(e, _)| e).collect();
+        Alerting::new(&config.alerts, &exchanges).run(
+            alert_tx.subscribe(),
             subscription_manager.subscribe_orderbook_state(),
-            shutdown.clone(),
-        );
-        handles.push(handle);
+            order_update_tx.subscribe(),
+            stop_alerting.clone(),
+        )
+    });
+
+    let parameters = ParameterSenders::new(&config);
+    let mut admin = Admin::new(
+        config.admin.token.clone().unwrap_or_default(),
+        order_tx.clone(),
+    );
+
+    // The executor is critical, as if it fails we lose track of the orders it placed
+    if config.order_executor.enabled {
+        // Credentials are checked when the config is validated
+        let pause = PauseSwitch::new();
+        admin = admin.with_processor("order_executor", pause.clone());
+        let config = config.clone();
+        let registry = registry.clone();
+        let order_update_tx = order_update_tx.clone();
+        let alert_tx = alert_tx.clone();
+        let risk_limits = parameters.risk_limits.subscribe();
+        let mut order_rx = Some(order_rx);
+        executor = executor.with_critical_component("order_executor", move |token| {
+            let mut order_executor_builder = OrderExecutorBuilder::new()
+                .with_pause_switch(pause.clone())
+                .with_shutdown_timeouts(
+                    Duration::from_secs(config.shutdown.drain_timeout_secs),
+                    Duration::from_secs(config.shutdown.cancel_timeout_secs),
+                )
+                .with_order_updates(order_update_tx.clone())
+                .with_alerts(alert_tx.clone())
+                .with_risk_limit_updates(latest(&risk_limits));
+
+            for (exchange, exchange_config) in config.exchanges.enabled() {
+                let registration = registry
+                    .get(exchange)
+                    .expect("Exchanges are checked during validation");
+                order_executor_builder = order_executor_builder
+                    .with_exchange(exchange, client(registration, exchange_config));
+            }
+            if let Some(max_slippage) = config.risk.max_slippage {
+                order_executor_builder = order_executor_builder.with_max_slippage(max_slippage);
+            }
+            if let Some(max_order_amount) = config.risk.max_order_amount {
+                order_executor_builder =
+                    order_executor_builder.with_max_order_amount(max_order_amount);
+            }
+
+            let order_executor = order_executor_builder.build();
+            order_executor.run(order_rx.take().expect("Started once"), token)
+        });
+    }
+
+    if config.arb_finder.enabled {
+        let pause = PauseSwitch::new();
+        admin = admin.with_processor("arb_finder", pause.clone());
+        strategy_pauses.push(pause.clone());
+        let config = config.clone();
+        let subscription_manager = subscription_manager.clone();
+        let order_tx = order_tx.clone();
+        let parameter_updates = parameters.arb_finder.subscribe();
+        strategies = strategies.with_component("arb_finder", move |token| {
+            ArbFinder::new(order_tx.clone())
+                .with_pause_switch(pause.clone())
+                .with_calendar(config.arb_finder.calendar())
+                .with_parameters(config.arb_finder.parameters())
+                .with_parameter_updates(latest(&parameter_updates))
+                .run(subscription_manager.subscribe_orderbook_state(), token)
+        });
     }
-    if is_enabled("CASHOUT") {
-        if let Some(amount) = get_config("CASHOUT_AMOUNT_PER_DAY") {
-            let cashout = Cashout::new(
-                amount,
-                get_config("CASHOUT_AMOUNT_PER_ITERATION").unwrap_or(amount / Decimal::from(100)),
-                get_config("CASHOUT_MIN_PRICE"),
-                order_tx.clone(),
-            );
-            let handle = cashout.run(
-                subscription_manager.subscribe_orderbook_state(),
-                shutdown.clone(),
-            );
-            handles.push(handle);
+    let post_only_exchanges: HashSet<Exchange> = registry
+        .registrations()
+        .filter(|registration| registration.supports_post_only)
+        .map(|registration| registration.exchange)
+        .collect();
+    for (name, cashout_config, direction, default_state_path, parameter_updates) in [
+        (
+            "cashout",
+            &config.cashout,
+            Direction::Sell,
+            "cashout_state.json",
+            parameters.cashout.subscribe(),
+        ),
+        (
+            "accumulate",
+            &config.accumulate,
+            Direction::Buy,
+            "accumulate_state.json",
+            parameters.accumulate.subscribe(),
+        ),
+    ] {
+        if !cashout_config.enabled {
+            continue;
         }
+        let pause = PauseSwitch::new();
+        admin = admin.with_processor(name, pause.clone());
+        strategy_pauses.push(pause.clone());
+        let cashout_config = cashout_config.clone();
+        let subscription_manager = subscription_manager.clone();
+        let order_tx = order_tx.clone();
+        let order_update_tx = order_update_tx.clone();
+        let alert_tx = alert_tx.clone();
+        let post_only_exchanges = post_only_exchanges.clone();
+        strategies = strategies.with_fallible_component(name, move |token| {
+            let cashout = build_cashout(
+                &cashout_config,
+                direction,
+                default_state_path,
+                &order_tx,
+                &order_update_tx,
+                latest(&parameter_updates),
+                pause.clone(),
+            )
+            .with_alerts(alert_tx.clone())
+            .with_post_only_exchanges(post_only_exchanges.clone());
+            // Starting over from a corrupt progress file would reset the daily cap
+            if let Some(error) = cashout.state_error() {
+                return Err(error.to_string());
+            }
+            Ok(cashout.run(subscription_manager.subscribe_orderbook_state(), token))
+        });
     }
 
-    if is_enabled("ORDER_EXECUTOR") {
-        let bitrue_client = BitrueClient::new(
-            get_config("BITRUE_API_KEY").unwrap(),
-            get_config("BITRUE_SECRET_KEY").unwrap(),
-        );
-        let lbank_client = LBankClient::new(
-            get_config("LBANK_API_KEY").unwrap(),
-            get_config("LBANK_SECRET_KEY").unwrap(),
-        );
-        let order_executor = OrderExecutorBuilder::new()
-            .with_exchange(Exchange::Bitrue, bitrue_client)
-            .with_exchange(Exchange::LBank, lbank_client)
-            .build();
+    let stop_subscribers = CancellationToken::new();
+    let stop_executor = CancellationToken::new();
+    let stop_strategies = CancellationToken::new();
+    let subscribers = subscribers.run(stop_subscribers.clone(), shutdown.clone());
+    let executor = executor.run(stop_executor.clone(), shutdown.clone());
+    let strategies = strategies.run(stop_strategies.clone(), shutdown.clone());
 
-        let handle = order_executor.run(order_rx, shutdown.clone());
+    if config.admin.enabled {
+        // Balances are only available for exchanges with credentials
+        for (exchange, exchange_config) in config.exchanges.iter() {
+            if let (Ok(registration), true) =
+                (registry.get(exchange), has_credentials(exchange_config))
+            {
+                admin = admin.with_exchange(exchange, client(registration, exchange_config));
+            }
+        }
+        let handle = admin.run(
+            config.admin.bind,
+            subscription_manager.subscribe_orderbook_state(),
+            order_tx.subscribe(),
+            order_update_tx.subscribe(),
+            shutdown.clone(),
+        );
         handles.push(handle);
     }
 
-    tokio::signal::ctrl_c().await.unwrap();
+    if config.metrics.enabled {
+        handles.push(metrics::run(config.metrics.bind, shutdown.clone()));
+    }
+
+    handles.push(reload::run(config_path, parameters, shutdown.clone()));
+
+    // The supervisor cancels the shutdown token itself if a critical component fails
+    let failed = select! {
+        _ = tokio::signal::ctrl_c() => false,
+        _ = shutdown.cancelled() => true,
+    };
 
     info!("Service stopping");
+    // Stops the admin API first so nothing can be resumed while shutting down
     shutdown.cancel();
+
+    // Pausing takes effect straight away, the strategies keep running to track the fills of the
+    // orders cancelled below
+    info!("Shutdown: Stopping strategies");
+    for pause in &strategy_pauses {
+        pause.pause();
+    }
+
+    // The executor enforces the timeouts of draining its queue and cancelling the open orders
+    let shutdown_config = &config.shutdown;
+    let executor_timeout = Duration::from_secs(
+        shutdown_config.drain_timeout_secs + shutdown_config.cancel_timeout_secs,
+    );
+    stop_phase(
+        "Draining and cancelling orders",
+        &stop_executor,
+        executor,
+        executor_timeout,
+    )
+    .await;
+    stop_phase(
+        "Saving strategy state",
+        &stop_strategies,
+        strategies,
+        Duration::from_secs(shutdown_config.snapshot_timeout_secs),
+    )
+    .await;
+    stop_phase(
+        "Closing websockets",
+        &stop_subscribers,
+        subscribers,
+        Duration::from_secs(shutdown_config.websocket_timeout_secs),
+    )
+    .await;
+
     for handle in handles {
         handle.await.unwrap();
     }
+    stop_alerting.cancel();
+    if let Some(alerting) = alerting {
+        alerting.await.unwrap();
+    }
     info!("Service stopped");
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+async fn record(path: &Path, exchanges: Vec<Exchange>) -> Result<(), String> {
+    let subscription_manager = SubscriptionManager::new();
+    let updates = subscription_manager.subscribe_orderbook_state();
+    let token = CancellationToken::new();
+    let registry = registry();
+    let registrations = if exchanges.is_empty() {
+        registry.registrations().copied().collect()
+    } else {
+        exchanges
+            .into_iter()
+            .map(|e| registry.get(e).copied())
+            .collect::<Result<_, _>>()?
+    };
+    let subscriber = Subscriber::new(registrations).run(&subscription_manager, token.clone());
+
+    info!("Recording to {path:?}");
+    let recording = tokio::spawn({
+        let path = path.to_path_buf();
+        let token = token.clone();
+        async move { recording::record(&path, updates, token).await }
+    });
+
+    let _ = tokio::signal::ctrl_c().await;
+    token.cancel();
+    subscriber.await.map_err(|e| e.to_string())?;
+
+    let written = recording.await.map_err(|e| e.to_string())??;
+    info!("Recorded {written} orderbook states");
+    Ok(())
+}
+
+async fn replay(config: &Config, path: &Path, speed: f64) -> Result<(), String> {
+    if !(speed > 0.0 && speed.is_finite()) {
+        return Err(format!("Invalid speed: {speed}"));
+    }
+    let states = recording::load(path)?;
+    info!("Replaying {} orderbook states", states.len());
+
+    let (state_tx, state_rx) = channel(1024);
+    let (order_tx, mut order_rx) = channel::<Arc<OrderRequest>>(1024);
+    let token = CancellationToken::new();
+    let arb_finder = ArbFinder::new(order_tx)
+        .with_parameters(config.arb_finder.parameters())
+        .run(state_rx, token.clone());
+
+    // The orders are only logged, nothing is sent to the exchanges
+    let orders = tokio::spawn(async move {
+        loop {
+            match order_rx.recv().await {
+                Ok(request) => info!("Replay: {request:?}"),
+                Err(RecvError::Lagged(skipped)) => warn!("Replay: {skipped} orders not logged"),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    select! {
+        _ = recording::replay(states, &state_tx, speed, token.clone()) => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    token.cancel();
+    arb_finder.await.map_err(|e| e.to_string())?;
+    orders.await.map_err(|e| e.to_string())
+}
+
+async fn backtest(config: &Config, path: &Path) -> Result<(), String> {
+    let states = recording::load(path)?
+        .into_iter()
+        .map(|r| r.state)
+        .collect();
+    let result = backtest::run(config, states).await;
+    println!("{result}");
+    Ok(())
+}
+
+// Stops one group of components, moving on if they don't all stop within the timeout so a stuck
+// component can't hold up the rest of the shutdown
+async fn stop_phase(
+    name: &str,
+    stop: &CancellationToken,
+    handle: JoinHandle<()>,
+    timeout: Duration,
+) {
+    info!("Shutdown: {name}");
+    stop.cancel();
+    if tokio::time::timeout(timeout, handle).await.is_err() {
+        warn!("Shutdown: {name} timed out after {timeout:?}");
+    }
+}
+
+// Subscribes to a parameter channel such that the current value is delivered straight away, so
+// components which are restarted pick up any parameters reloaded since the service started
+fn latest<T>(receiver: &watch::Receiver<T>) -> watch::Receiver<T> {
+    let mut receiver = receiver.clone();
+    receiver.mark_changed();
+    receiver
+}
+
+// Waits for the next message, skipping any missed while lagging behind. Never completes once the
+// channel has closed, so a component which isn't running doesn't stop the others being tracked.
+async fn recv<T: Clone>(receiver: &mut Receiver<T>) -> T {
+    loop {
+        match receiver.recv().await {
+            Ok(value) => return value,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+fn build_cashout(
+    config: &CashoutConfig,
+    direction: Direction,
+    default_state_path: &str,
+    order_tx: &Sender<Arc<OrderRequest>>,
+    order_update_tx: &Sender<Arc<OrderUpdate>>,
+    parameter_updates: watch::Receiver<CashoutParameters>,
+    pause: PauseSwitch,
+) -> Cashout {
+    let target = config.target(direction);
+    let mut cashout = Cashout::new(
+        config.amount_per_day.unwrap_or_default(),
+        config.amount_per_iteration(),
+        config.price_limit,
+        order_tx.clone(),
+    )
+    .with_direction(direction)
+    .with_schedule(
+        config
+            .schedule(target)
+            .expect("Schedule is checked during validation"),
+    )
+    .with_state_path(config.state_path(default_state_path))
+    .with_allocation(config.allocation)
+    .with_target(target)
+    .with_guards(config.guard_config())
+    .with_calendar(config.calendar())
+    .with_parameter_updates(parameter_updates)
+    .with_pause_switch(pause)
+    .with_order_updates(order_update_tx.subscribe());
+
+    if let Some(ladder_config) = config.ladder_config() {
+        cashout = cashout.with_ladder(ladder_config);
+    }
+
+    cashout
+}
+
+// Every exchange the service can trade on. Each crate names its own exchange, so adding one only
+// needs its crate registered here.
+fn registry() -> ExchangeRegistry {
+    ExchangeRegistry::new()
+        .with_exchange(xb_exchanges_bitrue::registration())
+        .with_exchange(xb_exchanges_lbank::registration())
 }
 
-pub fn abort_on_panic() {
-    let default_hook = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |panic_info| {
-        default_hook(panic_info);
-        std::process::abort();
-    }));
+fn client(
+    registration: &ExchangeRegistration,
+    config: &ExchangeConfig,
+) -> Box<dyn ExchangeOrderExecutor> {
+    (registration.client)(
+        config.api_key.clone().unwrap_or_default(),
+        config.secret_key.clone().unwrap_or_default(),
+    )
 }
 
-fn is_enabled(name: &str) -> bool {
-    get_config(&format!("{name}_ENABLED")).unwrap_or_default()
+fn has_credentials(config: &ExchangeConfig) -> bool {
+    config.api_key.is_some() && config.secret_key.is_some()
 }
 
-fn get_config<T: FromStr>(key: &str) -> Option<T> {
-    let value = dotenv::var(key).ok()?;
-    Some(T::from_str(&value).unwrap_or_else(|_| panic!("Failed to read config value: {key}")))
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
 }
@@ -0,0 +1,38 @@
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+// Serves the metrics registered by every component in the prometheus text format on /metrics
+pub fn run(bind: SocketAddr, cancellation_token: CancellationToken) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(bind).await {
+            Ok(listener) => listener,
+            Err(error) => {
+                error!("Metrics: Failed to bind to {bind}: {error}");
+                return;
+            }
+        };
+        info!("Metrics: Listening on {bind}");
+
+        let router = Router::new().route("/metrics", get(metrics));
+        if let Err(error) = axum::serve(listener, router)
+            .with_graceful_shutdown(cancellation_token.cancelled_owned())
+            .await
+        {
+            error!("Metrics: Server failed: {error}");
+        }
+    })
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        xb_types::metrics::encode(),
+    )
+}
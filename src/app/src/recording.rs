@@ -0,0 +1,183 @@
+use crate::now_ms;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use xb_types::OrderbookState;
+
+// A line of a recording. The time it was received paces the replay, as the exchanges' own
+// timestamps come from different clocks and aren't always set.
+#[derive(Serialize, Deserialize)]
+pub struct RecordedState {
+    // Missing from recordings made before it was added
+    #[serde(default)]
+    pub received_ms: Option<u64>,
+    #[serde(flatten)]
+    pub state: OrderbookState,
+}
+
+// Writes each orderbook state received to the file as a line of JSON until cancelled, returning
+// the number of states written
+pub async fn record(
+    path: &Path,
+    mut updates: Receiver<Arc<OrderbookState>>,
+    cancellation_token: CancellationToken,
+) -> Result<u64, String> {
+    let file = File::create(path).map_err(|e| format!("Failed to create {path:?}: {e}"))?;
+    let mut writer = BufWriter::new(file);
+    let mut written = 0;
+
+    loop {
+        select! {
+            next = updates.recv() => match next {
+                Ok(state) => {
+                    let recorded = RecordedState {
+                        received_ms: Some(now_ms()),
+                        state: state.as_ref().clone(),
+                    };
+                    serde_json::to_writer(&mut writer, &recorded)
+                        .map_err(|e| format!("Failed to write {path:?}: {e}"))?;
+                    writeln!(writer).map_err(|e| format!("Failed to write {path:?}: {e}"))?;
+                    written += 1;
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Recording lagging, {skipped} orderbook states dropped");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = cancellation_token.cancelled() => break,
+        }
+    }
+
+    writer
+        .flush()
+        .map_err(|e| format!("Failed to write {path:?}: {e}"))?;
+    Ok(written)
+}
+
+pub fn load(path: &Path) -> Result<Vec<RecordedState>, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {path:?}: {e}"))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|l| l.trim().is_empty()))
+        .map(|(i, line)| {
+            let line = line.map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+            serde_json::from_str(&line)
+                .map_err(|e| format!("Invalid orderbook state on line {} of {path:?}: {e}", i + 1))
+        })
+        .collect()
+}
+
+// Publishes the states with the same gaps between them as when they were received, divided by the
+// speed. Older recordings fall back to the exchange's timestamp, with unset ones not waited for.
+pub async fn replay(
+    states: Vec<RecordedState>,
+    sender: &Sender<Arc<OrderbookState>>,
+    speed: f64,
+    cancellation_token: CancellationToken,
+) {
+    let mut previous_timestamp_ms = None;
+    for RecordedState { received_ms, state } in states {
+        let timestamp_ms = received_ms.or((state.timestamp_ms > 0).then_some(state.timestamp_ms));
+        if let (Some(previous), Some(timestamp_ms)) = (previous_timestamp_ms, timestamp_ms) {
+            let gap = Duration::from_millis(timestamp_ms.saturating_sub(previous));
+            select! {
+                _ = tokio::time::sleep(gap.div_f64(speed)) => {}
+                _ = cancellation_token.cancelled() => return,
+            }
+        }
+        previous_timestamp_ms = timestamp_ms.or(previous_timestamp_ms);
+
+        // Sending only fails if there are no subscribers, in which case there is nothing to do
+        let _ = sender.send(Arc::new(state));
+    }
+
+    info!("Replay finished");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+    use std::collections::BTreeMap;
+    use tokio::sync::broadcast::channel;
+    use xb_types::{Exchange, LatencyTrace};
+
+    const BITRUE: Exchange = Exchange::new("bitrue");
+    const LBANK: Exchange = Exchange::new("lbank");
+
+    #[tokio::test]
+    async fn recorded_states_load_back() {
+        let path = std::env::temp_dir().join(format!("xb_recording_{}.jsonl", std::process::id()));
+        let (sender, receiver) = channel(16);
+        let token = CancellationToken::new();
+
+        let recording = tokio::spawn({
+            let path = path.clone();
+            let token = token.clone();
+            async move { record(&path, receiver, token).await }
+        });
+
+        let state = OrderbookState {
+            exchange: LBANK,
+            timestamp_ms: 1_700_000_000_000,
+            asks: BTreeMap::from([(Decimal::new(51, 2), Decimal::from(100))]),
+            bids: BTreeMap::from([(Decimal::new(49, 2), Decimal::from(200))]),
+            invalidated: false,
+            trace: LatencyTrace::start(),
+        };
+        sender.send(Arc::new(state.clone())).unwrap();
+        drop(sender);
+
+        assert_eq!(recording.await.unwrap(), Ok(1));
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), 1);
+        assert!(loaded[0].received_ms.is_some());
+        assert_eq!(loaded[0].state.exchange, state.exchange);
+        assert_eq!(loaded[0].state.timestamp_ms, state.timestamp_ms);
+        assert_eq!(loaded[0].state.asks, state.asks);
+        assert_eq!(loaded[0].state.bids, state.bids);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_mixed_exchanges_at_received_pace() {
+        let recorded = |exchange: &str, timestamp_ms: u64, received_ms: Option<u64>| {
+            serde_json::from_value::<RecordedState>(serde_json::json!({
+                "exchange": exchange,
+                "timestamp_ms": timestamp_ms,
+                "received_ms": received_ms,
+                "asks": {},
+                "bids": {},
+            }))
+            .unwrap()
+        };
+        let states = vec![
+            recorded("LBank", 0, Some(1_700_000_000_000)),
+            recorded("Bitrue", 1_700_000_000_500, Some(1_700_000_001_000)),
+            recorded("LBank", 0, Some(1_700_000_003_000)),
+            // Recorded before received times were, the unset timestamp isn't waited for
+            recorded("LBank", 0, None),
+        ];
+        let (sender, mut receiver) = channel(16);
+        let start = tokio::time::Instant::now();
+
+        replay(states, &sender, 2.0, CancellationToken::new()).await;
+
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+        let mut exchanges = Vec::new();
+        while let Ok(state) = receiver.try_recv() {
+            exchanges.push(state.exchange);
+        }
+        assert_eq!(exchanges, vec![LBANK, BITRUE, LBANK, LBANK]);
+    }
+}
@@ -0,0 +1,182 @@
+use crate::config::Config;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+use xb_arb_finder::ArbFinderParameters;
+use xb_cashout::CashoutParameters;
+use xb_order_executor::RiskLimits;
+
+// Publishes the parameters which can be changed without a restart to the components using them
+pub struct ParameterSenders {
+    pub arb_finder: watch::Sender<ArbFinderParameters>,
+    pub cashout: watch::Sender<CashoutParameters>,
+    pub accumulate: watch::Sender<CashoutParameters>,
+    pub risk_limits: watch::Sender<RiskLimits>,
+    // The strategies started with the service, whose parameters must stay valid even if the
+    // reloaded file disables them, as that only takes effect on a restart
+    running: Vec<&'static str>,
+}
+
+impl ParameterSenders {
+    pub fn new(config: &Config) -> ParameterSenders {
+        ParameterSenders {
+            arb_finder: watch::Sender::new(config.arb_finder.parameters()),
+            cashout: watch::Sender::new(config.cashout.parameters()),
+            accumulate: watch::Sender::new(config.accumulate.parameters()),
+            risk_limits: watch::Sender::new(config.risk.limits()),
+            running: [
+                ("cashout", config.cashout.enabled),
+                ("accumulate", config.accumulate.enabled),
+            ]
+            .into_iter()
+            .filter_map(|(name, enabled)| enabled.then_some(name))
+            .collect(),
+        }
+    }
+
+    fn check(&self, config: &Config) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        for name in &self.running {
+            let cashout_config = match *name {
+                "cashout" => &config.cashout,
+                _ => &config.accumulate,
+            };
+            cashout_config.validate_parameters(name, &mut errors);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn apply(&self, config: &Config) {
+        update(
+            &self.arb_finder,
+            config.arb_finder.parameters(),
+            "arb_finder",
+        );
+        update(&self.cashout, config.cashout.parameters(), "cashout");
+        update(
+            &self.accumulate,
+            config.accumulate.parameters(),
+            "accumulate",
+        );
+        update(&self.risk_limits, config.risk.limits(), "risk");
+    }
+}
+
+// Reloads the config file on SIGHUP. Only the strategy parameters and risk limits are applied, and
+// only if the whole file is valid, anything else needs a restart to take effect.
+pub fn run(
+    config_path: PathBuf,
+    senders: ParameterSenders,
+    cancellation_token: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut hangup = signal(SignalKind::hangup()).unwrap();
+
+        loop {
+            select! {
+                _ = hangup.recv() => reload(&config_path, &senders),
+                _ = cancellation_token.cancelled() => break,
+            }
+        }
+    })
+}
+
+fn reload(config_path: &PathBuf, senders: &ParameterSenders) {
+    info!("Reloading configuration from {config_path:?}");
+    match Config::load(config_path).and_then(|config| {
+        senders.check(&config)?;
+        Ok(config)
+    }) {
+        Ok(config) => senders.apply(&config),
+        Err(errors) => {
+            error!("Invalid configuration, keeping the current parameters:");
+            for error in errors {
+                error!("  {error}");
+            }
+        }
+    }
+}
+
+fn update<T: Debug + PartialEq>(sender: &watch::Sender<T>, value: T, section: &str) {
+    sender.send_if_modified(|current| {
+        if *current == value {
+            return false;
+        }
+        info!("Reloaded {section} parameters: {value:?}");
+        *current = value;
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    const CONFIG: &str = r#"
+        [cashout]
+        enabled = true
+        amount_per_day = 10000
+    "#;
+
+    #[test]
+    fn keeps_parameters_of_running_strategies_valid() {
+        let config = Config::from_table(CONFIG.parse().unwrap()).unwrap();
+        let senders = ParameterSenders::new(&config);
+
+        // Disabling a running strategy only takes effect on a restart, its parameters still apply
+        let disabled = Config::from_table(
+            r#"
+            [cashout]
+            enabled = false
+            "#
+            .parse()
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            senders.check(&disabled),
+            Err(vec!["cashout.amount_per_day is required".to_string()])
+        );
+
+        let zero = Config::from_table(
+            r#"
+            [cashout]
+            amount_per_day = 0
+            "#
+            .parse()
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            senders.check(&zero),
+            Err(vec!["cashout.amount_per_day must be positive".to_string()])
+        );
+
+        // Accumulate isn't running, so its parameters don't matter
+        let reloaded = Config::from_table(
+            r#"
+            [cashout]
+            amount_per_day = 20000
+            "#
+            .parse()
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(senders.check(&reloaded), Ok(()));
+        senders.apply(&reloaded);
+        assert_eq!(
+            senders.cashout.borrow().amount_per_day,
+            Decimal::from(20000)
+        );
+    }
+}
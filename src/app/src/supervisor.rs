@@ -0,0 +1,250 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::broadcast::Sender;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use xb_types::metrics::COMPONENT_RESTARTS;
+use xb_types::Alert;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// A component which has run for this long is considered healthy again, resetting its backoff
+const STABLE_AFTER: Duration = Duration::from_secs(5 * 60);
+
+type StartFn = Box<dyn FnMut(CancellationToken) -> Result<JoinHandle<()>, String> + Send>;
+
+// Owns a group of long running components, restarting any which panic or stop unexpectedly.
+// Failures of critical components, which can't safely be restarted, shut everything down instead.
+pub struct Supervisor {
+    components: Vec<Component>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    alerts: Option<Sender<Arc<Alert>>>,
+}
+
+struct Component {
+    name: &'static str,
+    critical: bool,
+    // Builds and spawns a fresh instance of the component
+    start: StartFn,
+}
+
+impl Supervisor {
+    pub fn new() -> Supervisor {
+        Supervisor {
+            components: Vec::new(),
+            initial_backoff: INITIAL_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+            alerts: None,
+        }
+    }
+
+    pub fn with_component<F>(self, name: &'static str, mut start: F) -> Self
+    where
+        F: FnMut(CancellationToken) -> JoinHandle<()> + Send + 'static,
+    {
+        self.with_fallible_component(name, move |token| Ok(start(token)))
+    }
+
+    // The component may refuse to start, which is treated as a critical failure as restarting it
+    // would only fail again
+    pub fn with_fallible_component<F>(mut self, name: &'static str, start: F) -> Self
+    where
+        F: FnMut(CancellationToken) -> Result<JoinHandle<()>, String> + Send + 'static,
+    {
+        self.components.push(Component {
+            name,
+            critical: false,
+            start: Box::new(start),
+        });
+        self
+    }
+
+    // The component is started once, and if it fails the whole service is shut down
+    pub fn with_critical_component<F>(mut self, name: &'static str, mut start: F) -> Self
+    where
+        F: FnMut(CancellationToken) -> JoinHandle<()> + Send + 'static,
+    {
+        self.components.push(Component {
+            name,
+            critical: true,
+            start: Box::new(move |token| Ok(start(token))),
+        });
+        self
+    }
+
+    // Each failure will be published to this channel
+    pub fn with_alerts(mut self, sender: Sender<Arc<Alert>>) -> Self {
+        self.alerts = Some(sender);
+        self
+    }
+
+    #[cfg(test)]
+    fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    // Starts every component in the order they were added, before returning a handle which
+    // completes once they have all stopped. The components are stopped by cancelling the stop
+    // token, while a critical failure cancels the shutdown token so the service can stop the rest.
+    pub fn run(self, stop: CancellationToken, shutdown: CancellationToken) -> JoinHandle<()> {
+        let mut supervised = Vec::new();
+        for mut component in self.components {
+            let started = (component.start)(stop.clone());
+            supervised.push(tokio::spawn(supervise(
+                component,
+                started,
+                self.initial_backoff,
+                self.max_backoff,
+                self.alerts.clone(),
+                stop.clone(),
+                shutdown.clone(),
+            )));
+        }
+
+        tokio::spawn(async move {
+            futures::future::join_all(supervised).await;
+        })
+    }
+}
+
+async fn supervise(
+    mut component: Component,
+    mut started: Result<JoinHandle<()>, String>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    alerts: Option<Sender<Arc<Alert>>>,
+    stop: CancellationToken,
+    shutdown: CancellationToken,
+) {
+    let name = component.name;
+    let mut backoff = initial_backoff;
+
+    loop {
+        let started_at = Instant::now();
+        let (error, critical) = match started {
+            Ok(handle) => {
+                let result = handle.await;
+                if stop.is_cancelled() {
+                    break;
+                }
+                match result {
+                    Ok(()) => ("Stopped unexpectedly".to_string(), component.critical),
+                    Err(error) => (error.to_string(), component.critical),
+                }
+            }
+            Err(error) => (format!("Failed to start: {error}"), true),
+        };
+        error!("Supervisor: {name} failed: {error}");
+        if let Some(sender) = &alerts {
+            // Sending only fails if there are no subscribers, in which case there is nothing to do
+            let _ = sender.send(Arc::new(Alert::ComponentFailed {
+                component: name,
+                error,
+                critical,
+            }));
+        }
+
+        if critical {
+            error!("Supervisor: {name} is critical, shutting down");
+            shutdown.cancel();
+            break;
+        }
+
+        if started_at.elapsed() >= STABLE_AFTER {
+            backoff = initial_backoff;
+        }
+        warn!("Supervisor: Restarting {name} in {backoff:?}");
+        select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = stop.cancelled() => break,
+        }
+        backoff = (backoff * 2).min(max_backoff);
+
+        COMPONENT_RESTARTS.with_label_values(&[name]).inc();
+        info!("Supervisor: Restarting {name}");
+        started = (component.start)(stop.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn restarts_failed_components() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let stop = CancellationToken::new();
+        let shutdown = CancellationToken::new();
+
+        let counter = starts.clone();
+        let handle = Supervisor::new()
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(1))
+            .with_component("flaky", move |token| {
+                let attempt = counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    if attempt < 2 {
+                        panic!("Failed on attempt {attempt}");
+                    }
+                    token.cancelled().await;
+                })
+            })
+            .run(stop.clone(), shutdown.clone());
+
+        while starts.load(Ordering::SeqCst) < 3 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(!shutdown.is_cancelled());
+
+        stop.cancel();
+        handle.await.unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn critical_failure_shuts_down() {
+        let stop = CancellationToken::new();
+        let shutdown = CancellationToken::new();
+
+        let handle = Supervisor::new()
+            .with_component("healthy", |token| {
+                tokio::spawn(async move { token.cancelled().await })
+            })
+            .with_critical_component("critical", |_| tokio::spawn(async {}))
+            .run(stop.clone(), shutdown.clone());
+
+        // The other components keep running until they're stopped
+        shutdown.cancelled().await;
+        assert!(!handle.is_finished());
+
+        stop.cancel();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refusing_to_start_shuts_down() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let stop = CancellationToken::new();
+        let shutdown = CancellationToken::new();
+
+        let counter = starts.clone();
+        let handle = Supervisor::new()
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(1))
+            .with_fallible_component("refusing", move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Err("Corrupt state".to_string())
+            })
+            .run(stop.clone(), shutdown.clone());
+
+        shutdown.cancelled().await;
+        handle.await.unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+    }
+}
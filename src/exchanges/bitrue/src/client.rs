@@ -1,11 +1,20 @@
+use crate::{EXCHANGE, SYMBOL};
 use async_trait::async_trait;
 use hmac::{Hmac, Mac};
-use reqwest::Client;
+use reqwest::{Client, Method};
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use sha2::Sha256;
-use std::collections::BTreeMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tracing::info;
-use xb_types::{ExchangeOrderExecutor, PendingOrder};
+use xb_types::metrics::REST_LATENCY;
+use xb_types::{
+    Balance, Direction, ExchangeOrderExecutor, OpenOrder, OrderStatus, PendingLimitOrder,
+    PendingOrder, TimeInForce,
+};
 
 const BASE_URL: &str = "https://openapi.bitrue.com";
 
@@ -24,7 +33,21 @@ impl BitrueClient {
         }
     }
 
-    async fn post_request(&self, path: &str, params: BTreeMap<&'static str, String>) {
+    async fn send_request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        mut params: BTreeMap<&'static str, String>,
+    ) -> Result<T, String> {
+        params.insert(
+            "timestamp",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+                .to_string(),
+        );
+
         let mut query = String::new();
         for (key, value) in params {
             push_query_param(&mut query, key, value.as_str());
@@ -33,20 +56,35 @@ impl BitrueClient {
         let sig = self.get_signature(&query);
         push_query_param(&mut query, "signature", &sig);
 
+        let started = Instant::now();
         let response = self
             .client
-            .post(format!("{BASE_URL}{path}?{query}"))
+            .request(method, format!("{BASE_URL}{path}?{query}"))
             .header("contentType", "application/x-www-form-urlencoded")
             .header("X-MBX-APIKEY", self.api_key.clone())
             .send()
             .await
-            .unwrap();
+            .map_err(|e| format!("Bitrue: Request failed: {e}"))?;
 
         info!("Bitrue: Response: {response:?}");
 
-        let content = response.text().await.unwrap();
+        let status = response.status();
+        let content = response
+            .text()
+            .await
+            .map_err(|e| format!("Bitrue: Failed to read response: {e}"))?;
+        REST_LATENCY
+            .with_label_values(&[EXCHANGE.as_str(), path])
+            .observe(started.elapsed().as_secs_f64());
 
         info!("Bitrue: Response content: {content}");
+
+        if !status.is_success() {
+            return Err(format!("Bitrue: Request failed: {content}"));
+        }
+
+        serde_json::from_str(&content)
+            .map_err(|e| format!("Bitrue: Failed to parse response: {e}. Content: {content}"))
     }
 
     fn get_signature(&self, query: &str) -> String {
@@ -62,7 +100,7 @@ impl BitrueClient {
 impl ExchangeOrderExecutor for BitrueClient {
     async fn submit_order(&self, order: PendingOrder) -> Result<String, String> {
         let mut params = BTreeMap::new();
-        params.insert("symbol", "chatusdt".to_string());
+        params.insert("symbol", SYMBOL.to_string());
         params.insert("quantity", order.amount().to_string());
         params.insert(
             "side",
@@ -73,18 +111,11 @@ impl ExchangeOrderExecutor for BitrueClient {
             })
             .to_string(),
         );
-        params.insert(
-            "timestamp",
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-                .to_string(),
-        );
 
         match order {
             PendingOrder::Limit(o) => {
                 params.insert("type", "LIMIT".to_string());
+                params.insert("timeInForce", time_in_force(&o)?.to_string());
                 params.insert("price", o.price.to_string());
             }
             PendingOrder::Market(_) => {
@@ -92,9 +123,151 @@ impl ExchangeOrderExecutor for BitrueClient {
             }
         }
 
-        self.post_request("/api/v1/order", params).await;
+        let response: OrderResponse = self
+            .send_request(Method::POST, "/api/v1/order", params)
+            .await?;
+
+        Ok(response.order_id.to_string())
+    }
+
+    async fn cancel_order(&self, order_id: &str) -> Result<(), String> {
+        let mut params = BTreeMap::new();
+        params.insert("symbol", SYMBOL.to_string());
+        params.insert("orderId", order_id.to_string());
+
+        self.send_request::<OrderResponse>(Method::DELETE, "/api/v1/order", params)
+            .await
+            .map(|_| ())
+    }
+
+    async fn get_order(&self, order_id: &str) -> Result<OrderStatus, String> {
+        let mut params = BTreeMap::new();
+        params.insert("symbol", SYMBOL.to_string());
+        params.insert("orderId", order_id.to_string());
+
+        let response: OrderInfoResponse = self
+            .send_request(Method::GET, "/api/v1/order", params)
+            .await?;
+
+        Ok(OrderStatus {
+            filled_amount: response.executed_qty,
+            filled_value: response.cummulative_quote_qty,
+            is_open: matches!(response.status.as_str(), "NEW" | "PARTIALLY_FILLED"),
+        })
+    }
 
-        todo!()
+    async fn get_balances(&self) -> Result<HashMap<String, Balance>, String> {
+        let response: AccountResponse = self
+            .send_request(Method::GET, "/api/v1/account", BTreeMap::new())
+            .await?;
+
+        Ok(response
+            .balances
+            .into_iter()
+            .map(|b| {
+                let balance = Balance {
+                    free: b.free,
+                    locked: b.locked,
+                };
+                (b.asset.to_uppercase(), balance)
+            })
+            .collect())
+    }
+
+    async fn get_open_orders(&self) -> Result<Vec<OpenOrder>, String> {
+        let mut params = BTreeMap::new();
+        params.insert("symbol", SYMBOL.to_string());
+
+        let response: Vec<OpenOrderInfo> = self
+            .send_request(Method::GET, "/api/v1/openOrders", params)
+            .await?;
+
+        Ok(response
+            .into_iter()
+            .map(|o| OpenOrder {
+                exchange_order_id: o.order_id.to_string(),
+                direction: if o.side == "BUY" {
+                    Direction::Buy
+                } else {
+                    Direction::Sell
+                },
+                price: o.price,
+                amount: o.orig_qty,
+                filled_amount: o.executed_qty,
+            })
+            .collect())
+    }
+
+    fn check_order_supported(&self, order: &PendingOrder) -> Result<(), String> {
+        match order {
+            PendingOrder::Limit(o) => time_in_force(o).map(|_| ()),
+            PendingOrder::Market(_) => Ok(()),
+        }
+    }
+}
+
+fn time_in_force(order: &PendingLimitOrder) -> Result<&'static str, String> {
+    if order.post_only {
+        return Err("Bitrue does not support post-only orders".to_string());
+    }
+
+    Ok(match order.time_in_force {
+        TimeInForce::GoodTillCancelled => "GTC",
+        TimeInForce::ImmediateOrCancel => "IOC",
+        TimeInForce::FillOrKill => "FOK",
+    })
+}
+
+#[derive(Deserialize)]
+struct OrderResponse {
+    #[serde(rename = "orderId")]
+    order_id: OrderId,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OrderInfoResponse {
+    executed_qty: Decimal,
+    cummulative_quote_qty: Decimal,
+    status: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OpenOrderInfo {
+    order_id: OrderId,
+    price: Decimal,
+    orig_qty: Decimal,
+    executed_qty: Decimal,
+    side: String,
+}
+
+#[derive(Deserialize)]
+struct AccountResponse {
+    balances: Vec<AssetBalance>,
+}
+
+#[derive(Deserialize)]
+struct AssetBalance {
+    asset: String,
+    free: Decimal,
+    locked: Decimal,
+}
+
+// Bitrue returns order Ids as numbers in some responses and as strings in others
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OrderId {
+    Number(u64),
+    String(String),
+}
+
+impl Display for OrderId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrderId::Number(n) => write!(f, "{n}"),
+            OrderId::String(s) => f.write_str(s),
+        }
     }
 }
 
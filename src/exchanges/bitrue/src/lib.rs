@@ -1,4 +1,5 @@
 use serde::Serialize;
+use xb_types::{Exchange, ExchangeRegistration, ExchangeSubscriber};
 
 mod client;
 mod subscriber;
@@ -6,6 +7,25 @@ mod subscriber;
 pub use client::BitrueClient;
 pub use subscriber::BitrueSubscriber;
 
+pub const EXCHANGE: Exchange = Exchange::new("bitrue");
+
+const SYMBOL: &str = "chatusdt";
+
+pub fn registration() -> ExchangeRegistration {
+    ExchangeRegistration {
+        exchange: EXCHANGE,
+        symbol: SYMBOL,
+        subscriber_name: "bitrue_subscriber",
+        supports_post_only: false,
+        subscriber: |policy, sender, token| {
+            BitrueSubscriber::default()
+                .with_reconnect_policy(policy)
+                .run_async(sender, token)
+        },
+        client: |api_key, secret_key| Box::new(BitrueClient::new(api_key, secret_key)),
+    }
+}
+
 fn serialize_to_json<S: Serialize>(value: &S) -> String {
     serde_json::to_string(value).unwrap()
 }
@@ -1,142 +1,89 @@
-use crate::serialize_to_json;
-use async_trait::async_trait;
-use ezsockets::client::ClientCloseMode;
-use ezsockets::{ClientConfig, ClientExt, Error, MessageSignal, WSError};
+use crate::{serialize_to_json, EXCHANGE, SYMBOL};
 use flate2::bufread::GzDecoder;
-use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use std::io::Read;
-use std::str::FromStr;
-use std::sync::Arc;
-use tokio::select;
-use tokio::sync::broadcast::Sender;
-use tokio_util::sync::CancellationToken;
-use tracing::{error, info, trace};
-use xb_types::{Exchange, ExchangeSubscriber, OrderbookState};
+use xb_exchanges_websocket::{Depth, Frame, Message, WebSocketCodec, WebSocketSubscriber};
+use xb_types::Exchange;
 
 const URL: &str = "wss://ws.bitrue.com/market/ws";
 
+pub type BitrueSubscriber = WebSocketSubscriber<BitrueCodec>;
+
 #[derive(Default)]
-pub struct BitrueSubscriber {}
+pub struct BitrueCodec {}
 
-struct WebSocketClient {
-    handle: ezsockets::Client<Self>,
-    sender: Sender<Arc<OrderbookState>>,
-}
+impl WebSocketCodec for BitrueCodec {
+    fn exchange(&self) -> Exchange {
+        EXCHANGE
+    }
 
-impl WebSocketClient {
-    fn send<S: Serialize>(&mut self, value: &S) -> Result<MessageSignal, Error> {
-        let json = serialize_to_json(&value);
-        trace!("Bitrue: Sending message: {json}");
-        self.handle.text(json).map_err(|e| e.into())
+    fn url(&self) -> &'static str {
+        URL
     }
 
-    fn subscribe(&mut self) -> Result<MessageSignal, Error> {
-        self.send(&Subscribe {
+    fn encode_subscribe(&self) -> Vec<String> {
+        vec![serialize_to_json(&Subscribe {
             event: "sub".to_string(),
             params: SubscribeParams {
-                cb_id: "chatusdt".to_string(),
-                channel: "market_chatusdt_simple_depth_step0".to_string(),
+                cb_id: SYMBOL.to_string(),
+                channel: format!("market_{SYMBOL}_simple_depth_step0"),
             },
-        })
-    }
-}
-
-#[async_trait]
-impl ClientExt for WebSocketClient {
-    type Call = ();
-
-    async fn on_text(&mut self, _: String) -> Result<(), Error> {
-        unreachable!()
-    }
-
-    async fn on_binary(&mut self, bytes: Vec<u8>) -> Result<(), Error> {
-        let mut d = GzDecoder::new(bytes.as_slice());
-        let mut s = String::new();
-        d.read_to_string(&mut s).unwrap();
-        trace!("Bitrue: Received text: {s}");
-
-        if let Ok(m) = serde_json::from_str::<MarketDepth>(&s) {
-            let update = OrderbookState {
-                exchange: Exchange::Bitrue,
-                timestamp_ms: m.timestamp,
-                bids: m
-                    .tick
-                    .buys
-                    .into_iter()
-                    .map(|b| {
-                        (
-                            Decimal::from_str(&b[0]).unwrap(),
-                            Decimal::from_str(&b[1]).unwrap(),
-                        )
-                    })
-                    .collect(),
-                asks: m
-                    .tick
-                    .asks
-                    .into_iter()
-                    .map(|a| {
-                        (
-                            Decimal::from_str(&a[0]).unwrap(),
-                            Decimal::from_str(&a[1]).unwrap(),
-                        )
-                    })
-                    .collect(),
-            };
-            self.sender.send(Arc::new(update)).unwrap();
-        } else if let Ok(Ping { ping }) = serde_json::from_str(&s) {
-            self.send(&Pong { pong: ping }).unwrap();
-        }
-        Ok(())
-    }
-
-    async fn on_call(&mut self, _: Self::Call) -> Result<(), Error> {
-        unreachable!()
-    }
-
-    async fn on_connect(&mut self) -> Result<(), Error> {
-        info!("Bitrue: Connected");
-        self.subscribe().unwrap();
-        Ok(())
+        })]
     }
 
-    async fn on_disconnect(&mut self) -> Result<ClientCloseMode, Error> {
-        info!("Bitrue: Disconnected");
-        Ok(ClientCloseMode::Reconnect)
+    // Every message is gzipped JSON
+    fn decode_frame(&self, frame: Frame) -> Result<String, String> {
+        let Frame::Binary(bytes) = frame else {
+            return Err("Unexpected text frame".to_string());
+        };
+        let mut text = String::new();
+        GzDecoder::new(bytes.as_slice())
+            .read_to_string(&mut text)
+            .map_err(|e| format!("Failed to decompress frame: {e}"))?;
+        Ok(text)
     }
 
-    async fn on_connect_fail(&mut self, error: WSError) -> Result<ClientCloseMode, Error> {
-        error!("Bitrue: Failed to connect: {error:?}");
-        Ok(ClientCloseMode::Reconnect)
+    fn handle_heartbeat(&self, text: &str) -> Option<String> {
+        let Ping { ping } = serde_json::from_str(text).ok()?;
+        Some(serialize_to_json(&Pong { pong: ping }))
     }
-}
 
-#[async_trait]
-impl ExchangeSubscriber for BitrueSubscriber {
-    async fn run_async(
-        self,
-        sender: Sender<Arc<OrderbookState>>,
-        cancellation_token: CancellationToken,
-    ) {
-        info!("BitrueSubscriber started");
-
-        let (handle, future) = ezsockets::connect(
-            |handle| WebSocketClient { handle, sender },
-            ClientConfig::new(URL),
-        )
-        .await;
-
-        select! {
-            _ = future => (),
-            _ = cancellation_token.cancelled() => {
-                handle.close(None).unwrap();
+    fn decode_message(&self, text: &str) -> Result<Message, String> {
+        let error = match serde_json::from_str::<MarketDepth>(text) {
+            Ok(m) => {
+                return Ok(Message::Depth(Depth {
+                    timestamp_ms: Some(m.timestamp),
+                    bids: m.tick.buys,
+                    asks: m.tick.asks,
+                }))
+            }
+            Err(error) => error,
+        };
+
+        // Only parsed again when it isn't a valid depth message, to tell why
+        match serde_json::from_str::<Envelope>(text) {
+            // Replies to the subscription
+            Ok(Envelope {
+                event_rep: Some(_), ..
+            }) => Ok(Message::Ignored),
+            Ok(Envelope {
+                channel: Some(channel),
+                ..
+            }) if channel.ends_with("_depth_step0") => {
+                Err(format!("Invalid depth message: {error}"))
             }
+            Ok(_) => Ok(Message::Unknown),
+            Err(error) => Err(format!("Invalid message: {error}")),
         }
-
-        info!("BitrueSubscriber stopped");
     }
 }
 
+#[derive(Deserialize)]
+struct Envelope {
+    channel: Option<String>,
+    event_rep: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct Ping {
     ping: u64,
@@ -172,3 +119,51 @@ struct MarketDepthInner {
     buys: Vec<[String; 2]>,
     asks: Vec<[String; 2]>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn decodes_messages() {
+        let codec = BitrueCodec::default();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(br#"{"ping":1722470400000}"#).unwrap();
+        let frame = Frame::Binary(encoder.finish().unwrap());
+
+        let text = codec.decode_frame(frame).unwrap();
+        assert_eq!(
+            codec.handle_heartbeat(&text),
+            Some(r#"{"pong":1722470400000}"#.to_string())
+        );
+
+        let Ok(Message::Depth(depth)) = codec.decode_message(
+            r#"{"channel":"market_chatusdt_simple_depth_step0","ts":1722470400000,
+            "tick":{"buys":[["0.49","100"]],"asks":[["0.51","10"]]}}"#,
+        ) else {
+            panic!("Expected depth");
+        };
+        assert_eq!(depth.timestamp_ms, Some(1722470400000));
+        assert_eq!(depth.bids, vec![["0.49".to_string(), "100".to_string()]]);
+
+        assert!(matches!(
+            codec.decode_message(
+                r#"{"event_rep":"subed","channel":"market_chatusdt_simple_depth_step0","status":"ok"}"#
+            ),
+            Ok(Message::Ignored)
+        ));
+        assert!(codec
+            .decode_message(r#"{"channel":"market_chatusdt_simple_depth_step0","ts":1}"#)
+            .unwrap_err()
+            .starts_with("Invalid depth message"));
+        assert!(matches!(
+            codec.decode_message(r#"{"channel":"market_chatusdt_trade_ticker"}"#),
+            Ok(Message::Unknown)
+        ));
+
+        assert!(codec.decode_frame(Frame::Text("{}".to_string())).is_err());
+    }
+}
@@ -1,12 +1,20 @@
+use crate::{EXCHANGE, SYMBOL};
 use async_trait::async_trait;
 use hmac::{Hmac, Mac};
 use rand::random;
 use reqwest::Client;
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use sha2::Sha256;
-use std::collections::BTreeMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tracing::info;
-use xb_types::{ExchangeOrderExecutor, PendingOrder};
+use xb_types::metrics::REST_LATENCY;
+use xb_types::{
+    Balance, Direction, ExchangeOrderExecutor, OpenOrder, OrderStatus, PendingLimitOrder,
+    PendingOrder, TimeInForce,
+};
 
 const BASE_URL: &str = "https://www.lbkex.net";
 
@@ -25,7 +33,11 @@ impl LBankClient {
         }
     }
 
-    async fn post_request(&self, path: &str, mut params: BTreeMap<&'static str, String>) {
+    async fn post_request<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        mut params: BTreeMap<&'static str, String>,
+    ) -> Result<T, String> {
         params.insert("api_key", self.api_key.clone());
         params.insert("echostr", generate_echostr());
         params.insert("signature_method", "HmacSHA256".to_string());
@@ -46,19 +58,34 @@ impl LBankClient {
         let sig = self.get_signature(&query);
         push_query_param(&mut query, "sign", &sig);
 
+        let started = Instant::now();
         let response = self
             .client
             .post(format!("{BASE_URL}{path}?{query}"))
             .header("contentType", "application/x-www-form-urlencoded")
             .send()
             .await
-            .unwrap();
+            .map_err(|e| format!("LBank: Request failed: {e}"))?;
 
         info!("LBank: Response: {response:?}");
 
-        let content = response.text().await.unwrap();
+        let content = response
+            .text()
+            .await
+            .map_err(|e| format!("LBank: Failed to read response: {e}"))?;
+        REST_LATENCY
+            .with_label_values(&[EXCHANGE.as_str(), path])
+            .observe(started.elapsed().as_secs_f64());
 
         info!("LBank: Response content: {content}");
+
+        match serde_json::from_str::<Response<T>>(&content) {
+            Ok(Response {
+                error_code: 0,
+                data: Some(data),
+            }) => Ok(data),
+            _ => Err(format!("LBank: Request failed: {content}")),
+        }
     }
 
     fn get_signature(&self, query: &str) -> String {
@@ -77,20 +104,12 @@ impl LBankClient {
 impl ExchangeOrderExecutor for LBankClient {
     async fn submit_order(&self, order: PendingOrder) -> Result<String, String> {
         let mut params = BTreeMap::new();
-        params.insert("symbol", "chat_usdt".to_string());
+        params.insert("symbol", SYMBOL.to_string());
         params.insert("amount", order.amount().to_string());
 
         match order {
             PendingOrder::Limit(o) => {
-                params.insert(
-                    "type",
-                    (if o.direction.is_buy() {
-                        "buy_maker"
-                    } else {
-                        "sell_maker"
-                    })
-                    .to_string(),
-                );
+                params.insert("type", limit_order_type(&o)?.to_string());
                 params.insert("price", o.price.to_string());
             }
             PendingOrder::Market(o) => {
@@ -106,13 +125,152 @@ impl ExchangeOrderExecutor for LBankClient {
             }
         }
 
-        self.post_request("/v2/supplement/create_order.do", params)
-            .await;
+        let response: CreateOrderResponse = self
+            .post_request("/v2/supplement/create_order.do", params)
+            .await?;
+
+        Ok(response.order_id)
+    }
+
+    async fn cancel_order(&self, order_id: &str) -> Result<(), String> {
+        let mut params = BTreeMap::new();
+        params.insert("symbol", SYMBOL.to_string());
+        params.insert("orderId", order_id.to_string());
+
+        self.post_request::<serde_json::Value>("/v2/supplement/cancel_order.do", params)
+            .await
+            .map(|_| ())
+    }
+
+    async fn get_order(&self, order_id: &str) -> Result<OrderStatus, String> {
+        let mut params = BTreeMap::new();
+        params.insert("symbol", SYMBOL.to_string());
+        params.insert("orderId", order_id.to_string());
+
+        let response: OrderInfoResponse = self
+            .post_request("/v2/supplement/orders_info.do", params)
+            .await?;
+
+        Ok(OrderStatus {
+            filled_amount: response.executed_qty,
+            filled_value: response.cummulative_quote_qty,
+            // 0 = unfilled, 1 = partially filled
+            is_open: matches!(response.status, 0 | 1),
+        })
+    }
+
+    async fn get_balances(&self) -> Result<HashMap<String, Balance>, String> {
+        let response: Vec<AssetInfo> = self
+            .post_request("/v2/supplement/user_info.do", BTreeMap::new())
+            .await?;
+
+        Ok(response
+            .into_iter()
+            .map(|a| {
+                let balance = Balance {
+                    free: a.usable_amt,
+                    locked: a.freeze_amt,
+                };
+                (a.coin.to_uppercase(), balance)
+            })
+            .collect())
+    }
+
+    async fn get_open_orders(&self) -> Result<Vec<OpenOrder>, String> {
+        let mut params = BTreeMap::new();
+        params.insert("symbol", SYMBOL.to_string());
+        params.insert("current_page", "1".to_string());
+        // The largest page allowed, we never have anywhere near this many orders open
+        params.insert("page_length", "100".to_string());
+
+        let response: OpenOrdersResponse = self
+            .post_request("/v2/supplement/orders_info_no_deal.do", params)
+            .await?;
+
+        Ok(response
+            .orders
+            .into_iter()
+            .map(|o| OpenOrder {
+                exchange_order_id: o.order_id,
+                // The type is the side, optionally followed by the order type, eg. buy_maker
+                direction: if o.order_type.starts_with("buy") {
+                    Direction::Buy
+                } else {
+                    Direction::Sell
+                },
+                price: o.price,
+                amount: o.orig_qty,
+                filled_amount: o.executed_qty,
+            })
+            .collect())
+    }
 
-        todo!()
+    fn check_order_supported(&self, order: &PendingOrder) -> Result<(), String> {
+        match order {
+            PendingOrder::Limit(o) => limit_order_type(o).map(|_| ()),
+            PendingOrder::Market(_) => Ok(()),
+        }
+    }
+}
+
+fn limit_order_type(order: &PendingLimitOrder) -> Result<&'static str, String> {
+    let is_buy = order.direction.is_buy();
+    match (order.time_in_force, order.post_only) {
+        (TimeInForce::GoodTillCancelled, false) => Ok(if is_buy { "buy" } else { "sell" }),
+        (TimeInForce::GoodTillCancelled, true) => {
+            Ok(if is_buy { "buy_maker" } else { "sell_maker" })
+        }
+        (TimeInForce::ImmediateOrCancel, false) => Ok(if is_buy { "buy_ioc" } else { "sell_ioc" }),
+        (TimeInForce::FillOrKill, false) => Ok(if is_buy { "buy_fok" } else { "sell_fok" }),
+        (time_in_force, true) => Err(format!(
+            "LBank does not support post-only orders with time in force {time_in_force:?}"
+        )),
     }
 }
 
+#[derive(Deserialize)]
+struct Response<T> {
+    error_code: i64,
+    data: Option<T>,
+}
+
+#[derive(Deserialize)]
+struct CreateOrderResponse {
+    order_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OrderInfoResponse {
+    executed_qty: Decimal,
+    cummulative_quote_qty: Decimal,
+    status: i32,
+}
+
+#[derive(Deserialize)]
+struct OpenOrdersResponse {
+    orders: Vec<OpenOrderInfo>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OpenOrderInfo {
+    order_id: String,
+    price: Decimal,
+    orig_qty: Decimal,
+    executed_qty: Decimal,
+    #[serde(rename = "type")]
+    order_type: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AssetInfo {
+    coin: String,
+    usable_amt: Decimal,
+    freeze_amt: Decimal,
+}
+
 fn push_query_param(q: &mut String, key: &str, value: &str) {
     if !q.is_empty() {
         q.push('&');
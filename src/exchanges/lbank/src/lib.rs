@@ -1,4 +1,5 @@
 use serde::Serialize;
+use xb_types::{Exchange, ExchangeRegistration, ExchangeSubscriber};
 
 mod client;
 mod subscriber;
@@ -6,6 +7,25 @@ mod subscriber;
 pub use client::LBankClient;
 pub use subscriber::LBankSubscriber;
 
+pub const EXCHANGE: Exchange = Exchange::new("lbank");
+
+const SYMBOL: &str = "chat_usdt";
+
+pub fn registration() -> ExchangeRegistration {
+    ExchangeRegistration {
+        exchange: EXCHANGE,
+        symbol: SYMBOL,
+        subscriber_name: "lbank_subscriber",
+        supports_post_only: true,
+        subscriber: |policy, sender, token| {
+            LBankSubscriber::default()
+                .with_reconnect_policy(policy)
+                .run_async(sender, token)
+        },
+        client: |api_key, secret_key| Box::new(LBankClient::new(api_key, secret_key)),
+    }
+}
+
 fn serialize_to_json<S: Serialize>(value: &S) -> String {
     serde_json::to_string(value).unwrap()
 }
@@ -1,141 +1,72 @@
-use crate::serialize_to_json;
-use async_trait::async_trait;
-use ezsockets::client::ClientCloseMode;
-use ezsockets::{ClientConfig, ClientExt, Error, MessageSignal, WSError};
-use rust_decimal::Decimal;
+use crate::{serialize_to_json, EXCHANGE, SYMBOL};
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
-use std::sync::Arc;
-use tokio::select;
-use tokio::sync::broadcast::Sender;
-use tokio_util::sync::CancellationToken;
-use tracing::{error, info, trace};
-use xb_types::{Exchange, ExchangeSubscriber, OrderbookState};
+use xb_exchanges_websocket::{Depth, Message, WebSocketCodec, WebSocketSubscriber};
+use xb_types::Exchange;
 
 const URL: &str = "wss://www.lbkex.net/ws/V2/";
 
+pub type LBankSubscriber = WebSocketSubscriber<LBankCodec>;
+
 #[derive(Default)]
-pub struct LBankSubscriber {}
+pub struct LBankCodec {}
 
-struct WebSocketClient {
-    handle: ezsockets::Client<Self>,
-    sender: Sender<Arc<OrderbookState>>,
-}
+impl WebSocketCodec for LBankCodec {
+    fn exchange(&self) -> Exchange {
+        EXCHANGE
+    }
 
-impl WebSocketClient {
-    fn send(&mut self, value: &Action) -> Result<MessageSignal, Error> {
-        let json = serialize_to_json(&value);
-        trace!("LBank: Sending message: {json}");
-        self.handle.text(json).map_err(|e| e.into())
+    fn url(&self) -> &'static str {
+        URL
     }
 
-    fn subscribe(&mut self) -> Result<MessageSignal, Error> {
-        self.send(&Action::Subscribe(Subscribe::MarketDepth(
-            SubscribeMarketDepth {
-                pair: "chat_usdt".to_string(),
+    fn encode_subscribe(&self) -> Vec<String> {
+        vec![serialize_to_json(&Action::Subscribe(
+            Subscribe::MarketDepth(SubscribeMarketDepth {
+                pair: SYMBOL.to_string(),
                 depth: "10".to_string(),
-            },
-        )))
+            }),
+        ))]
     }
-}
 
-#[async_trait]
-impl ClientExt for WebSocketClient {
-    type Call = ();
-
-    async fn on_text(&mut self, text: String) -> Result<(), Error> {
-        trace!("LBank: Received text: {text}");
-
-        if let Ok(message) = serde_json::from_str(&text) {
-            match message {
-                DataMessage::MarketDepth(d) => {
-                    let update = OrderbookState {
-                        exchange: Exchange::LBank,
-                        timestamp_ms: 0,
-                        bids: d
-                            .depth
-                            .bids
-                            .into_iter()
-                            .map(|b| {
-                                (
-                                    Decimal::from_str(&b[0]).unwrap(),
-                                    Decimal::from_str(&b[1]).unwrap(),
-                                )
-                            })
-                            .collect(),
-                        asks: d
-                            .depth
-                            .asks
-                            .into_iter()
-                            .map(|a| {
-                                (
-                                    Decimal::from_str(&a[0]).unwrap(),
-                                    Decimal::from_str(&a[1]).unwrap(),
-                                )
-                            })
-                            .collect(),
-                    };
-                    trace!("LBank: Received update: {update:?}");
-                    self.sender.send(Arc::new(update)).unwrap();
-                }
+    fn handle_heartbeat(&self, text: &str) -> Option<String> {
+        match serde_json::from_str(text) {
+            Ok(Action::Ping(Ping { ping })) => {
+                Some(serialize_to_json(&Action::Pong(Pong { pong: ping })))
             }
-        } else if let Ok(Action::Ping(Ping { ping })) = serde_json::from_str(&text) {
-            self.send(&Action::Pong(Pong { pong: ping })).unwrap();
+            _ => None,
         }
-        Ok(())
-    }
-
-    async fn on_binary(&mut self, _: Vec<u8>) -> Result<(), Error> {
-        unreachable!()
     }
 
-    async fn on_call(&mut self, _: Self::Call) -> Result<(), Error> {
-        unreachable!()
-    }
-
-    async fn on_connect(&mut self) -> Result<(), Error> {
-        info!("LBank: Connected");
-        self.subscribe().unwrap();
-        Ok(())
-    }
-
-    async fn on_disconnect(&mut self) -> Result<ClientCloseMode, Error> {
-        info!("LBank: Disconnected");
-        Ok(ClientCloseMode::Reconnect)
-    }
-
-    async fn on_connect_fail(&mut self, error: WSError) -> Result<ClientCloseMode, Error> {
-        error!("LBank: Failed to connect: {error:?}");
-        Ok(ClientCloseMode::Reconnect)
-    }
-}
+    // The timestamp is left unset as the exchange sends it as a formatted date without a timezone
+    fn decode_message(&self, text: &str) -> Result<Message, String> {
+        let error = match serde_json::from_str(text) {
+            Ok(DataMessage::MarketDepth(d)) => {
+                return Ok(Message::Depth(Depth {
+                    timestamp_ms: None,
+                    bids: d.depth.bids,
+                    asks: d.depth.asks,
+                }))
+            }
+            Err(error) => error,
+        };
 
-#[async_trait]
-impl ExchangeSubscriber for LBankSubscriber {
-    async fn run_async(
-        self,
-        sender: Sender<Arc<OrderbookState>>,
-        cancellation_token: CancellationToken,
-    ) {
-        info!("LBankSubscriber started");
-
-        let (handle, future) = ezsockets::connect(
-            |handle| WebSocketClient { handle, sender },
-            ClientConfig::new(URL),
-        )
-        .await;
-
-        select! {
-            _ = future => (),
-            _ = cancellation_token.cancelled() => {
-                handle.close(None).unwrap();
+        // Only parsed again when it isn't a valid depth message, to tell why
+        match serde_json::from_str::<Envelope>(text) {
+            Ok(Envelope { kind: Some(kind) }) if kind == "depth" => {
+                Err(format!("Invalid depth message: {error}"))
             }
+            Ok(_) => Ok(Message::Unknown),
+            Err(error) => Err(format!("Invalid message: {error}")),
         }
-
-        info!("LBankSubscriber stopped");
     }
 }
 
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum DataMessage {
@@ -187,3 +118,39 @@ struct SubscribeMarketDepth {
     depth: String,
     pair: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_messages() {
+        let codec = LBankCodec::default();
+
+        let Ok(Message::Depth(depth)) = codec.decode_message(
+            r#"{"type":"depth","pair":"chat_usdt","TS":"2024-08-01T00:00:00.000",
+            "depth":{"bids":[["0.49","100"]],"asks":[["0.51","10"]]}}"#,
+        ) else {
+            panic!("Expected depth");
+        };
+        assert_eq!(depth.timestamp_ms, None);
+        assert_eq!(depth.bids, vec![["0.49".to_string(), "100".to_string()]]);
+        assert_eq!(depth.asks, vec![["0.51".to_string(), "10".to_string()]]);
+
+        assert!(matches!(
+            codec.decode_message(r#"{"type":"trade","pair":"chat_usdt"}"#),
+            Ok(Message::Unknown)
+        ));
+        assert!(codec
+            .decode_message(r#"{"type":"depth","pair":"chat_usdt"}"#)
+            .unwrap_err()
+            .starts_with("Invalid depth message"));
+        assert!(codec.decode_message("not json").is_err());
+
+        assert_eq!(
+            codec.handle_heartbeat(r#"{"action":"ping","ping":"abc"}"#),
+            Some(r#"{"action":"pong","pong":"abc"}"#.to_string())
+        );
+        assert_eq!(codec.handle_heartbeat(r#"{"type":"depth"}"#), None);
+    }
+}
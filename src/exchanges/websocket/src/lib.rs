@@ -0,0 +1,541 @@
+use async_trait::async_trait;
+use ezsockets::client::ClientCloseMode;
+use ezsockets::{ClientConfig, ClientExt, CloseFrame, Error, WSError};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::select;
+use tokio::sync::broadcast::Sender;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, trace, warn};
+use xb_types::metrics::{
+    EXCHANGE_UP, MARKET_DATA_ERRORS, WEBSOCKET_MESSAGES, WEBSOCKET_RECONNECTS,
+};
+use xb_types::{
+    Exchange, ExchangeSubscriber, LatencyTrace, OrderbookState, ReconnectPolicy, Stage,
+};
+
+// Each kind of error is logged at most this often, every one is counted in the metrics
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(10);
+// The most of a bad message included in the log
+const ERROR_SAMPLE_CHARS: usize = 200;
+
+// A message as received from the exchange, before any decompression
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+// The levels of one side of the book as the exchange sends them, each a price and an amount
+pub type Levels = Vec<[String; 2]>;
+
+// A snapshot of the book, still in the exchange's format
+#[derive(Debug, Default)]
+pub struct Depth {
+    // Left unset when the exchange doesn't send a usable one, so the time it was received is used
+    pub timestamp_ms: Option<u64>,
+    pub bids: Levels,
+    pub asks: Levels,
+}
+
+// A message once any framing has been removed
+#[derive(Debug)]
+pub enum Message {
+    Depth(Depth),
+    // Acknowledgements and the like which need no action
+    Ignored,
+    // A type of message the codec doesn't recognise
+    Unknown,
+}
+
+// What differs between the exchanges' market data websockets. Connecting, reconnecting,
+// resubscribing, metrics and errors are handled by the subscriber.
+pub trait WebSocketCodec: Send + Sync + 'static {
+    fn exchange(&self) -> Exchange;
+
+    fn url(&self) -> &'static str;
+
+    // The messages which subscribe to the book, sent again on every reconnect
+    fn encode_subscribe(&self) -> Vec<String>;
+
+    // The text a frame carries. Exchanges which compress their messages override this.
+    fn decode_frame(&self, frame: Frame) -> Result<String, String> {
+        match frame {
+            Frame::Text(text) => Ok(text),
+            Frame::Binary(_) => Err("Unexpected binary frame".to_string()),
+        }
+    }
+
+    // The reply to send if the message is a heartbeat
+    fn handle_heartbeat(&self, text: &str) -> Option<String>;
+
+    // Fails if the message is malformed, which includes a recognised type of message which
+    // doesn't match its schema
+    fn decode_message(&self, text: &str) -> Result<Message, String>;
+}
+
+// Publishes the book of any exchange which has a codec
+#[derive(Default)]
+pub struct WebSocketSubscriber<C> {
+    codec: C,
+    reconnect_policy: ReconnectPolicy,
+}
+
+struct WebSocketClient<C: WebSocketCodec> {
+    handle: ezsockets::Client<Self>,
+    codec: Arc<C>,
+    sender: Sender<Arc<OrderbookState>>,
+    errors: ErrorSampler,
+    backoff: Backoff,
+    // Whether a snapshot has been received since the book was last invalidated
+    book_valid: bool,
+    cancellation_token: CancellationToken,
+}
+
+// Counts the consecutive failures to connect or stay connected, only reset once a snapshot is
+// received so a connection which drops straight away still backs off
+struct Backoff {
+    policy: ReconnectPolicy,
+    failures: u32,
+}
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+enum ErrorKind {
+    // The frame didn't hold text, eg. failed to decompress
+    Frame,
+    Message,
+    // A price or amount in the book which isn't a valid number
+    Levels,
+    Unknown,
+}
+
+// Limits how often each kind of error is logged, so a stream of bad messages doesn't flood the logs
+#[derive(Default)]
+struct ErrorSampler {
+    // When each kind was last logged and how many have been seen since
+    logged: HashMap<ErrorKind, (Instant, u64)>,
+}
+
+impl<C: WebSocketCodec> WebSocketSubscriber<C> {
+    pub fn new(codec: C) -> WebSocketSubscriber<C> {
+        WebSocketSubscriber {
+            codec,
+            reconnect_policy: ReconnectPolicy::default(),
+        }
+    }
+
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+}
+
+impl<C: WebSocketCodec> WebSocketClient<C> {
+    fn exchange(&self) -> Exchange {
+        self.codec.exchange()
+    }
+
+    fn send(&self, text: String) -> Result<(), Error> {
+        trace!("{:?}: Sending message: {text}", self.exchange());
+        self.handle.text(text)?;
+        Ok(())
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        let trace = LatencyTrace::start();
+        let exchange = self.exchange();
+        WEBSOCKET_MESSAGES
+            .with_label_values(&[exchange.as_str()])
+            .inc();
+
+        let text = match self.codec.decode_frame(frame) {
+            Ok(text) => text,
+            Err(error) => {
+                self.report(ErrorKind::Frame, &error, "");
+                return Ok(());
+            }
+        };
+        trace!("{exchange:?}: Received text: {text}");
+
+        if let Some(reply) = self.codec.handle_heartbeat(&text) {
+            return self.send(reply);
+        }
+
+        let depth = match self.codec.decode_message(&text) {
+            Ok(Message::Depth(depth)) => depth,
+            Ok(Message::Ignored) => return Ok(()),
+            Ok(Message::Unknown) => {
+                self.report(ErrorKind::Unknown, "Unknown message", &text);
+                return Ok(());
+            }
+            Err(error) => {
+                self.report(ErrorKind::Message, &error, &text);
+                return Ok(());
+            }
+        };
+        match orderbook_state(exchange, depth, now_ms(), trace) {
+            Ok(update) => {
+                trace!("{exchange:?}: Received update: {update:?}");
+                if !self.book_valid {
+                    self.on_snapshot();
+                }
+                // Only fails when nothing is subscribed, in which case there's no one to tell
+                let _ = self.sender.send(Arc::new(update));
+            }
+            Err(error) => self.report(ErrorKind::Levels, &error, &text),
+        }
+        Ok(())
+    }
+
+    // The connection is only known to be healthy once the book arrives
+    fn on_snapshot(&mut self) {
+        let exchange = self.exchange();
+        self.book_valid = true;
+        if self.backoff.on_success() {
+            info!("{exchange:?}: Receiving books again, marked up");
+        }
+        EXCHANGE_UP.with_label_values(&[exchange.as_str()]).set(1.0);
+    }
+
+    // Invalidates the book and waits out the backoff, or closes if cancelled in the meantime
+    async fn on_failure(&mut self) -> ClientCloseMode {
+        let exchange = self.exchange();
+        WEBSOCKET_RECONNECTS
+            .with_label_values(&[exchange.as_str()])
+            .inc();
+
+        if self.book_valid {
+            self.book_valid = false;
+            let _ = self
+                .sender
+                .send(Arc::new(OrderbookState::invalidated(exchange, now_ms())));
+        }
+
+        let (delay, tripped) = self.backoff.on_failure(rand::random());
+        if tripped {
+            error!(
+                "{exchange:?}: Marked down after {} consecutive connection failures",
+                self.backoff.failures
+            );
+            EXCHANGE_UP.with_label_values(&[exchange.as_str()]).set(0.0);
+        }
+
+        info!("{exchange:?}: Reconnecting in {delay:?}");
+        select! {
+            _ = tokio::time::sleep(delay) => ClientCloseMode::Reconnect,
+            _ = self.cancellation_token.cancelled() => ClientCloseMode::Close,
+        }
+    }
+
+    fn report(&mut self, kind: ErrorKind, error: &str, text: &str) {
+        let exchange = self.exchange();
+        MARKET_DATA_ERRORS
+            .with_label_values(&[exchange.as_str(), kind.as_str()])
+            .inc();
+
+        let Some(suppressed) = self.errors.sample(kind, Instant::now()) else {
+            return;
+        };
+        let mut message = format!("{exchange:?}: {error}");
+        if suppressed > 0 {
+            message.push_str(&format!(" ({suppressed} similar since last logged)"));
+        }
+        if !text.is_empty() {
+            let sample: String = text.chars().take(ERROR_SAMPLE_CHARS).collect();
+            message.push_str(&format!(": {sample}"));
+        }
+        warn!("{message}");
+    }
+}
+
+impl Backoff {
+    // The delay before reconnecting, and whether this failure marks the exchange as down
+    fn on_failure(&mut self, jitter: f64) -> (Duration, bool) {
+        self.failures += 1;
+        let delay = self.policy.delay(self.failures, jitter);
+        (delay, self.failures == self.policy.circuit_threshold)
+    }
+
+    // Whether the exchange had been marked as down
+    fn on_success(&mut self) -> bool {
+        let was_down = self.failures >= self.policy.circuit_threshold;
+        self.failures = 0;
+        was_down
+    }
+}
+
+impl ErrorKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Frame => "frame",
+            ErrorKind::Message => "message",
+            ErrorKind::Levels => "levels",
+            ErrorKind::Unknown => "unknown",
+        }
+    }
+}
+
+impl ErrorSampler {
+    // The number of errors of the kind held back since it was last logged, if it's time to log it
+    fn sample(&mut self, kind: ErrorKind, now: Instant) -> Option<u64> {
+        match self.logged.get_mut(&kind) {
+            Some((last_logged, suppressed))
+                if now.duration_since(*last_logged) < ERROR_LOG_INTERVAL =>
+            {
+                *suppressed += 1;
+                None
+            }
+            Some((last_logged, suppressed)) => {
+                *last_logged = now;
+                Some(std::mem::take(suppressed))
+            }
+            None => {
+                self.logged.insert(kind, (now, 0));
+                Some(0)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<C: WebSocketCodec> ClientExt for WebSocketClient<C> {
+    type Call = ();
+
+    async fn on_text(&mut self, text: String) -> Result<(), Error> {
+        self.on_frame(Frame::Text(text))
+    }
+
+    async fn on_binary(&mut self, bytes: Vec<u8>) -> Result<(), Error> {
+        self.on_frame(Frame::Binary(bytes))
+    }
+
+    // Nothing calls the client
+    async fn on_call(&mut self, _: Self::Call) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn on_connect(&mut self) -> Result<(), Error> {
+        info!("{:?}: Connected", self.exchange());
+        for message in self.codec.encode_subscribe() {
+            self.send(message)?;
+        }
+        Ok(())
+    }
+
+    async fn on_close(&mut self, frame: Option<CloseFrame>) -> Result<ClientCloseMode, Error> {
+        info!("{:?}: Closed by the exchange: {frame:?}", self.exchange());
+        Ok(self.on_failure().await)
+    }
+
+    async fn on_disconnect(&mut self) -> Result<ClientCloseMode, Error> {
+        info!("{:?}: Disconnected", self.exchange());
+        Ok(self.on_failure().await)
+    }
+
+    async fn on_connect_fail(&mut self, error: WSError) -> Result<ClientCloseMode, Error> {
+        error!("{:?}: Failed to connect: {error:?}", self.exchange());
+        Ok(self.on_failure().await)
+    }
+}
+
+#[async_trait]
+impl<C: WebSocketCodec> ExchangeSubscriber for WebSocketSubscriber<C> {
+    async fn run_async(
+        self,
+        sender: Sender<Arc<OrderbookState>>,
+        cancellation_token: CancellationToken,
+    ) {
+        let exchange = self.codec.exchange();
+        info!("{exchange:?} subscriber started");
+
+        let codec = Arc::new(self.codec);
+        let url = codec.url();
+        let backoff = Backoff {
+            policy: self.reconnect_policy,
+            failures: 0,
+        };
+        let client_token = cancellation_token.clone();
+        // The backoff is waited out by the client itself rather than at a fixed interval
+        let (handle, future) = ezsockets::connect(
+            |handle| WebSocketClient {
+                handle,
+                codec,
+                sender,
+                errors: ErrorSampler::default(),
+                backoff,
+                book_valid: false,
+                cancellation_token: client_token,
+            },
+            ClientConfig::new(url).reconnect_interval(Duration::ZERO),
+        )
+        .await;
+
+        select! {
+            _ = future => (),
+            _ = cancellation_token.cancelled() => {
+                if let Err(error) = handle.close(None) {
+                    error!("{exchange:?}: Failed to close websocket: {error}");
+                }
+            }
+        }
+
+        info!("{exchange:?} subscriber stopped");
+    }
+}
+
+fn orderbook_state(
+    exchange: Exchange,
+    depth: Depth,
+    received_ms: u64,
+    trace: LatencyTrace,
+) -> Result<OrderbookState, String> {
+    let mut state = OrderbookState {
+        exchange,
+        timestamp_ms: depth.timestamp_ms.unwrap_or(received_ms),
+        bids: parse_levels(depth.bids)?,
+        asks: parse_levels(depth.asks)?,
+        invalidated: false,
+        trace,
+    };
+    state.trace.mark(Stage::Decode);
+    Ok(state)
+}
+
+// A price of zero or less or a negative amount can't be real, and the strategies divide by prices
+fn parse_levels(levels: Levels) -> Result<BTreeMap<Decimal, Decimal>, String> {
+    levels
+        .into_iter()
+        .map(|[price, amount]| {
+            let level = (parse_decimal(&price)?, parse_decimal(&amount)?);
+            if level.0 <= Decimal::ZERO || level.1 < Decimal::ZERO {
+                return Err(format!("Invalid level [{price:?}, {amount:?}]"));
+            }
+            Ok(level)
+        })
+        .collect()
+}
+
+fn parse_decimal(s: &str) -> Result<Decimal, String> {
+    Decimal::from_str(s).map_err(|e| format!("Invalid decimal {s:?}: {e}"))
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    const LBANK: Exchange = Exchange::new("lbank");
+
+    #[test]
+    fn parses_depth() {
+        let depth = Depth {
+            timestamp_ms: Some(1),
+            bids: vec![["0.49".to_string(), "100".to_string()]],
+            asks: vec![
+                ["0.51".to_string(), "10".to_string()],
+                ["0.52".to_string(), "20".to_string()],
+            ],
+        };
+
+        let state = orderbook_state(LBANK, depth, 2, LatencyTrace::default()).unwrap();
+
+        assert_eq!(state.timestamp_ms, 1);
+        assert_eq!(
+            state.bids,
+            BTreeMap::from([(Decimal::new(49, 2), Decimal::from(100))])
+        );
+        assert_eq!(
+            state.asks,
+            BTreeMap::from([
+                (Decimal::new(51, 2), Decimal::from(10)),
+                (Decimal::new(52, 2), Decimal::from(20)),
+            ])
+        );
+    }
+
+    #[test]
+    fn samples_errors() {
+        let mut sampler = ErrorSampler::default();
+        let start = Instant::now();
+
+        assert_eq!(sampler.sample(ErrorKind::Message, start), Some(0));
+        assert_eq!(sampler.sample(ErrorKind::Message, start), None);
+        assert_eq!(sampler.sample(ErrorKind::Unknown, start), Some(0));
+        assert_eq!(sampler.sample(ErrorKind::Message, start), None);
+        assert_eq!(
+            sampler.sample(ErrorKind::Message, start + ERROR_LOG_INTERVAL),
+            Some(2)
+        );
+        assert_eq!(
+            sampler.sample(ErrorKind::Message, start + ERROR_LOG_INTERVAL),
+            None
+        );
+    }
+
+    #[test]
+    fn marks_down_after_consecutive_failures() {
+        let mut backoff = Backoff {
+            policy: ReconnectPolicy {
+                circuit_threshold: 3,
+                ..Default::default()
+            },
+            failures: 0,
+        };
+
+        assert_eq!(backoff.on_failure(0.0), (Duration::from_millis(500), false));
+        assert!(!backoff.on_success());
+
+        assert_eq!(backoff.on_failure(0.0), (Duration::from_millis(500), false));
+        assert_eq!(backoff.on_failure(0.0), (Duration::from_secs(1), false));
+        assert_eq!(backoff.on_failure(1.0), (Duration::from_secs(1), true));
+        // Only marked down once
+        assert_eq!(backoff.on_failure(0.0), (Duration::from_secs(4), false));
+        assert!(backoff.on_success());
+        assert_eq!(backoff.failures, 0);
+    }
+
+    #[test]
+    fn stamps_depth_without_timestamp_when_received() {
+        let depth = Depth {
+            timestamp_ms: None,
+            ..Default::default()
+        };
+
+        let state = orderbook_state(LBANK, depth, 2, LatencyTrace::default()).unwrap();
+
+        assert_eq!(state.timestamp_ms, 2);
+    }
+
+    #[test]
+    fn empty_snapshot_is_not_invalidated() {
+        let state = orderbook_state(LBANK, Depth::default(), 2, LatencyTrace::default()).unwrap();
+
+        assert!(state.bids.is_empty() && state.asks.is_empty());
+        assert!(!state.invalidated);
+        assert!(OrderbookState::invalidated(LBANK, 2).invalidated);
+    }
+
+    #[test_case("0.49", "lots", "Invalid decimal \"lots\""; "amount not a number")]
+    #[test_case("0", "100", "Invalid level [\"0\", \"100\"]"; "zero price")]
+    #[test_case("-0.49", "100", "Invalid level [\"-0.49\", \"100\"]"; "negative price")]
+    #[test_case("0.49", "-100", "Invalid level [\"0.49\", \"-100\"]"; "negative amount")]
+    fn rejects_invalid_levels(price: &str, amount: &str, expected: &str) {
+        let depth = Depth {
+            bids: vec![[price.to_string(), amount.to_string()]],
+            ..Default::default()
+        };
+
+        let error = orderbook_state(LBANK, depth, 2, LatencyTrace::default()).unwrap_err();
+
+        assert!(error.starts_with(expected), "{error}");
+    }
+}
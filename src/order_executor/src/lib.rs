@@ -1,74 +1,1148 @@
+use crate::slippage::{protect_market_order, slippage_bps, SlippageStats};
+use futures::StreamExt;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::select;
-use tokio::sync::broadcast::Receiver;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
-use tracing::error;
-use xb_types::{Exchange, ExchangeOrderExecutor, PendingOrder};
+use tracing::{error, info, warn, Instrument};
+use xb_types::metrics::{
+    BROADCAST_LAGGED, FILLED_AMOUNT, ORDERS_FILLED, ORDERS_REJECTED, ORDERS_SUBMITTED, SLIPPAGE,
+};
+use xb_types::{
+    next_parameters, Alert, CancelOrder, Exchange, ExchangeOrderExecutor, LatencyTrace,
+    OrderRequest, OrderStatus, OrderUpdate, PauseSwitch, PendingLimitOrder, PendingOrder,
+    ReplaceOrder, Stage, Strategy, TimeInForce,
+};
+
+mod slippage;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+// The most order statuses requested from the exchanges at once while polling
+const MAX_CONCURRENT_POLLS: usize = 4;
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct OrderExecutor {
-    exchanges: HashMap<Exchange, Box<dyn ExchangeOrderExecutor>>,
+    // Shared with the task polling the live orders
+    exchanges: HashMap<Exchange, Arc<dyn ExchangeOrderExecutor>>,
+    live_limit_orders: HashMap<Strategy, HashMap<u64, LiveLimitOrder>>,
+    max_slippage: Option<Decimal>,
+    max_order_amount: Option<Decimal>,
+    slippage_per_strategy: HashMap<Strategy, SlippageStats>,
+    order_updates: Option<Sender<Arc<OrderUpdate>>>,
+    risk_limit_updates: Option<watch::Receiver<RiskLimits>>,
+    pause: PauseSwitch,
+    drain_timeout: Duration,
+    cancel_timeout: Duration,
+    alerts: Option<Sender<Arc<Alert>>>,
+}
+
+// The limits which can be changed while running
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RiskLimits {
+    pub max_slippage: Option<Decimal>,
+    pub max_order_amount: Option<Decimal>,
 }
 
 #[derive(Default)]
 pub struct OrderExecutorBuilder {
     exchanges: HashMap<Exchange, Box<dyn ExchangeOrderExecutor>>,
+    max_slippage: Option<Decimal>,
+    max_order_amount: Option<Decimal>,
+    order_updates: Option<Sender<Arc<OrderUpdate>>>,
+    risk_limit_updates: Option<watch::Receiver<RiskLimits>>,
+    pause: PauseSwitch,
+    drain_timeout: Duration,
+    cancel_timeout: Duration,
+    alerts: Option<Sender<Arc<Alert>>>,
+}
+
+struct LiveLimitOrder {
+    order: PendingLimitOrder,
+    exchange_order_id: String,
+    filled_amount: Decimal,
+    filled_value: Decimal,
+}
+
+// A live order confirmed closed after cancelling it
+struct ClosedOrder {
+    live_order: LiveLimitOrder,
+    // Whether the cancellation succeeded, rather than the order having closed by itself
+    cancelled: bool,
+    // Filled since the last update
+    filled_amount: Decimal,
+    filled_value: Decimal,
+}
+
+// The status of a live order fetched by the polling task
+struct PolledStatus {
+    strategy: Strategy,
+    id: u64,
+    exchange_order_id: String,
+    status: OrderStatus,
+}
+
+impl LiveLimitOrder {
+    // Updates the filled totals, returning the amount and value filled since the last update
+    fn apply_status(&mut self, status: &OrderStatus) -> (Decimal, Decimal) {
+        let filled_amount = status.filled_amount - self.filled_amount;
+        let filled_value = status.filled_value - self.filled_value;
+        self.filled_amount = status.filled_amount;
+        self.filled_value = status.filled_value;
+        (filled_amount, filled_value)
+    }
 }
 
 impl OrderExecutor {
     pub fn run(
         self,
-        receiver: Receiver<Arc<PendingOrder>>,
+        receiver: Receiver<Arc<OrderRequest>>,
         cancellation_token: CancellationToken,
     ) -> JoinHandle<()> {
         tokio::spawn(self.run_async(receiver, cancellation_token))
     }
 
     async fn run_async(
-        self,
-        mut receiver: Receiver<Arc<PendingOrder>>,
+        mut self,
+        mut receiver: Receiver<Arc<OrderRequest>>,
         cancellation_token: CancellationToken,
     ) {
+        info!("OrderExecutor started");
+
+        let mut poll_interval = tokio::time::interval(POLL_INTERVAL);
+        // Polling runs in its own task so the orders sent meanwhile don't wait behind it
+        let mut poll = None;
+        let mut risk_limit_updates = self.risk_limit_updates.take();
+
         loop {
             select! {
-                next = receiver.recv() => {
-                    if let Ok(order) = next {
-                        let exchange = order.exchange();
-                        if let Some(order_executor) = self.exchanges.get(&exchange) {
-                            let _ = order_executor.submit_order((*order).clone()).await;
-                        } else {
-                            error!("No order executor found for exchange: {exchange:?}")
-                        }
+                limits = next_parameters(&mut risk_limit_updates) => {
+                    info!("Applying risk limits: {limits:?}");
+                    self.max_slippage = limits.max_slippage;
+                    self.max_order_amount = limits.max_order_amount;
+                }
+                next = receiver.recv() => match next {
+                    Ok(request) => self.process_request((*request).clone()).await,
+                    Err(RecvError::Lagged(skipped)) => {
+                        error!("Order executor lagging, {skipped} order requests dropped");
+                        BROADCAST_LAGGED.with_label_values(&["order_executor"]).inc_by(skipped);
+                    }
+                    // Nothing can be sent anymore, so stop and leave restarting to the supervisor
+                    Err(RecvError::Closed) => {
+                        error!("Order executor: Order requests closed");
+                        break;
                     }
                 },
+                _ = poll_interval.tick() => {
+                    if poll.is_none() {
+                        poll = Some(self.poll_live_limit_orders());
+                    }
+                }
+                statuses = polled(&mut poll) => self.apply_polled_statuses(statuses),
                 _ = cancellation_token.cancelled() => {
                     break;
                 }
             }
         }
+
+        info!("OrderExecutor stopping");
+        if let Some(poll) = poll {
+            poll.abort();
+        }
+
+        let drain_timeout = self.drain_timeout;
+        if tokio::time::timeout(drain_timeout, self.drain(&mut receiver))
+            .await
+            .is_err()
+        {
+            warn!("Timed out after {drain_timeout:?} draining order requests");
+        }
+
+        let cancel_timeout = self.cancel_timeout;
+        if tokio::time::timeout(cancel_timeout, self.cancel_all_live_orders())
+            .await
+            .is_err()
+        {
+            warn!("Timed out after {cancel_timeout:?} cancelling live limit orders");
+        }
+
+        let remaining: Vec<_> = self
+            .live_limit_orders
+            .values()
+            .flat_map(|o| o.values())
+            .map(|o| &o.order)
+            .collect();
+        if !remaining.is_empty() {
+            error!("Limit orders may still be open: {remaining:?}");
+        }
+
+        info!("OrderExecutor stopped");
+    }
+
+    // Handles the requests queued before the strategies stopped. Cancellations still go through,
+    // but nothing new is placed as it would only have to be cancelled again straight away.
+    async fn drain(&mut self, receiver: &mut Receiver<Arc<OrderRequest>>) {
+        loop {
+            match receiver.try_recv() {
+                Ok(request) => match (*request).clone() {
+                    OrderRequest::Submit(order) => {
+                        warn!("Shutting down, rejecting order: {order:?}");
+                        self.reject(&order, "Shutting down".to_string());
+                    }
+                    OrderRequest::Replace(replace) => {
+                        self.cancel_limit_order(CancelOrder {
+                            strategy: replace.strategy,
+                            id: replace.id,
+                        })
+                        .await
+                    }
+                    request => self.process_request(request).await,
+                },
+                Err(TryRecvError::Lagged(skipped)) => {
+                    error!("Order executor lagging, {skipped} order requests dropped");
+                    BROADCAST_LAGGED
+                        .with_label_values(&["order_executor"])
+                        .inc_by(skipped);
+                }
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+    }
+
+    async fn process_request(&mut self, request: OrderRequest) {
+        match request {
+            OrderRequest::Submit(order) => self.submit_order(order).await,
+            OrderRequest::Cancel(cancel) => self.cancel_limit_order(cancel).await,
+            OrderRequest::Replace(replace) => self.replace_limit_order(replace).await,
+            OrderRequest::CancelAll => self.cancel_all_live_orders().await,
+        }
+    }
+
+    async fn submit_order(&mut self, order: PendingOrder) {
+        if self.pause.is_paused() {
+            warn!("Order executor paused, rejecting order: {order:?}");
+            self.reject(&order, "Order executor paused".to_string());
+            return;
+        }
+
+        let exchange = order.exchange();
+        let Some(order_executor) = self.exchanges.get(&exchange) else {
+            error!("No order executor found for exchange: {exchange:?}");
+            self.reject(&order, "No order executor for the exchange".to_string());
+            return;
+        };
+
+        if order.amount() <= Decimal::ZERO {
+            error!("Order amount must be positive. Order: {order:?}");
+            self.reject(&order, format!("Invalid order amount: {}", order.amount()));
+            return;
+        }
+
+        if let Some(max_order_amount) = self.max_order_amount {
+            if order.amount() > max_order_amount {
+                error!("Order amount exceeds limit of {max_order_amount}. Order: {order:?}");
+                self.alert(Alert::RiskLimit {
+                    exchange,
+                    strategy: order.strategy(),
+                    reason: format!(
+                        "Order amount {} exceeds limit of {max_order_amount}",
+                        order.amount()
+                    ),
+                });
+                self.record_rejection(&order);
+                return;
+            }
+        }
+
+        let expected_return = match &order {
+            PendingOrder::Market(o) => Some(o.expected_return),
+            PendingOrder::Limit(_) => None,
+        };
+
+        let order = match (order, self.max_slippage) {
+            (PendingOrder::Market(o), Some(max_slippage)) => {
+                match protect_market_order(&o, max_slippage) {
+                    Ok(limit) => PendingOrder::Limit(limit),
+                    Err(reason) => {
+                        let order = PendingOrder::Market(o);
+                        error!("Failed to protect market order: {reason}. Order: {order:?}");
+                        self.reject(&order, reason);
+                        return;
+                    }
+                }
+            }
+            (o, _) => o,
+        };
+
+        if let Err(reason) = order_executor.check_order_supported(&order) {
+            error!("Order not supported by {exchange:?}: {reason}. Order: {order:?}");
+            self.reject(&order, reason);
+            return;
+        }
+
+        let mut trace = order.trace();
+        trace.mark(Stage::Dispatch);
+        let span = trace.span();
+        let result = order_executor
+            .submit_order(order.clone())
+            .instrument(span.clone())
+            .await;
+        trace.mark(Stage::Http);
+        if let Some(http) = trace.duration(Stage::Http) {
+            span.record("http_us", http.as_micros() as u64);
+        }
+
+        match result {
+            Ok(exchange_order_id) => {
+                ORDERS_SUBMITTED.with_label_values(&labels(&order)).inc();
+                if let Some(expected_return) = expected_return {
+                    self.process_immediate_fill(&order, &exchange_order_id, expected_return)
+                        .await;
+                }
+
+                // Only orders which can rest on the book need tracking, IOC and FOK orders are
+                // either filled or cancelled immediately
+                match order {
+                    PendingOrder::Limit(order)
+                        if order.time_in_force == TimeInForce::GoodTillCancelled =>
+                    {
+                        self.insert_live_order(LiveLimitOrder {
+                            order,
+                            exchange_order_id,
+                            filled_amount: Decimal::ZERO,
+                            filled_value: Decimal::ZERO,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            Err(error) => {
+                error!("Failed to submit order: {error}. Order: {order:?}");
+                self.reject(&order, error);
+            }
+        }
+    }
+
+    // Market orders (including those converted into IOC limit orders) have finished executing by
+    // the time the exchange responds, so their fills can be published and checked for slippage
+    async fn process_immediate_fill(
+        &mut self,
+        order: &PendingOrder,
+        exchange_order_id: &str,
+        expected_return: Decimal,
+    ) {
+        let Some(status) = self
+            .get_order_status(order.exchange(), exchange_order_id)
+            .await
+        else {
+            error!("Fills of order unknown: {order:?}");
+            self.publish_unknown_fill(order);
+            return;
+        };
+
+        self.publish_update(order, status.filled_amount, status.filled_value, false);
+
+        if status.filled_amount.is_zero() {
+            warn!("Order not filled: {order:?}");
+            return;
+        }
+
+        // Scale the expected return down to the amount which was actually filled so that partial
+        // fills don't show up as slippage
+        let expected = expected_return * status.filled_amount / order.amount();
+        let realized = status.filled_value;
+        let direction = order.direction();
+
+        let stats = self
+            .slippage_per_strategy
+            .entry(order.strategy())
+            .or_default();
+        stats.record(direction, expected, realized);
+
+        let bps = slippage_bps(direction, expected, realized);
+        SLIPPAGE
+            .with_label_values(&labels(order))
+            .observe(bps.to_f64().unwrap_or_default());
+        info!(
+            "Slippage: {bps} bps. Expected: {expected}. Realized: {realized}. Strategy: {:?}",
+            order.strategy(),
+        );
+        info!(
+            "Slippage: {} bps over {} orders. Strategy: {:?}",
+            stats.slippage_bps(),
+            stats.orders,
+            order.strategy(),
+        );
+    }
+
+    // Fetches the status of each resting order in the background, a few at a time
+    fn poll_live_limit_orders(&self) -> JoinHandle<Vec<PolledStatus>> {
+        let polls: Vec<_> = self
+            .live_limit_orders
+            .values()
+            .flat_map(|o| o.values())
+            .filter_map(|o| {
+                let order_executor = self.exchanges.get(&o.order.exchange)?.clone();
+                Some(poll_status(
+                    o.order.strategy,
+                    o.order.id,
+                    o.order.exchange,
+                    o.exchange_order_id.clone(),
+                    order_executor,
+                ))
+            })
+            .collect();
+
+        tokio::spawn(async move {
+            futures::stream::iter(polls)
+                .buffer_unordered(MAX_CONCURRENT_POLLS)
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .flatten()
+                .collect()
+        })
+    }
+
+    // Publishes any new fills and drops orders which are no longer on the book. Orders cancelled,
+    // replaced or updated since the poll started already have a newer status, so are skipped.
+    fn apply_polled_statuses(&mut self, statuses: Vec<PolledStatus>) {
+        for polled in statuses {
+            let Some(mut live_order) = self.take_live_order(polled.strategy, polled.id) else {
+                continue;
+            };
+            if live_order.exchange_order_id != polled.exchange_order_id
+                || polled.status.filled_amount < live_order.filled_amount
+            {
+                self.insert_live_order(live_order);
+                continue;
+            }
+
+            let status = polled.status;
+            let (filled_amount, filled_value) = live_order.apply_status(&status);
+            if !filled_amount.is_zero() || !status.is_open {
+                self.publish_update(
+                    &PendingOrder::Limit(live_order.order.clone()),
+                    filled_amount,
+                    filled_value,
+                    status.is_open,
+                );
+            }
+            if status.is_open {
+                self.insert_live_order(live_order);
+            }
+        }
+    }
+
+    async fn cancel_limit_order(&mut self, cancel: CancelOrder) {
+        let Some(closed) = self.close_live_order(cancel.strategy, cancel.id).await else {
+            return;
+        };
+
+        self.publish_update(
+            &PendingOrder::Limit(closed.live_order.order),
+            closed.filled_amount,
+            closed.filled_value,
+            false,
+        );
+    }
+
+    async fn replace_limit_order(&mut self, replace: ReplaceOrder) {
+        // Only place the new order once the old one is definitely gone, otherwise if the old order
+        // has been filled in the meantime we would end up trading twice
+        let Some(ClosedOrder {
+            live_order,
+            cancelled,
+            filled_amount,
+            filled_value,
+        }) = self.close_live_order(replace.strategy, replace.id).await
+        else {
+            return;
+        };
+
+        // Never place more than was left unfilled on the original order
+        let amount = replace
+            .amount
+            .min(live_order.order.amount - live_order.filled_amount);
+
+        let order = PendingOrder::Limit(PendingLimitOrder {
+            amount,
+            price: replace.price,
+            // The replacement wasn't triggered by the book update which led to the original order
+            trace: LatencyTrace::default(),
+            ..live_order.order
+        });
+
+        if cancelled && amount > Decimal::ZERO {
+            self.publish_update(&order, filled_amount, filled_value, true);
+            self.submit_order(order).await;
+        } else {
+            self.publish_update(&order, filled_amount, filled_value, false);
+        }
+    }
+
+    // Cancels a live order and stops tracking it once it is confirmed closed. An order which may
+    // still be open stays tracked, so it keeps being polled and can be cancelled again.
+    async fn close_live_order(&mut self, strategy: Strategy, id: u64) -> Option<ClosedOrder> {
+        let Some(live_order) = self
+            .live_limit_orders
+            .get(&strategy)
+            .and_then(|o| o.get(&id))
+        else {
+            error!("Limit order not found. Strategy: {strategy:?}. Id: {id}");
+            return None;
+        };
+
+        let cancelled = self.cancel_on_exchange(live_order).await;
+        let status = self
+            .get_order_status(live_order.order.exchange, &live_order.exchange_order_id)
+            .await;
+        match &status {
+            Some(status) if status.is_open => {
+                error!("Order still open after cancelling: {:?}", live_order.order);
+                return None;
+            }
+            None if !cancelled => return None,
+            _ => {}
+        }
+
+        let mut live_order = self.take_live_order(strategy, id)?;
+        let (filled_amount, filled_value) = status
+            .map(|status| live_order.apply_status(&status))
+            .unwrap_or_default();
+        Some(ClosedOrder {
+            live_order,
+            cancelled,
+            filled_amount,
+            filled_value,
+        })
+    }
+
+    // Each cancellation is confirmed and published so the strategies stop tracking the orders and
+    // count any fills made before they were cancelled
+    async fn cancel_all_live_orders(&mut self) {
+        let orders: Vec<_> = self
+            .live_limit_orders
+            .values()
+            .flat_map(|o| o.values())
+            .map(|o| CancelOrder {
+                strategy: o.order.strategy,
+                id: o.order.id,
+            })
+            .collect();
+
+        info!("Cancelling all {} live limit orders", orders.len());
+
+        for cancel in orders {
+            self.cancel_limit_order(cancel).await;
+        }
+    }
+
+    async fn cancel_on_exchange(&self, live_order: &LiveLimitOrder) -> bool {
+        let exchange = live_order.order.exchange;
+        let Some(order_executor) = self.exchanges.get(&exchange) else {
+            error!("No order executor found for exchange: {exchange:?}");
+            return false;
+        };
+
+        match order_executor
+            .cancel_order(&live_order.exchange_order_id)
+            .await
+        {
+            Ok(()) => true,
+            Err(error) => {
+                error!(
+                    "Failed to cancel order: {error}. Order: {:?}",
+                    live_order.order
+                );
+                false
+            }
+        }
+    }
+
+    async fn get_order_status(
+        &self,
+        exchange: Exchange,
+        exchange_order_id: &str,
+    ) -> Option<OrderStatus> {
+        let order_executor = self.exchanges.get(&exchange)?;
+
+        match order_executor.get_order(exchange_order_id).await {
+            Ok(status) => Some(status),
+            Err(error) => {
+                error!("Failed to get order status: {error}. Exchange: {exchange:?}. OrderId: {exchange_order_id}");
+                None
+            }
+        }
+    }
+
+    fn take_live_order(&mut self, strategy: Strategy, id: u64) -> Option<LiveLimitOrder> {
+        self.live_limit_orders
+            .get_mut(&strategy)
+            .and_then(|o| o.remove(&id))
+    }
+
+    fn insert_live_order(&mut self, live_order: LiveLimitOrder) {
+        self.live_limit_orders
+            .entry(live_order.order.strategy)
+            .or_default()
+            .insert(live_order.order.id, live_order);
+    }
+
+    fn reject(&self, order: &PendingOrder, reason: String) {
+        self.alert(Alert::OrderRejected {
+            exchange: order.exchange(),
+            strategy: order.strategy(),
+            reason,
+        });
+        self.record_rejection(order);
+    }
+
+    fn record_rejection(&self, order: &PendingOrder) {
+        ORDERS_REJECTED.with_label_values(&labels(order)).inc();
+        self.publish_update(order, Decimal::ZERO, Decimal::ZERO, false);
+    }
+
+    fn alert(&self, alert: Alert) {
+        if let Some(sender) = &self.alerts {
+            // Sending only fails if there are no subscribers, in which case there is nothing to do
+            let _ = sender.send(Arc::new(alert));
+        }
+    }
+
+    fn publish_update(
+        &self,
+        order: &PendingOrder,
+        filled_amount: Decimal,
+        filled_value: Decimal,
+        is_open: bool,
+    ) {
+        if filled_amount > Decimal::ZERO {
+            ORDERS_FILLED.with_label_values(&labels(order)).inc();
+            FILLED_AMOUNT
+                .with_label_values(&labels(order))
+                .inc_by(filled_amount.to_f64().unwrap_or_default());
+        }
+
+        self.send_update(OrderUpdate {
+            strategy: order.strategy(),
+            id: order.id(),
+            exchange: order.exchange(),
+            direction: order.direction(),
+            filled_amount,
+            filled_value,
+            is_open,
+            fills_unknown: false,
+        });
+    }
+
+    // Closes an order whose fills couldn't be fetched, so the strategy stops waiting for them
+    fn publish_unknown_fill(&self, order: &PendingOrder) {
+        self.send_update(OrderUpdate {
+            strategy: order.strategy(),
+            id: order.id(),
+            exchange: order.exchange(),
+            direction: order.direction(),
+            filled_amount: Decimal::ZERO,
+            filled_value: Decimal::ZERO,
+            is_open: false,
+            fills_unknown: true,
+        });
+    }
+
+    fn send_update(&self, update: OrderUpdate) {
+        if let Some(sender) = &self.order_updates {
+            // Sending only fails if there are no subscribers, in which case there is nothing to do
+            let _ = sender.send(Arc::new(update));
+        }
     }
 }
 
+async fn poll_status(
+    strategy: Strategy,
+    id: u64,
+    exchange: Exchange,
+    exchange_order_id: String,
+    order_executor: Arc<dyn ExchangeOrderExecutor>,
+) -> Option<PolledStatus> {
+    match order_executor.get_order(&exchange_order_id).await {
+        Ok(status) => Some(PolledStatus {
+            strategy,
+            id,
+            exchange_order_id,
+            status,
+        }),
+        Err(error) => {
+            error!("Failed to get order status: {error}. Exchange: {exchange:?}. OrderId: {exchange_order_id}");
+            None
+        }
+    }
+}
+
+// Waits for the poll in progress, if there is one
+async fn polled(poll: &mut Option<JoinHandle<Vec<PolledStatus>>>) -> Vec<PolledStatus> {
+    let Some(handle) = poll else {
+        return std::future::pending().await;
+    };
+    let result = handle.await;
+    *poll = None;
+    result.unwrap_or_else(|error| {
+        error!("Polling live limit orders failed: {error}");
+        Vec::new()
+    })
+}
+
+fn labels(order: &PendingOrder) -> [&'static str; 2] {
+    [order.exchange().as_str(), order.strategy().as_str()]
+}
+
 impl OrderExecutorBuilder {
     pub fn new() -> OrderExecutorBuilder {
         OrderExecutorBuilder {
             exchanges: HashMap::new(),
+            max_slippage: None,
+            max_order_amount: None,
+            order_updates: None,
+            risk_limit_updates: None,
+            pause: PauseSwitch::new(),
+            drain_timeout: DRAIN_TIMEOUT,
+            cancel_timeout: CANCEL_TIMEOUT,
+            alerts: None,
         }
     }
 
-    pub fn with_exchange<E: ExchangeOrderExecutor + 'static>(
+    pub fn with_exchange(
         mut self,
         exchange: Exchange,
-        order_executor: E,
+        order_executor: Box<dyn ExchangeOrderExecutor>,
     ) -> Self {
-        self.exchanges.insert(exchange, Box::new(order_executor));
+        self.exchanges.insert(exchange, order_executor);
+        self
+    }
+
+    // Market orders will be sent as IOC limit orders capped at a price no more than this fraction
+    // worse than the average price implied by their expected return
+    pub fn with_max_slippage(mut self, max_slippage: Decimal) -> Self {
+        self.max_slippage = Some(max_slippage);
+        self
+    }
+
+    // Orders for a larger amount than this will be rejected without being sent to the exchange
+    pub fn with_max_order_amount(mut self, max_order_amount: Decimal) -> Self {
+        self.max_order_amount = Some(max_order_amount);
+        self
+    }
+
+    pub fn with_risk_limit_updates(mut self, receiver: watch::Receiver<RiskLimits>) -> Self {
+        self.risk_limit_updates = Some(receiver);
+        self
+    }
+
+    // New orders are rejected while paused, cancellations still go through
+    pub fn with_pause_switch(mut self, pause: PauseSwitch) -> Self {
+        self.pause = pause;
+        self
+    }
+
+    // On shutdown the queued requests are drained, then every live limit order is cancelled, each
+    // within its own timeout
+    pub fn with_shutdown_timeouts(mut self, drain: Duration, cancel: Duration) -> Self {
+        self.drain_timeout = drain;
+        self.cancel_timeout = cancel;
+        self
+    }
+
+    // Fills and orders leaving the book will be published to this channel
+    pub fn with_order_updates(mut self, sender: Sender<Arc<OrderUpdate>>) -> Self {
+        self.order_updates = Some(sender);
+        self
+    }
+
+    // Rejected orders, including those blocked by the risk limits, will be published to this channel
+    pub fn with_alerts(mut self, sender: Sender<Arc<Alert>>) -> Self {
+        self.alerts = Some(sender);
         self
     }
 
     pub fn build(self) -> OrderExecutor {
         OrderExecutor {
-            exchanges: self.exchanges,
+            exchanges: self
+                .exchanges
+                .into_iter()
+                .map(|(exchange, order_executor)| (exchange, Arc::from(order_executor)))
+                .collect(),
+            live_limit_orders: HashMap::new(),
+            max_slippage: self.max_slippage,
+            max_order_amount: self.max_order_amount,
+            slippage_per_strategy: HashMap::new(),
+            order_updates: self.order_updates,
+            risk_limit_updates: self.risk_limit_updates,
+            pause: self.pause,
+            drain_timeout: self.drain_timeout,
+            cancel_timeout: self.cancel_timeout,
+            alerts: self.alerts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+    use tokio::sync::broadcast::channel;
+    use xb_types::{Direction, PendingMarketOrder};
+
+    const LBANK: Exchange = Exchange::new("lbank");
+
+    #[derive(Default)]
+    struct MockState {
+        next_id: u64,
+        statuses: HashMap<String, OrderStatus>,
+        // The status of orders once submitted, resting unfilled if unset
+        submitted_status: Option<OrderStatus>,
+        cancel_fails: bool,
+        status_fails: bool,
+    }
+
+    #[derive(Clone, Default)]
+    struct MockExchange {
+        state: Arc<Mutex<MockState>>,
+    }
+
+    #[async_trait]
+    impl ExchangeOrderExecutor for MockExchange {
+        async fn submit_order(&self, _order: PendingOrder) -> Result<String, String> {
+            let mut state = self.state.lock().unwrap();
+            state.next_id += 1;
+            let id = state.next_id.to_string();
+            let status = state
+                .submitted_status
+                .clone()
+                .unwrap_or_else(|| status("0", true));
+            state.statuses.insert(id.clone(), status);
+            Ok(id)
+        }
+
+        async fn cancel_order(&self, order_id: &str) -> Result<(), String> {
+            let mut state = self.state.lock().unwrap();
+            if state.cancel_fails {
+                return Err("Exchange unavailable".to_string());
+            }
+            if let Some(status) = state.statuses.get_mut(order_id) {
+                status.is_open = false;
+            }
+            Ok(())
+        }
+
+        async fn get_order(&self, order_id: &str) -> Result<OrderStatus, String> {
+            let state = self.state.lock().unwrap();
+            if state.status_fails {
+                return Err("Exchange unavailable".to_string());
+            }
+            state
+                .statuses
+                .get(order_id)
+                .cloned()
+                .ok_or_else(|| "Not found".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn polls_fills_without_blocking_and_skips_replaced_orders() {
+        let exchange = MockExchange::default();
+        let (mut executor, mut updates) = executor(&exchange);
+        executor.submit_order(limit_order(1)).await;
+        executor.submit_order(limit_order(2)).await;
+
+        exchange
+            .state
+            .lock()
+            .unwrap()
+            .statuses
+            .insert("1".to_string(), status("40", true));
+        let mut poll = Some(executor.poll_live_limit_orders());
+        // Order 2 is replaced while the poll is running, its polled status is for the old order
+        executor
+            .replace_limit_order(ReplaceOrder {
+                strategy: Strategy::Cashout,
+                id: 2,
+                amount: Decimal::from(100),
+                price: Decimal::new(33, 2),
+            })
+            .await;
+        let statuses = polled(&mut poll).await;
+        assert!(poll.is_none());
+        assert_eq!(statuses.len(), 2);
+        executor.apply_polled_statuses(statuses);
+
+        let update = updates.try_recv().unwrap();
+        assert_eq!((update.id, update.is_open), (2, true));
+        let update = updates.try_recv().unwrap();
+        assert_eq!(
+            (update.id, update.filled_amount, update.is_open),
+            (1, Decimal::from(40), true)
+        );
+        assert!(updates.try_recv().is_err());
+        assert_eq!(live_order_ids(&executor), vec![(1, "1"), (2, "3")]);
+    }
+
+    #[tokio::test]
+    async fn stops_once_order_requests_close() {
+        let exchange = MockExchange::default();
+        let (executor, _updates) = executor(&exchange);
+        let (sender, receiver) = channel(1);
+        drop(sender);
+
+        let handle = executor.run(receiver, CancellationToken::new());
+
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("Executor kept running")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancels_and_replaces_live_orders() {
+        let exchange = MockExchange::default();
+        let (mut executor, mut updates) = executor(&exchange);
+        executor.submit_order(limit_order(1)).await;
+        executor.submit_order(limit_order(2)).await;
+
+        exchange
+            .state
+            .lock()
+            .unwrap()
+            .statuses
+            .insert("1".to_string(), status("10", true));
+        executor
+            .cancel_limit_order(CancelOrder {
+                strategy: Strategy::Cashout,
+                id: 1,
+            })
+            .await;
+        let update = updates.try_recv().unwrap();
+        assert_eq!(
+            (update.id, update.filled_amount, update.is_open),
+            (1, Decimal::from(10), false)
+        );
+        assert_eq!(live_order_ids(&executor), vec![(2, "2")]);
+
+        exchange
+            .state
+            .lock()
+            .unwrap()
+            .statuses
+            .insert("2".to_string(), status("30", true));
+        executor
+            .replace_limit_order(ReplaceOrder {
+                strategy: Strategy::Cashout,
+                id: 2,
+                amount: Decimal::from(100),
+                price: Decimal::new(33, 2),
+            })
+            .await;
+        let update = updates.try_recv().unwrap();
+        assert_eq!(
+            (update.id, update.filled_amount, update.is_open),
+            (2, Decimal::from(30), true)
+        );
+        assert!(updates.try_recv().is_err());
+        // The replacement is only for what was left unfilled
+        assert_eq!(live_order_ids(&executor), vec![(2, "3")]);
+        assert_eq!(
+            executor.live_limit_orders[&Strategy::Cashout][&2]
+                .order
+                .amount,
+            Decimal::from(70)
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_tracking_orders_which_failed_to_cancel() {
+        let exchange = MockExchange::default();
+        let (mut executor, mut updates) = executor(&exchange);
+        executor.submit_order(limit_order(1)).await;
+        executor.submit_order(limit_order(2)).await;
+        exchange.state.lock().unwrap().cancel_fails = true;
+
+        executor
+            .cancel_limit_order(CancelOrder {
+                strategy: Strategy::Cashout,
+                id: 1,
+            })
+            .await;
+        executor
+            .replace_limit_order(ReplaceOrder {
+                strategy: Strategy::Cashout,
+                id: 2,
+                amount: Decimal::from(100),
+                price: Decimal::new(33, 2),
+            })
+            .await;
+        assert!(updates.try_recv().is_err());
+        assert_eq!(live_order_ids(&executor), vec![(1, "1"), (2, "2")]);
+
+        // An order which filled before it could be cancelled is closed, but not replaced
+        exchange
+            .state
+            .lock()
+            .unwrap()
+            .statuses
+            .insert("2".to_string(), status("100", false));
+        executor
+            .replace_limit_order(ReplaceOrder {
+                strategy: Strategy::Cashout,
+                id: 2,
+                amount: Decimal::from(100),
+                price: Decimal::new(33, 2),
+            })
+            .await;
+        let update = updates.try_recv().unwrap();
+        assert_eq!(
+            (update.id, update.filled_amount, update.is_open),
+            (2, Decimal::from(100), false)
+        );
+        assert_eq!(live_order_ids(&executor), vec![(1, "1")]);
+    }
+
+    #[tokio::test]
+    async fn records_slippage_of_market_orders() {
+        let exchange = MockExchange::default();
+        exchange.state.lock().unwrap().submitted_status = Some(OrderStatus {
+            filled_amount: Decimal::from(100),
+            filled_value: Decimal::from_str("31.68").unwrap(),
+            is_open: false,
+        });
+        let (executor, mut updates) = executor(&exchange);
+        let mut executor = OrderExecutor {
+            max_slippage: Some(Decimal::new(1, 2)),
+            ..executor
+        };
+        let slippage = SLIPPAGE.with_label_values(&[LBANK.as_str(), Strategy::Manual.as_str()]);
+        let (count, sum) = (slippage.get_sample_count(), slippage.get_sample_sum());
+
+        executor
+            .submit_order(PendingOrder::Market(PendingMarketOrder {
+                id: 1,
+                strategy: Strategy::Manual,
+                exchange: LBANK,
+                direction: Direction::Sell,
+                amount: Decimal::from(100),
+                expected_return: Decimal::from(32),
+                trace: LatencyTrace::default(),
+            }))
+            .await;
+
+        let update = updates.try_recv().unwrap();
+        assert_eq!(update.filled_amount, Decimal::from(100));
+        assert_eq!(slippage.get_sample_count(), count + 1);
+        assert_eq!(slippage.get_sample_sum() - sum, 100.0);
+    }
+
+    #[tokio::test]
+    async fn closes_market_orders_with_unknown_fills() {
+        let exchange = MockExchange::default();
+        exchange.state.lock().unwrap().status_fails = true;
+        let (mut executor, mut updates) = executor(&exchange);
+
+        executor
+            .submit_order(PendingOrder::Market(PendingMarketOrder {
+                id: 1,
+                strategy: Strategy::Cashout,
+                exchange: LBANK,
+                direction: Direction::Sell,
+                amount: Decimal::from(100),
+                expected_return: Decimal::from(32),
+                trace: LatencyTrace::default(),
+            }))
+            .await;
+
+        let update = updates.try_recv().unwrap();
+        assert_eq!(
+            (update.id, update.is_open, update.fills_unknown),
+            (1, false, true)
+        );
+        assert!(updates.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_orders_without_amount() {
+        let exchange = MockExchange::default();
+        let (executor, mut updates) = executor(&exchange);
+        let mut executor = OrderExecutor {
+            max_slippage: Some(Decimal::new(1, 2)),
+            ..executor
+        };
+
+        executor
+            .submit_order(PendingOrder::Market(PendingMarketOrder {
+                id: 1,
+                strategy: Strategy::Cashout,
+                exchange: LBANK,
+                direction: Direction::Sell,
+                amount: Decimal::ZERO,
+                expected_return: Decimal::from(10),
+                trace: LatencyTrace::default(),
+            }))
+            .await;
+
+        let update = updates.try_recv().unwrap();
+        assert_eq!(
+            (update.id, update.filled_amount, update.is_open),
+            (1, Decimal::ZERO, false)
+        );
+        assert_eq!(exchange.state.lock().unwrap().next_id, 0);
+    }
+
+    fn executor(exchange: &MockExchange) -> (OrderExecutor, Receiver<Arc<OrderUpdate>>) {
+        let (sender, receiver) = channel(16);
+        let executor = OrderExecutorBuilder::new()
+            .with_exchange(LBANK, Box::new(exchange.clone()))
+            .with_order_updates(sender)
+            .build();
+        (executor, receiver)
+    }
+
+    fn live_order_ids(executor: &OrderExecutor) -> Vec<(u64, &str)> {
+        let mut ids: Vec<_> = executor
+            .live_limit_orders
+            .values()
+            .flat_map(|o| o.values())
+            .map(|o| (o.order.id, o.exchange_order_id.as_str()))
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    fn limit_order(id: u64) -> PendingOrder {
+        PendingOrder::Limit(PendingLimitOrder {
+            id,
+            strategy: Strategy::Cashout,
+            exchange: LBANK,
+            direction: Direction::Sell,
+            amount: Decimal::from(100),
+            price: Decimal::new(32, 2),
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: true,
+            trace: LatencyTrace::default(),
+        })
+    }
+
+    fn status(filled_amount: &str, is_open: bool) -> OrderStatus {
+        let filled_amount: Decimal = filled_amount.parse().unwrap();
+        OrderStatus {
+            filled_amount,
+            filled_value: filled_amount * Decimal::new(32, 2),
+            is_open,
         }
     }
 }
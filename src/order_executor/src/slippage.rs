@@ -0,0 +1,139 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+use xb_types::{Direction, PendingLimitOrder, PendingMarketOrder, TimeInForce};
+
+const PRICE_DECIMAL_PLACES: u32 = 8;
+
+#[derive(Default)]
+pub struct SlippageStats {
+    pub orders: u64,
+    pub expected: Decimal,
+    // The total amount by which we did worse than expected, across both buys and sells
+    pub shortfall: Decimal,
+}
+
+impl SlippageStats {
+    pub fn record(&mut self, direction: Direction, expected: Decimal, realized: Decimal) {
+        self.orders += 1;
+        self.expected += expected;
+        self.shortfall += shortfall(direction, expected, realized);
+    }
+
+    pub fn slippage_bps(&self) -> Decimal {
+        to_bps(self.shortfall, self.expected)
+    }
+}
+
+// Converts a market order into an IOC limit order which won't fill at a price worse than the
+// average price implied by the order's expected return, adjusted by the allowed slippage
+pub fn protect_market_order(
+    order: &PendingMarketOrder,
+    max_slippage: Decimal,
+) -> Result<PendingLimitOrder, String> {
+    if order.amount <= Decimal::ZERO {
+        return Err(format!("Invalid order amount: {}", order.amount));
+    }
+    let expected_price = order
+        .expected_return
+        .checked_div(order.amount)
+        .ok_or_else(|| format!("Invalid expected return: {}", order.expected_return))?;
+    let price = if order.direction.is_buy() {
+        (expected_price * (Decimal::ONE + max_slippage))
+            .round_dp_with_strategy(PRICE_DECIMAL_PLACES, RoundingStrategy::ToZero)
+    } else {
+        (expected_price * (Decimal::ONE - max_slippage))
+            .round_dp_with_strategy(PRICE_DECIMAL_PLACES, RoundingStrategy::AwayFromZero)
+    };
+
+    Ok(PendingLimitOrder {
+        id: order.id,
+        strategy: order.strategy,
+        exchange: order.exchange,
+        direction: order.direction,
+        amount: order.amount,
+        price,
+        time_in_force: TimeInForce::ImmediateOrCancel,
+        post_only: false,
+        trace: order.trace,
+    })
+}
+
+// Positive values mean we did worse than expected, ie. we received less when selling or paid more
+// when buying
+pub fn slippage_bps(direction: Direction, expected: Decimal, realized: Decimal) -> Decimal {
+    to_bps(shortfall(direction, expected, realized), expected)
+}
+
+fn shortfall(direction: Direction, expected: Decimal, realized: Decimal) -> Decimal {
+    if direction.is_buy() {
+        realized - expected
+    } else {
+        expected - realized
+    }
+}
+
+fn to_bps(value: Decimal, total: Decimal) -> Decimal {
+    if total.is_zero() {
+        Decimal::ZERO
+    } else {
+        (value / total * Decimal::from(10000)).round_dp(2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use test_case::test_case;
+    use xb_types::{Exchange, LatencyTrace, Strategy};
+
+    #[test_case(Direction::Sell, "100", "31.7", Ok("0.31383"); "sell limit below expected price")]
+    #[test_case(Direction::Buy, "100", "31.7", Ok("0.32017"); "buy limit above expected price")]
+    #[test_case(Direction::Sell, "3", "1", Ok("0.33000000"); "rounds sell limit up")]
+    #[test_case(Direction::Buy, "3", "1", Ok("0.33666666"); "rounds buy limit down")]
+    #[test_case(Direction::Sell, "0", "31.7", Err("Invalid order amount: 0"); "zero amount")]
+    #[test_case(Direction::Buy, "-1", "31.7", Err("Invalid order amount: -1"); "negative amount")]
+    fn protect_market_order_tests(
+        direction: Direction,
+        amount: &str,
+        expected_return: &str,
+        expected: Result<&str, &str>,
+    ) {
+        let order = PendingMarketOrder {
+            id: 1,
+            strategy: Strategy::Cashout,
+            exchange: Exchange::new("lbank"),
+            direction,
+            amount: Decimal::from_str(amount).unwrap(),
+            expected_return: Decimal::from_str(expected_return).unwrap(),
+            trace: LatencyTrace::default(),
+        };
+
+        let result = protect_market_order(&order, Decimal::new(1, 2));
+
+        match expected {
+            Ok(price) => {
+                let limit = result.unwrap();
+                assert_eq!(limit.price, Decimal::from_str(price).unwrap());
+                assert_eq!(limit.amount, Decimal::from_str(amount).unwrap());
+                assert_eq!(limit.time_in_force, TimeInForce::ImmediateOrCancel);
+            }
+            Err(error) => assert_eq!(result.unwrap_err(), error),
+        }
+    }
+
+    #[test_case(Direction::Sell, "100", "99", "100"; "selling for less is positive")]
+    #[test_case(Direction::Sell, "100", "101", "-100"; "selling for more is negative")]
+    #[test_case(Direction::Buy, "100", "101", "100"; "buying for more is positive")]
+    #[test_case(Direction::Buy, "300", "299", "-33.33"; "rounds to two decimal places")]
+    #[test_case(Direction::Buy, "0", "1", "0"; "nothing expected")]
+    fn slippage_bps_tests(direction: Direction, expected: &str, realized: &str, bps: &str) {
+        assert_eq!(
+            slippage_bps(
+                direction,
+                Decimal::from_str(expected).unwrap(),
+                Decimal::from_str(realized).unwrap()
+            ),
+            Decimal::from_str(bps).unwrap()
+        );
+    }
+}
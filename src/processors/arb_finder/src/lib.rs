@@ -1,44 +1,118 @@
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{error, info};
+use xb_types::metrics::{
+    ARBS_DETECTED, ARB_THEORETICAL_PROFIT, BROADCAST_LAGGED, TRADING_WINDOW_OPEN,
+};
 use xb_types::{
-    ArbOpportunity, Direction, Exchange, OrderbookState, OrderbookStateProcessor,
-    PendingMarketOrder, PendingOrder,
+    next_parameters, ArbOpportunity, Direction, Exchange, LatencyTrace, Order, OrderRequest,
+    OrderbookState, OrderbookStateProcessor, PauseSwitch, PendingLimitOrder, PendingOrder, Stage,
+    Strategy, TimeInForce, TradingCalendar,
 };
 
 pub struct ArbFinder {
-    order_sender: Sender<Arc<PendingOrder>>,
+    order_sender: Sender<Arc<OrderRequest>>,
     state_per_exchange: HashMap<Exchange, OrderbookState>,
+    next_order_id: u64,
+    calendar: TradingCalendar,
+    parameters: ArbFinderParameters,
+    parameter_updates: Option<watch::Receiver<ArbFinderParameters>>,
+    pause: PauseSwitch,
+}
+
+// The parameters which can be changed while running
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ArbFinderParameters {
+    // Arbs are only traded when the sell price exceeds the buy price by more than this
+    pub min_profit_bps: Decimal,
 }
 
 impl ArbFinder {
-    pub fn new(order_sender: Sender<Arc<PendingOrder>>) -> ArbFinder {
+    pub fn new(order_sender: Sender<Arc<OrderRequest>>) -> ArbFinder {
         ArbFinder {
             order_sender,
             state_per_exchange: HashMap::new(),
+            next_order_id: 0,
+            calendar: TradingCalendar::new(),
+            parameters: ArbFinderParameters::default(),
+            parameter_updates: None,
+            pause: PauseSwitch::new(),
         }
     }
 
+    pub fn with_parameters(mut self, parameters: ArbFinderParameters) -> Self {
+        self.parameters = parameters;
+        self
+    }
+
+    pub fn with_parameter_updates(
+        mut self,
+        receiver: watch::Receiver<ArbFinderParameters>,
+    ) -> Self {
+        self.parameter_updates = Some(receiver);
+        self
+    }
+
+    // Arbs are only traded while the calendar is open, the books are still tracked outside of it
+    pub fn with_calendar(mut self, calendar: TradingCalendar) -> Self {
+        self.calendar = calendar;
+        self
+    }
+
+    pub fn with_pause_switch(mut self, pause: PauseSwitch) -> Self {
+        self.pause = pause;
+        self
+    }
+
     async fn run_async(
         mut self,
         mut updates: Receiver<Arc<OrderbookState>>,
         cancellation_token: CancellationToken,
     ) {
         info!("ArbFinder started");
+        // Reports the initial state of the trading window
+        self.is_trading();
+
+        let mut parameter_updates = self.parameter_updates.take();
 
         loop {
             select! {
-                next = updates.recv() => {
-                    if let Ok(state) = next {
+                parameters = next_parameters(&mut parameter_updates) => {
+                    info!("ArbFinder: Applying parameters: {parameters:?}");
+                    self.parameters = parameters;
+                }
+                next = updates.recv() => match next {
+                    Ok(state) if state.invalidated => {
+                        self.state_per_exchange.remove(&state.exchange);
+                    }
+                    Ok(state) => {
                         let exchange = state.exchange;
-                        self.state_per_exchange.insert(exchange, (*state).clone());
-                        self.find_and_notify_arbs(exchange);
+                        let mut state = (*state).clone();
+                        state.trace.mark(Stage::Broadcast);
+                        self.state_per_exchange.insert(exchange, state);
+                        if self.is_trading() {
+                            for arb in self.find_arbs(exchange) {
+                                self.notify_arb(arb);
+                            }
+                        }
                     }
-                }
+                    Err(RecvError::Lagged(skipped)) => {
+                        BROADCAST_LAGGED.with_label_values(&["arb_finder"]).inc_by(skipped);
+                    }
+                    Err(RecvError::Closed) => {
+                        error!("ArbFinder: Orderbook updates closed");
+                        break;
+                    }
+                },
                 _ = cancellation_token.cancelled() => break,
             }
         }
@@ -46,7 +120,26 @@ impl ArbFinder {
         info!("ArbFinder stopped");
     }
 
-    fn find_and_notify_arbs(&self, latest_update: Exchange) {
+    fn is_trading(&mut self) -> bool {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        if let Some(is_open) = self.calendar.transition(now_ms) {
+            if is_open {
+                info!("ArbFinder: Trading window opened, resuming");
+            } else {
+                info!("ArbFinder: Trading window closed, pausing");
+            }
+            TRADING_WINDOW_OPEN
+                .with_label_values(&[Strategy::ArbFinder.as_str()])
+                .set(if is_open { 1.0 } else { 0.0 });
+        }
+        self.calendar.is_open(now_ms) && !self.pause.is_paused()
+    }
+
+    fn find_arbs(&self, latest_update: Exchange) -> Vec<ArbOpportunity> {
+        let mut arbs = Vec::new();
         if let Some(updated) = self.state_per_exchange.get(&latest_update) {
             if let (Some(updated_bid), Some(updated_ask)) = (updated.best_bid(), updated.best_ask())
             {
@@ -56,48 +149,71 @@ impl ArbFinder {
                     .filter(|v| v.exchange != latest_update)
                 {
                     if let Some(bid) = existing.best_bid() {
-                        if bid.price > updated_ask.price {
+                        if self.is_profitable(&updated_ask, &bid) {
                             let arb = ArbOpportunity {
                                 buy: updated_ask.clone(),
                                 sell: bid.clone(),
+                                trace: updated.trace,
                             };
-                            self.notify_arb(arb);
+                            arbs.push(arb);
                         }
                     }
 
                     if let Some(ask) = existing.best_ask() {
-                        if ask.price < updated_bid.price {
+                        if self.is_profitable(&ask, &updated_bid) {
                             let arb = ArbOpportunity {
                                 buy: ask.clone(),
                                 sell: updated_bid.clone(),
+                                trace: updated.trace,
                             };
-                            self.notify_arb(arb);
+                            arbs.push(arb);
                         }
                     }
                 }
             }
         }
+        arbs
     }
 
-    fn notify_arb(&self, arb: ArbOpportunity) {
+    fn is_profitable(&self, buy: &Order, sell: &Order) -> bool {
+        let profit_bps = (sell.price - buy.price) / buy.price * Decimal::from(10_000);
+        sell.price > buy.price && profit_bps > self.parameters.min_profit_bps
+    }
+
+    fn notify_arb(&mut self, mut arb: ArbOpportunity) {
+        arb.trace.mark(Stage::Detect);
         info!("Found arb: {arb:?}");
 
-        self.order_sender
-            .send(Arc::new(PendingOrder::Market(PendingMarketOrder {
-                exchange: arb.sell.exchange,
-                direction: Direction::Sell,
-                amount: arb.sell.amount,
-                expected_return: arb.sell.amount * arb.sell.price,
-            })))
-            .unwrap();
+        let labels = [arb.buy.exchange.as_str(), arb.sell.exchange.as_str()];
+        let profit = (arb.sell.price - arb.buy.price) * arb.buy.amount.min(arb.sell.amount);
+        ARBS_DETECTED.with_label_values(&labels).inc();
+        ARB_THEORETICAL_PROFIT
+            .with_label_values(&labels)
+            .inc_by(profit.to_f64().unwrap_or_default());
+
+        // Each leg is sent as an IOC limit order at the price the arb was found at, so if the book
+        // has moved by the time the order arrives we don't end up trading at a worse price
+        self.submit_ioc_order(Direction::Sell, &arb.sell, arb.trace);
+        self.submit_ioc_order(Direction::Buy, &arb.buy, arb.trace);
+    }
+
+    fn submit_ioc_order(&mut self, direction: Direction, order: &Order, trace: LatencyTrace) {
+        self.next_order_id += 1;
 
         self.order_sender
-            .send(Arc::new(PendingOrder::Market(PendingMarketOrder {
-                exchange: arb.buy.exchange,
-                direction: Direction::Buy,
-                amount: arb.buy.amount,
-                expected_return: arb.buy.amount * arb.buy.price,
-            })))
+            .send(Arc::new(OrderRequest::Submit(PendingOrder::Limit(
+                PendingLimitOrder {
+                    id: self.next_order_id,
+                    strategy: Strategy::ArbFinder,
+                    exchange: order.exchange,
+                    direction,
+                    amount: order.amount,
+                    price: order.price,
+                    time_in_force: TimeInForce::ImmediateOrCancel,
+                    post_only: false,
+                    trace,
+                },
+            ))))
             .unwrap();
     }
 }
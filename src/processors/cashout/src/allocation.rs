@@ -0,0 +1,158 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use xb_types::{Direction, Exchange};
+
+const AMOUNT_DECIMAL_PLACES: u32 = 8;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CashoutAllocation {
+    // Send each iteration in full to the exchange offering the best return
+    #[default]
+    BestExchange,
+    // Split each iteration across exchanges so as to get the best overall price
+    Split,
+}
+
+impl FromStr for CashoutAllocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "best_exchange" => Ok(CashoutAllocation::BestExchange),
+            "split" => Ok(CashoutAllocation::Split),
+            _ => Err(format!("Unknown cashout allocation: {s}")),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CashoutTarget {
+    // The daily and per iteration amounts are in the base currency (CHAT)
+    #[default]
+    Base,
+    // The daily and per iteration amounts are in the quote currency (USDT), each iteration is
+    // converted into an amount of CHAT using the books at the time it is executed
+    Quote,
+}
+
+impl FromStr for CashoutTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "base" => Ok(CashoutTarget::Base),
+            "quote" => Ok(CashoutTarget::Quote),
+            _ => Err(format!("Unknown cashout target: {s}")),
+        }
+    }
+}
+
+// The remaining size of an order, either as an amount of the base currency or as a value in the
+// quote currency
+#[derive(Copy, Clone, Debug)]
+pub enum OrderSize {
+    Base(Decimal),
+    Quote(Decimal),
+}
+
+impl OrderSize {
+    // Returns the amount to trade against a level, reducing the remaining size accordingly
+    pub fn take(&mut self, price: Decimal, available: Decimal) -> Decimal {
+        match self {
+            OrderSize::Base(remaining) => {
+                let amount = available.min(*remaining);
+                *remaining -= amount;
+                amount
+            }
+            OrderSize::Quote(remaining) => {
+                let required = (*remaining / price)
+                    .round_dp_with_strategy(AMOUNT_DECIMAL_PLACES, RoundingStrategy::AwayFromZero);
+                if available >= required {
+                    *remaining = Decimal::ZERO;
+                    required
+                } else {
+                    *remaining -= available * price;
+                    available
+                }
+            }
+        }
+    }
+
+    pub fn is_filled(&self) -> bool {
+        match self {
+            OrderSize::Base(remaining) | OrderSize::Quote(remaining) => remaining.is_zero(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Allocation {
+    pub exchange: Exchange,
+    pub amount: Decimal,
+    pub expected_return: Decimal,
+}
+
+// Walks the books of all exchanges together from the best price outwards, so that each unit is
+// traded wherever it gets the best price, which gives the best overall price for the given depth
+pub fn split_across_exchanges(
+    books_per_exchange: &HashMap<Exchange, BTreeMap<Decimal, Decimal>>,
+    direction: Direction,
+    mut size: OrderSize,
+    price_limit: Option<Decimal>,
+) -> Option<Vec<Allocation>> {
+    let mut levels: Vec<_> = books_per_exchange
+        .iter()
+        .flat_map(|(e, b)| b.iter().map(|(p, a)| (*e, *p, *a)))
+        .filter(|(_, p, _)| within_limit(*p, price_limit, direction))
+        .collect();
+
+    levels.sort_by_key(|(_, p, _)| *p);
+    if !direction.is_buy() {
+        levels.reverse();
+    }
+
+    let mut allocations: BTreeMap<Exchange, Allocation> = BTreeMap::new();
+
+    for (exchange, price, available) in levels {
+        let amount = size.take(price, available);
+        let allocation = allocations.entry(exchange).or_insert(Allocation {
+            exchange,
+            amount: Decimal::ZERO,
+            expected_return: Decimal::ZERO,
+        });
+        allocation.amount += amount;
+        allocation.expected_return += amount * price;
+
+        if size.is_filled() {
+            return Some(allocations.into_values().collect());
+        }
+    }
+
+    None
+}
+
+// Iterates over the levels of a book from the best price outwards, ie. from the highest bid down
+// when selling, or from the lowest ask up when buying
+pub fn levels_from_best(
+    book: &BTreeMap<Decimal, Decimal>,
+    direction: Direction,
+) -> Box<dyn Iterator<Item = (&Decimal, &Decimal)> + '_> {
+    if direction.is_buy() {
+        Box::new(book.iter())
+    } else {
+        Box::new(book.iter().rev())
+    }
+}
+
+// The price limit is the minimum price when selling and the maximum price when buying
+pub fn within_limit(price: Decimal, price_limit: Option<Decimal>, direction: Direction) -> bool {
+    match price_limit {
+        Some(limit) if direction.is_buy() => price <= limit,
+        Some(limit) => price >= limit,
+        None => true,
+    }
+}
@@ -0,0 +1,282 @@
+use rust_decimal::Decimal;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use xb_types::{Direction, Exchange, OrderbookState};
+
+// Books update many times a second, so only keep one mid price sample per exchange per interval
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Default)]
+pub struct GuardConfig {
+    // Skip iterations while the tightest spread across exchanges is wider than this
+    pub max_spread_bps: Option<Decimal>,
+    // Shrink iterations in proportion to how far the high-low range of the mid price over the
+    // volatility window exceeds this
+    pub max_volatility_bps: Option<Decimal>,
+    pub volatility_window: Duration,
+    // Skip iterations while the mid price is below its moving average over this window when
+    // selling, or above it when buying
+    pub moving_average_window: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GuardDecision {
+    Trade,
+    Shrink(Decimal, String),
+    Skip(String),
+}
+
+// Tracks recent market conditions from the orderbook updates to decide whether an iteration should
+// go ahead
+#[derive(Debug)]
+pub struct MarketGuards {
+    config: GuardConfig,
+    spreads_bps: HashMap<Exchange, Decimal>,
+    // Timestamped mid prices, oldest first
+    mid_prices: HashMap<Exchange, VecDeque<(u64, Decimal)>>,
+}
+
+impl MarketGuards {
+    pub fn new(config: GuardConfig) -> MarketGuards {
+        MarketGuards {
+            config,
+            spreads_bps: HashMap::new(),
+            mid_prices: HashMap::new(),
+        }
+    }
+
+    pub fn on_orderbook_update(&mut self, state: &OrderbookState) {
+        // Prices from before a gap in the exchange's book aren't compared with those after it
+        if state.invalidated {
+            self.spreads_bps.remove(&state.exchange);
+            self.mid_prices.remove(&state.exchange);
+            return;
+        }
+
+        let (Some(bid), Some(ask)) = (state.best_bid(), state.best_ask()) else {
+            return;
+        };
+        let mid_price = (bid.price + ask.price) / Decimal::TWO;
+        self.spreads_bps
+            .insert(state.exchange, to_bps(ask.price - bid.price, mid_price));
+
+        let history = self.mid_prices.entry(state.exchange).or_default();
+        match history.back() {
+            Some((ts, _)) if state.timestamp_ms < ts + SAMPLE_INTERVAL.as_millis() as u64 => {}
+            _ => history.push_back((state.timestamp_ms, mid_price)),
+        }
+
+        let retention = self
+            .config
+            .moving_average_window
+            .unwrap_or_default()
+            .max(self.config.volatility_window)
+            .as_millis() as u64;
+        while let Some((ts, _)) = history.front() {
+            if *ts + retention >= state.timestamp_ms {
+                break;
+            }
+            history.pop_front();
+        }
+    }
+
+    pub fn check(&self, direction: Direction) -> GuardDecision {
+        if let Some(max_spread_bps) = self.config.max_spread_bps {
+            match self.spreads_bps.values().min() {
+                Some(spread_bps) if *spread_bps > max_spread_bps => {
+                    return GuardDecision::Skip(format!("Spread of {spread_bps} bps too wide"));
+                }
+                None => return GuardDecision::Skip("No spread available".to_string()),
+                _ => {}
+            }
+        }
+
+        if let Some(window) = self.config.moving_average_window {
+            for (exchange, history) in &self.mid_prices {
+                let Some(average) = self.moving_average(history, window) else {
+                    continue;
+                };
+                let (_, mid_price) = history.back().unwrap();
+                let unfavourable = if direction.is_buy() {
+                    *mid_price > average
+                } else {
+                    *mid_price < average
+                };
+                if unfavourable {
+                    return GuardDecision::Skip(format!(
+                        "Mid price {mid_price} on {exchange:?} is on the wrong side of its moving average {average}"
+                    ));
+                }
+            }
+        }
+
+        if let Some(max_volatility_bps) = self.config.max_volatility_bps {
+            let volatility_bps = self
+                .mid_prices
+                .values()
+                .filter_map(|h| self.volatility_bps(h))
+                .max();
+            if let Some(volatility_bps) = volatility_bps {
+                if volatility_bps > max_volatility_bps {
+                    return GuardDecision::Shrink(
+                        max_volatility_bps / volatility_bps,
+                        format!("Volatility of {volatility_bps} bps too high"),
+                    );
+                }
+            }
+        }
+
+        GuardDecision::Trade
+    }
+
+    fn moving_average(
+        &self,
+        history: &VecDeque<(u64, Decimal)>,
+        window: Duration,
+    ) -> Option<Decimal> {
+        let samples: Vec<_> = within_window(history, window).collect();
+        if samples.len() < 2 {
+            return None;
+        }
+        Some(samples.iter().sum::<Decimal>() / Decimal::from(samples.len()))
+    }
+
+    // The high-low range of the mid price over the window, relative to the average
+    fn volatility_bps(&self, history: &VecDeque<(u64, Decimal)>) -> Option<Decimal> {
+        let samples: Vec<_> = within_window(history, self.config.volatility_window).collect();
+        if samples.len() < 2 {
+            return None;
+        }
+        let high = samples.iter().max()?;
+        let low = samples.iter().min()?;
+        let average = samples.iter().sum::<Decimal>() / Decimal::from(samples.len());
+        Some(to_bps(high - low, average))
+    }
+}
+
+fn within_window(
+    history: &VecDeque<(u64, Decimal)>,
+    window: Duration,
+) -> impl Iterator<Item = Decimal> + '_ {
+    let latest = history.back().map(|(ts, _)| *ts).unwrap_or_default();
+    let start = latest.saturating_sub(window.as_millis() as u64);
+    history
+        .iter()
+        .filter(move |(ts, _)| *ts >= start)
+        .map(|(_, p)| *p)
+}
+
+fn to_bps(value: Decimal, reference: Decimal) -> Decimal {
+    (value / reference * Decimal::from(10_000)).round_dp(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    const BITRUE: Exchange = Exchange::new("bitrue");
+    const LBANK: Exchange = Exchange::new("lbank");
+
+    #[test]
+    fn skips_when_spread_too_wide() {
+        let mut guards = MarketGuards::new(GuardConfig {
+            max_spread_bps: Some(dec("50")),
+            ..Default::default()
+        });
+
+        guards.on_orderbook_update(&book(LBANK, 0, "0.3100", "0.3200"));
+        assert!(matches!(
+            guards.check(Direction::Sell),
+            GuardDecision::Skip(_)
+        ));
+
+        // Only the tightest spread matters
+        guards.on_orderbook_update(&book(BITRUE, 0, "0.3165", "0.3176"));
+        assert_eq!(guards.check(Direction::Sell), GuardDecision::Trade);
+    }
+
+    #[test]
+    fn shrinks_when_volatile() {
+        let mut guards = MarketGuards::new(GuardConfig {
+            max_volatility_bps: Some(dec("100")),
+            volatility_window: Duration::from_secs(60),
+            ..Default::default()
+        });
+
+        guards.on_orderbook_update(&book(LBANK, 0, "0.3990", "0.4010"));
+        guards.on_orderbook_update(&book(LBANK, 30_000, "0.3590", "0.3610"));
+        // Sampled too soon after the previous update so ignored
+        guards.on_orderbook_update(&book(LBANK, 30_500, "0.1000", "0.1010"));
+
+        // A range of 0.04 around an average of 0.38 is 1052.63 bps
+        assert_eq!(
+            guards.check(Direction::Sell),
+            GuardDecision::Shrink(
+                dec("100") / dec("1052.63"),
+                "Volatility of 1052.63 bps too high".to_string()
+            )
+        );
+
+        // Once the spike drops out of the window the iteration goes ahead
+        guards.on_orderbook_update(&book(LBANK, 100_000, "0.3590", "0.3610"));
+        assert_eq!(guards.check(Direction::Sell), GuardDecision::Trade);
+    }
+
+    #[test]
+    fn forgets_invalidated_books() {
+        let mut guards = MarketGuards::new(GuardConfig {
+            max_spread_bps: Some(dec("50")),
+            moving_average_window: Some(Duration::from_secs(600)),
+            ..Default::default()
+        });
+
+        guards.on_orderbook_update(&book(LBANK, 0, "0.3165", "0.3176"));
+        guards.on_orderbook_update(&book(BITRUE, 0, "0.3100", "0.3200"));
+        assert_eq!(guards.check(Direction::Sell), GuardDecision::Trade);
+
+        // The tight spread is gone with the book it came from
+        guards.on_orderbook_update(&OrderbookState::invalidated(LBANK, 1_000));
+        assert!(matches!(
+            guards.check(Direction::Sell),
+            GuardDecision::Skip(_)
+        ));
+
+        // Prices from before the gap don't count towards the moving average
+        guards.on_orderbook_update(&book(LBANK, 60_000, "0.3065", "0.3076"));
+        assert_eq!(guards.check(Direction::Sell), GuardDecision::Trade);
+    }
+
+    #[test]
+    fn skips_when_price_on_wrong_side_of_moving_average() {
+        let mut guards = MarketGuards::new(GuardConfig {
+            moving_average_window: Some(Duration::from_secs(600)),
+            ..Default::default()
+        });
+
+        guards.on_orderbook_update(&book(LBANK, 0, "0.3190", "0.3210"));
+        guards.on_orderbook_update(&book(LBANK, 60_000, "0.3090", "0.3110"));
+
+        assert!(matches!(
+            guards.check(Direction::Sell),
+            GuardDecision::Skip(_)
+        ));
+        assert_eq!(guards.check(Direction::Buy), GuardDecision::Trade);
+    }
+
+    fn book(exchange: Exchange, timestamp_ms: u64, bid: &str, ask: &str) -> OrderbookState {
+        OrderbookState {
+            exchange,
+            timestamp_ms,
+            asks: BTreeMap::from([(dec(ask), dec("1000"))]),
+            bids: BTreeMap::from([(dec(bid), dec("1000"))]),
+            invalidated: false,
+            trace: Default::default(),
+        }
+    }
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+}
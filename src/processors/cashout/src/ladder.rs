@@ -0,0 +1,471 @@
+use crate::allocation::{within_limit, CashoutTarget, OrderSize};
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tokio::time::Instant;
+use xb_types::{Direction, Exchange, OrderUpdate};
+
+// The CHAT/USDT tick size on both exchanges
+const PRICE_DECIMAL_PLACES: u32 = 4;
+// Limits how often the orders on each exchange are moved, as every move is a cancel and a resubmit
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct LadderConfig {
+    // The number of orders to keep resting on each exchange
+    pub levels: usize,
+    // The size of each order in the base currency
+    pub amount_per_level: Decimal,
+    // The distance between consecutive levels, the first level joins the best price on our side
+    pub spacing_bps: Decimal,
+    // Orders are only moved once the price they should be at differs by more than this
+    pub refresh_threshold_bps: Decimal,
+    // The fraction of the daily amount the passive fills may fall behind a straight line from
+    // midnight before the schedule starts taking liquidity to catch up
+    pub catch_up_tolerance: Decimal,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LadderOrder {
+    pub id: u64,
+    pub price: Decimal,
+    pub remaining: Decimal,
+    // Set once a cancellation has been sent, the slot is left alone until it's confirmed
+    pub cancelling: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LadderAction {
+    Place {
+        exchange: Exchange,
+        level: usize,
+        price: Decimal,
+        amount: Decimal,
+    },
+    Move {
+        id: u64,
+        price: Decimal,
+        amount: Decimal,
+    },
+    Cancel {
+        id: u64,
+    },
+}
+
+// A ladder of resting limit orders on our own side of each book, sells from the best ask up or buys
+// from the best bid down, which trade passively instead of crossing the spread. The first level
+// joins the touch rather than improving on it, so it queues behind the orders already there but
+// never narrows the spread we'd be selling or buying into.
+#[derive(Debug)]
+pub struct Ladder {
+    pub config: LadderConfig,
+    orders_per_exchange: HashMap<Exchange, Vec<Option<LadderOrder>>>,
+    last_refresh: HashMap<Exchange, Instant>,
+}
+
+impl Ladder {
+    pub fn new(config: LadderConfig) -> Ladder {
+        Ladder {
+            config,
+            orders_per_exchange: HashMap::new(),
+            last_refresh: HashMap::new(),
+        }
+    }
+
+    // Works out which orders to place or move given the best price on our side of the book.
+    // New orders are only placed while the budget allows, which is the amount left for the day
+    // less whatever is already resting.
+    pub fn refresh(
+        &mut self,
+        exchange: Exchange,
+        reference_price: Decimal,
+        direction: Direction,
+        price_limit: Option<Decimal>,
+        mut budget: OrderSize,
+        now: Instant,
+    ) -> Vec<LadderAction> {
+        if let Some(last_refresh) = self.last_refresh.get(&exchange) {
+            if now < *last_refresh + MIN_REFRESH_INTERVAL {
+                return Vec::new();
+            }
+        }
+
+        let levels = self.config.levels;
+        let orders = self
+            .orders_per_exchange
+            .entry(exchange)
+            .or_insert_with(|| vec![None; levels]);
+
+        let mut actions = Vec::new();
+        for (level, slot) in orders.iter_mut().enumerate() {
+            let price = level_price(reference_price, level, self.config.spacing_bps, direction);
+            let in_limit = within_limit(price, price_limit, direction);
+
+            match slot {
+                Some(order) if order.cancelling => {}
+                Some(order) if !in_limit => {
+                    order.cancelling = true;
+                    actions.push(LadderAction::Cancel { id: order.id });
+                }
+                Some(order)
+                    if difference_bps(order.price, price) > self.config.refresh_threshold_bps =>
+                {
+                    order.price = price;
+                    actions.push(LadderAction::Move {
+                        id: order.id,
+                        price,
+                        amount: order.remaining,
+                    });
+                }
+                Some(_) => {}
+                None if in_limit => {
+                    let amount = budget.take(price, self.config.amount_per_level);
+                    if amount > Decimal::ZERO {
+                        actions.push(LadderAction::Place {
+                            exchange,
+                            level,
+                            price,
+                            amount,
+                        });
+                    }
+                }
+                None => {}
+            }
+        }
+
+        if !actions.is_empty() {
+            self.last_refresh.insert(exchange, now);
+        }
+        actions
+    }
+
+    // The best price on our side of the book once our own resting orders are taken out of it, so
+    // the ladder doesn't end up chasing itself
+    pub fn reference_price(
+        &self,
+        exchange: Exchange,
+        levels: &BTreeMap<Decimal, Decimal>,
+        direction: Direction,
+    ) -> Option<Decimal> {
+        let ours = |price: &Decimal| -> Decimal {
+            self.orders_per_exchange
+                .get(&exchange)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter(|o| o.price == *price)
+                .map(|o| o.remaining)
+                .sum()
+        };
+        let others = |(price, amount): &(&Decimal, &Decimal)| **amount > ours(price);
+        let level = if direction.is_buy() {
+            levels.iter().rev().find(others)
+        } else {
+            levels.iter().find(others)
+        };
+        level.map(|(price, _)| *price)
+    }
+
+    pub fn insert(&mut self, exchange: Exchange, level: usize, order: LadderOrder) {
+        if let Some(slot) = self
+            .orders_per_exchange
+            .get_mut(&exchange)
+            .and_then(|o| o.get_mut(level))
+        {
+            *slot = Some(order);
+        }
+    }
+
+    // Applies a fill or closure to the matching order, returning false if the update isn't for an
+    // order in the ladder
+    pub fn apply_update(&mut self, update: &OrderUpdate) -> bool {
+        let Some(slot) = self
+            .orders_per_exchange
+            .get_mut(&update.exchange)
+            .and_then(|o| {
+                o.iter_mut()
+                    .find(|o| matches!(o, Some(o) if o.id == update.id))
+            })
+        else {
+            return false;
+        };
+
+        if update.is_open {
+            if let Some(order) = slot {
+                order.remaining = (order.remaining - update.filled_amount).max(Decimal::ZERO);
+            }
+        } else {
+            *slot = None;
+        }
+        true
+    }
+
+    // Cancels every resting order, the slots are cleared once the cancellations are confirmed
+    pub fn cancel_all(&mut self) -> Vec<LadderAction> {
+        self.orders_per_exchange
+            .values_mut()
+            .flatten()
+            .flatten()
+            .filter(|o| !o.cancelling)
+            .map(|o| {
+                o.cancelling = true;
+                LadderAction::Cancel { id: o.id }
+            })
+            .collect()
+    }
+
+    // The total amount resting across all exchanges, in the same units as the daily target
+    pub fn resting(&self, target: CashoutTarget) -> Decimal {
+        self.orders_per_exchange
+            .values()
+            .flatten()
+            .flatten()
+            .map(|o| match target {
+                CashoutTarget::Base => o.remaining,
+                CashoutTarget::Quote => o.remaining * o.price,
+            })
+            .sum()
+    }
+}
+
+// Levels move away from the spread, up from the best ask when selling or down from the best bid
+// when buying, rounding away from the spread onto the tick size. Level 0 is the touch itself.
+fn level_price(
+    reference_price: Decimal,
+    level: usize,
+    spacing_bps: Decimal,
+    direction: Direction,
+) -> Decimal {
+    let offset = Decimal::from(level) * spacing_bps / Decimal::from(10_000);
+    if direction.is_buy() {
+        (reference_price * (Decimal::ONE - offset))
+            .round_dp_with_strategy(PRICE_DECIMAL_PLACES, RoundingStrategy::ToZero)
+    } else {
+        (reference_price * (Decimal::ONE + offset))
+            .round_dp_with_strategy(PRICE_DECIMAL_PLACES, RoundingStrategy::AwayFromZero)
+    }
+}
+
+fn difference_bps(price: Decimal, target: Decimal) -> Decimal {
+    (price - target).abs() / target * Decimal::from(10_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use test_case::test_case;
+    use xb_types::Strategy;
+
+    const BITRUE: Exchange = Exchange::new("bitrue");
+    const LBANK: Exchange = Exchange::new("lbank");
+
+    #[test_case(Direction::Sell, 0, "0.3176"; "sell first level joins best ask")]
+    #[test_case(Direction::Sell, 2, "0.3183"; "sell levels step up and round up")]
+    #[test_case(Direction::Buy, 0, "0.3176"; "buy first level joins best bid")]
+    #[test_case(Direction::Buy, 2, "0.3169"; "buy levels step down and round down")]
+    fn level_price_tests(direction: Direction, level: usize, expected: &str) {
+        let result = level_price(dec("0.3176"), level, dec("10"), direction);
+
+        assert_eq!(result, dec(expected));
+    }
+
+    // Joining the touch is intended, the first level must neither improve on it nor fall behind
+    #[test_case(Direction::Sell, "0.3176", "100"; "sell joins best ask with wide spacing")]
+    #[test_case(Direction::Buy, "0.3165", "100"; "buy joins best bid with wide spacing")]
+    #[test_case(Direction::Sell, "0.3176", "0"; "sell joins best ask without spacing")]
+    fn first_level_joins_touch(direction: Direction, touch: &str, spacing_bps: &str) {
+        let result = level_price(dec(touch), 0, dec(spacing_bps), direction);
+
+        assert_eq!(result, dec(touch));
+    }
+
+    #[test]
+    fn places_levels_within_budget() {
+        let mut ladder = Ladder::new(config());
+
+        let actions = ladder.refresh(
+            LBANK,
+            dec("0.3176"),
+            Direction::Sell,
+            None,
+            OrderSize::Base(dec("1500")),
+            Instant::now(),
+        );
+
+        assert_eq!(
+            actions,
+            vec![
+                LadderAction::Place {
+                    exchange: LBANK,
+                    level: 0,
+                    price: dec("0.3176"),
+                    amount: dec("1000"),
+                },
+                LadderAction::Place {
+                    exchange: LBANK,
+                    level: 1,
+                    price: dec("0.3180"),
+                    amount: dec("500"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn moves_orders_beyond_threshold_and_cancels_beyond_limit() {
+        let mut ladder = Ladder::new(config());
+        let start = Instant::now();
+        ladder.refresh(
+            LBANK,
+            dec("0.3176"),
+            Direction::Sell,
+            Some(dec("0.3170")),
+            OrderSize::Base(dec("3000")),
+            start,
+        );
+        ladder.insert(LBANK, 0, order(1, "0.3176"));
+        ladder.insert(LBANK, 1, order(2, "0.3180"));
+        ladder.insert(LBANK, 2, order(3, "0.3183"));
+
+        // Within the rate limit nothing moves
+        let actions = ladder.refresh(
+            LBANK,
+            dec("0.3160"),
+            Direction::Sell,
+            Some(dec("0.3170")),
+            OrderSize::Base(Decimal::ZERO),
+            start,
+        );
+        assert!(actions.is_empty());
+
+        let actions = ladder.refresh(
+            LBANK,
+            dec("0.3166"),
+            Direction::Sell,
+            Some(dec("0.3170")),
+            OrderSize::Base(Decimal::ZERO),
+            start + MIN_REFRESH_INTERVAL,
+        );
+
+        assert_eq!(
+            actions,
+            vec![
+                LadderAction::Cancel { id: 1 },
+                LadderAction::Move {
+                    id: 2,
+                    price: dec("0.3170"),
+                    amount: dec("1000"),
+                },
+                LadderAction::Move {
+                    id: 3,
+                    price: dec("0.3173"),
+                    amount: dec("1000"),
+                },
+            ]
+        );
+
+        // The cancellation isn't sent again while waiting for it to be confirmed
+        let actions = ladder.refresh(
+            LBANK,
+            dec("0.3166"),
+            Direction::Sell,
+            Some(dec("0.3170")),
+            OrderSize::Base(Decimal::ZERO),
+            start + MIN_REFRESH_INTERVAL * 2,
+        );
+        assert!(actions.is_empty());
+        assert_eq!(
+            ladder.cancel_all(),
+            vec![
+                LadderAction::Cancel { id: 2 },
+                LadderAction::Cancel { id: 3 }
+            ]
+        );
+        assert!(ladder.cancel_all().is_empty());
+    }
+
+    #[test]
+    fn applies_fills_to_ladder_orders() {
+        let mut ladder = Ladder::new(config());
+        ladder.refresh(
+            BITRUE,
+            dec("0.3176"),
+            Direction::Sell,
+            None,
+            OrderSize::Base(Decimal::ZERO),
+            Instant::now(),
+        );
+        ladder.insert(BITRUE, 0, order(1, "0.3176"));
+
+        assert!(ladder.apply_update(&update(1, "400", true)));
+        assert_eq!(ladder.resting(CashoutTarget::Base), dec("600"));
+        assert_eq!(ladder.resting(CashoutTarget::Quote), dec("190.56"));
+
+        assert!(!ladder.apply_update(&update(2, "400", true)));
+
+        assert!(ladder.apply_update(&update(1, "600", false)));
+        assert_eq!(ladder.resting(CashoutTarget::Base), Decimal::ZERO);
+    }
+
+    #[test]
+    fn reference_price_excludes_our_orders() {
+        let mut ladder = Ladder::new(config());
+        ladder.refresh(
+            LBANK,
+            dec("0.3176"),
+            Direction::Sell,
+            None,
+            OrderSize::Base(Decimal::ZERO),
+            Instant::now(),
+        );
+        ladder.insert(LBANK, 0, order(1, "0.3176"));
+        let asks = BTreeMap::from([(dec("0.3176"), dec("1000")), (dec("0.3180"), dec("1500"))]);
+
+        // Only our order rests at the best ask
+        assert_eq!(
+            ladder.reference_price(LBANK, &asks, Direction::Sell),
+            Some(dec("0.3180"))
+        );
+        assert_eq!(
+            ladder.reference_price(BITRUE, &asks, Direction::Sell),
+            Some(dec("0.3176"))
+        );
+    }
+
+    fn config() -> LadderConfig {
+        LadderConfig {
+            levels: 3,
+            amount_per_level: dec("1000"),
+            spacing_bps: dec("10"),
+            refresh_threshold_bps: dec("5"),
+            catch_up_tolerance: dec("0.05"),
+        }
+    }
+
+    fn order(id: u64, price: &str) -> LadderOrder {
+        LadderOrder {
+            id,
+            price: dec(price),
+            remaining: dec("1000"),
+            cancelling: false,
+        }
+    }
+
+    fn update(id: u64, filled_amount: &str, is_open: bool) -> OrderUpdate {
+        OrderUpdate {
+            strategy: Strategy::Cashout,
+            id,
+            exchange: BITRUE,
+            direction: Direction::Sell,
+            filled_amount: dec(filled_amount),
+            filled_value: dec(filled_amount) * dec("0.3176"),
+            is_open,
+            fills_unknown: false,
+        }
+    }
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+}
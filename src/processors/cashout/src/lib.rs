@@ -1,48 +1,236 @@
-use rand::random;
+use crate::allocation::{
+    levels_from_best, split_across_exchanges, within_limit, Allocation, OrderSize,
+};
+use crate::guards::{GuardDecision, MarketGuards};
+use crate::ladder::{Ladder, LadderAction, LadderOrder};
+use crate::progress::{fraction_of_day_elapsed, now_ms};
 use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::Decimal;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::select;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
 use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
 use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
-use tracing::{info, trace};
+use tracing::{error, info, trace, warn};
+use xb_types::metrics::{
+    BROADCAST_LAGGED, CASHOUT_REMAINING_TODAY, CASHOUT_TRADED_TODAY, TRADING_WINDOW_OPEN,
+};
 use xb_types::{
-    Direction, Exchange, OrderbookState, OrderbookStateProcessor, PendingMarketOrder, PendingOrder,
+    next_parameters, Alert, CancelOrder, Direction, Exchange, LatencyTrace, OrderRequest,
+    OrderUpdate, OrderbookState, OrderbookStateProcessor, PauseSwitch, PendingLimitOrder,
+    PendingMarketOrder, PendingOrder, ReplaceOrder, Stage, Strategy, TimeInForce, TradingCalendar,
 };
 
-const ONE_DAY: Duration = Duration::from_secs(24 * 60 * 60);
+mod allocation;
+mod guards;
+mod ladder;
+mod progress;
+mod schedule;
+
+pub use allocation::{CashoutAllocation, CashoutTarget};
+pub use guards::GuardConfig;
+pub use ladder::LadderConfig;
+pub use progress::CashoutProgress;
+pub use schedule::{
+    CashoutSchedule, PercentOfVolumeSchedule, PoissonSchedule, TwapSchedule, VwapSchedule,
+};
 
+// Sells (or when configured to buy, accumulates) CHAT gradually over time according to a schedule
 pub struct Cashout {
-    average_interval: Duration,
-    amount_per_iteration: Decimal,
-    min_price: Option<Decimal>,
-    order_sender: Sender<Arc<PendingOrder>>,
-    asks_per_exchange: HashMap<Exchange, BTreeMap<Decimal, Decimal>>,
+    schedule: Box<dyn CashoutSchedule>,
+    direction: Direction,
+    amount_per_day: Decimal,
+    // The minimum price when selling or the maximum price when buying
+    price_limit: Option<Decimal>,
+    order_sender: Sender<Arc<OrderRequest>>,
+    // The side of each book which our orders execute against, the bids when selling or the asks
+    // when buying
+    books_per_exchange: HashMap<Exchange, BTreeMap<Decimal, Decimal>>,
+    next_order_id: u64,
+    progress: CashoutProgress,
+    state_path: Option<PathBuf>,
+    // Set when the saved progress couldn't be loaded, in which case it refuses to run
+    state_error: Option<String>,
+    // The orders sent when taking liquidity, booked at their expected fill until the order
+    // executor reports the actual one
+    taken_orders: HashMap<u64, TakenOrder>,
+    allocation: CashoutAllocation,
+    target: CashoutTarget,
+    ladder: Option<Ladder>,
+    // The exchanges where the ladder's orders can be post-only
+    post_only_exchanges: HashSet<Exchange>,
+    order_updates: Option<Receiver<Arc<OrderUpdate>>>,
+    // Whether the order updates are received, the receiver itself is moved out while running
+    tracks_fills: bool,
+    guards: Option<MarketGuards>,
+    calendar: TradingCalendar,
+    parameter_updates: Option<watch::Receiver<CashoutParameters>>,
+    pause: PauseSwitch,
+    was_trading: bool,
+    alerts: Option<Sender<Arc<Alert>>>,
+}
+
+struct TakenOrder {
+    day: u64,
+    amount: Decimal,
+    value: Decimal,
+}
+
+// The parameters which can be changed while running
+#[derive(Clone, Debug, PartialEq)]
+pub struct CashoutParameters {
+    pub amount_per_day: Decimal,
+    pub amount_per_iteration: Decimal,
+    pub price_limit: Option<Decimal>,
 }
 
 impl Cashout {
     pub fn new(
         amount_per_day: Decimal,
         amount_per_iteration: Decimal,
-        min_price: Option<Decimal>,
-        order_sender: Sender<Arc<PendingOrder>>,
+        price_limit: Option<Decimal>,
+        order_sender: Sender<Arc<OrderRequest>>,
     ) -> Cashout {
-        let average_interval = Duration::from_millis(
-            (Decimal::from(ONE_DAY.as_millis()) * amount_per_iteration / amount_per_day)
-                .to_u64()
-                .unwrap(),
-        );
-
         Cashout {
-            average_interval,
-            amount_per_iteration,
-            min_price,
+            schedule: Box::new(PoissonSchedule::new(amount_per_day, amount_per_iteration)),
+            direction: Direction::Sell,
+            amount_per_day,
+            price_limit,
             order_sender,
-            asks_per_exchange: HashMap::new(),
+            books_per_exchange: HashMap::new(),
+            next_order_id: 0,
+            progress: CashoutProgress::default(),
+            state_path: None,
+            state_error: None,
+            taken_orders: HashMap::new(),
+            allocation: CashoutAllocation::default(),
+            target: CashoutTarget::default(),
+            ladder: None,
+            post_only_exchanges: HashSet::new(),
+            order_updates: None,
+            tracks_fills: false,
+            guards: None,
+            calendar: TradingCalendar::new(),
+            parameter_updates: None,
+            pause: PauseSwitch::new(),
+            was_trading: false,
+            alerts: None,
+        }
+    }
+
+    // Buying turns this into an accumulation strategy, walking the asks and enforcing the price
+    // limit as a maximum price
+    pub fn with_direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    pub fn with_schedule(mut self, schedule: Box<dyn CashoutSchedule>) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    pub fn with_allocation(mut self, allocation: CashoutAllocation) -> Self {
+        self.allocation = allocation;
+        self
+    }
+
+    pub fn with_target(mut self, target: CashoutTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    // Trades passively through a ladder of resting limit orders, which needs the order updates to
+    // track fills. The schedule then only takes liquidity when the fills fall behind.
+    pub fn with_ladder(mut self, config: LadderConfig) -> Self {
+        self.ladder = Some(Ladder::new(config));
+        self
+    }
+
+    // Ladder orders on these exchanges are post-only, so a book which moved before they arrive
+    // can't make them cross the spread and pay taker fees
+    pub fn with_post_only_exchanges(mut self, exchanges: HashSet<Exchange>) -> Self {
+        self.post_only_exchanges = exchanges;
+        self
+    }
+
+    // Books the actual fills of our orders in place of their expected fills
+    pub fn with_order_updates(mut self, order_updates: Receiver<Arc<OrderUpdate>>) -> Self {
+        self.order_updates = Some(order_updates);
+        self.tracks_fills = true;
+        self
+    }
+
+    // Skips or shrinks iterations while market conditions are unfavourable, carrying the amount
+    // held back forward to later iterations the same day
+    pub fn with_guards(mut self, config: GuardConfig) -> Self {
+        self.guards = Some(MarketGuards::new(config));
+        self
+    }
+
+    // Iterations only run and the ladder only rests while the calendar is open
+    pub fn with_calendar(mut self, calendar: TradingCalendar) -> Self {
+        self.calendar = calendar;
+        self
+    }
+
+    pub fn with_parameter_updates(mut self, receiver: watch::Receiver<CashoutParameters>) -> Self {
+        self.parameter_updates = Some(receiver);
+        self
+    }
+
+    // While paused no iterations run and the ladder is pulled, the books are still tracked
+    pub fn with_pause_switch(mut self, pause: PauseSwitch) -> Self {
+        self.pause = pause;
+        self
+    }
+
+    // Publishes an alert when the daily target is reached
+    pub fn with_alerts(mut self, sender: Sender<Arc<Alert>>) -> Self {
+        self.alerts = Some(sender);
+        self
+    }
+
+    // Persists the amount traded each day to the given path so that the daily cap and schedule
+    // survive restarts, resuming from the existing state if there is any
+    pub fn with_state_path(mut self, path: PathBuf) -> Self {
+        match CashoutProgress::load(&path) {
+            Ok(Some(progress)) => {
+                info!(
+                    "{}: Resuming from saved progress: {progress:?}",
+                    self.name()
+                );
+                self.progress = progress;
+            }
+            Ok(None) => {}
+            Err(error) => self.state_error = Some(error),
+        }
+        self.state_path = Some(path);
+        self
+    }
+
+    // Why the saved progress couldn't be loaded, if it couldn't
+    pub fn state_error(&self) -> Option<&str> {
+        self.state_error.as_deref()
+    }
+
+    pub fn remaining_today(&self) -> Decimal {
+        (self.amount_per_day - self.completed_today()).max(Decimal::ZERO)
+    }
+
+    // The amount traded today in the same units as the daily target
+    fn completed_today(&self) -> Decimal {
+        let mut progress = self.progress.clone();
+        progress.roll_over(now_ms());
+        match self.target {
+            CashoutTarget::Base => progress.amount_traded,
+            CashoutTarget::Quote => progress.value_traded,
         }
     }
 
@@ -51,82 +239,531 @@ impl Cashout {
         mut updates: Receiver<Arc<OrderbookState>>,
         cancellation_token: CancellationToken,
     ) {
+        // Saving over it would lose what was traded today
+        if let Some(error) = &self.state_error {
+            error!("{}: Not starting. {error}", self.name());
+            return;
+        }
+
         info!(
-            "Cashout started. AmountPerIteration: {}. AverageInterval: {:?}. MinPrice: {:?}",
-            self.amount_per_iteration, self.average_interval, self.min_price
+            "{} started. Schedule: {:?}. PriceLimit: {:?}. RemainingToday: {}",
+            self.name(),
+            self.schedule,
+            self.price_limit,
+            self.remaining_today()
         );
 
-        let sleep = tokio::time::sleep(self.next_interval());
+        self.record_progress();
+        // Reports the initial state of the trading window
+        self.is_trading();
+
+        // If an iteration was scheduled before the last restart then stick to that schedule
+        let first_iteration = match self.progress.next_iteration_timestamp_ms {
+            Some(ts) => Instant::now() + Duration::from_millis(ts.saturating_sub(now_ms())),
+            None => self.schedule_next_iteration(),
+        };
+
+        let sleep = tokio::time::sleep_until(first_iteration);
         tokio::pin!(sleep);
 
+        let mut order_updates = self.order_updates.take();
+        let mut parameter_updates = self.parameter_updates.take();
+
         loop {
             select! {
-                next = updates.recv() => {
-                    if let Ok(state) = next {
+                next = updates.recv() => match next {
+                    Ok(state) => {
                         let exchange = state.exchange;
-                        self.asks_per_exchange.insert(exchange, state.asks.clone());
+                        self.schedule.on_orderbook_update(&state);
+                        if let Some(guards) = &mut self.guards {
+                            guards.on_orderbook_update(&state);
+                        }
+                        if state.invalidated {
+                            self.books_per_exchange.remove(&exchange);
+                            continue;
+                        }
+                        let book = if self.direction.is_buy() { &state.asks } else { &state.bids };
+                        self.books_per_exchange.insert(exchange, book.clone());
+                        if self.is_trading() {
+                            let mut trace = state.trace;
+                            trace.mark(Stage::Broadcast);
+                            self.refresh_ladder(&state, trace);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        BROADCAST_LAGGED
+                            .with_label_values(&[self.strategy().as_str()])
+                            .inc_by(skipped);
                     }
+                    // Stopping leaves restarting to the supervisor
+                    Err(RecvError::Closed) => {
+                        error!("{}: Orderbook updates closed", self.name());
+                        break;
+                    }
+                },
+                parameters = next_parameters(&mut parameter_updates) => {
+                    self.apply_parameters(parameters);
                 }
-                _ = &mut sleep => {
-                    if let Some((exchange, expected_return)) = self
-                        .asks_per_exchange
-                        .iter()
-                        .filter_map(|(e, a)| self.calculate_return(a).map(|r| (*e, r)))
-                        .max_by_key(|(_, r)| *r)
-                    {
-                        let order = PendingMarketOrder {
-                            exchange,
-                            direction: Direction::Sell,
-                            amount: self.amount_per_iteration,
-                            expected_return,
-                        };
-                        info!("Cashout: {order:?}");
-                        self.order_sender.send(Arc::new(PendingOrder::Market(order))).unwrap();
-                    } else {
-                        info!("Cashout: No cashout available");
+                next = recv_order_update(&mut order_updates) => match next {
+                    Ok(update) => self.process_order_update(&update),
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => {
+                        error!("{}: Order updates closed", self.name());
+                        break;
                     }
-                    sleep.as_mut().reset(Instant::now() + self.next_interval());
+                },
+                _ = &mut sleep => {
+                    self.run_iteration();
+                    sleep.as_mut().reset(self.schedule_next_iteration());
                 }
                 _ = cancellation_token.cancelled() => break,
             }
         }
 
-        info!("Cashout stopped");
+        // Count the fills published while the order executor cancelled the ladder on shutdown
+        // before taking the final snapshot
+        if let Some(order_updates) = &mut order_updates {
+            loop {
+                match order_updates.try_recv() {
+                    Ok(update) => self.process_order_update(&update),
+                    Err(TryRecvError::Lagged(_)) => {}
+                    Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                }
+            }
+        }
+        self.save_progress();
+
+        info!("{} stopped", self.name());
     }
 
-    fn next_interval(&self) -> Duration {
-        // Generate random interval such that the events follow a Poisson distribution
-        // (https://en.wikipedia.org/wiki/Poisson_distribution), where on average the desired amount
-        // will be cashed out per day
-        let rand = -random::<f64>().ln();
+    fn run_iteration(&mut self) {
+        if !self.is_trading() {
+            info!("{}: Outside trading window", self.name());
+            return;
+        }
 
-        let interval =
-            Duration::from_millis((rand * self.average_interval.as_millis() as f64) as u64);
-        trace!("Cashout: next interval: {interval:?}");
-        interval
-    }
+        self.progress.roll_over(now_ms());
 
-    fn calculate_return(&self, asks: &BTreeMap<Decimal, Decimal>) -> Option<Decimal> {
-        let mut total_return = Decimal::ZERO;
-        let mut total_remaining = self.amount_per_iteration;
+        let remaining_today = self.remaining_today();
+        if remaining_today.is_zero() {
+            info!("{}: Daily cap reached", self.name());
+            return;
+        }
 
-        for (&price, &amount) in asks {
-            if let Some(min_price) = self.min_price {
-                if price < min_price {
-                    break;
+        let mut amount =
+            (self.schedule.next_amount() + self.progress.carried_forward).min(remaining_today);
+        if let Some(ladder) = &self.ladder {
+            // Leave the trading to the ladder unless its fills have fallen behind schedule
+            let shortfall = self.shortfall();
+            if shortfall <= ladder.config.catch_up_tolerance * self.amount_per_day {
+                info!("{}: Passive fills on schedule", self.name());
+                return;
+            }
+            let resting = ladder.resting(self.target);
+            info!(
+                "{}: Passive fills behind schedule by {shortfall}, taking liquidity",
+                self.name()
+            );
+            amount = amount.min(shortfall).min(remaining_today - resting);
+        }
+
+        // Whatever was carried forward is consumed now, only what the guards hold back carries on
+        let scheduled = amount;
+        if let Some(guards) = &self.guards {
+            match guards.check(self.direction) {
+                GuardDecision::Trade => {}
+                GuardDecision::Shrink(fraction, reason) => {
+                    amount *= fraction;
+                    info!("{}: Shrinking iteration. {reason}", self.name());
+                }
+                GuardDecision::Skip(reason) => {
+                    amount = Decimal::ZERO;
+                    info!("{}: Skipping iteration. {reason}", self.name());
+                }
+            }
+        }
+        if self.progress.carried_forward != scheduled - amount {
+            self.progress.carried_forward = scheduled - amount;
+            self.save_progress();
+        }
+        if amount <= Decimal::ZERO {
+            info!("{}: Nothing to trade this iteration", self.name());
+            return;
+        }
+
+        let size = match self.target {
+            CashoutTarget::Base => OrderSize::Base(amount),
+            CashoutTarget::Quote => OrderSize::Quote(amount),
+        };
+
+        let allocations = match self.allocation {
+            CashoutAllocation::BestExchange => self.best_exchange(size).map(|a| vec![a]),
+            CashoutAllocation::Split => split_across_exchanges(
+                &self.books_per_exchange,
+                self.direction,
+                size,
+                self.price_limit,
+            ),
+        };
+
+        if let Some(allocations) = allocations {
+            for allocation in allocations {
+                self.next_order_id += 1;
+                let order = PendingMarketOrder {
+                    id: self.next_order_id,
+                    strategy: self.strategy(),
+                    exchange: allocation.exchange,
+                    direction: self.direction,
+                    amount: allocation.amount,
+                    expected_return: allocation.expected_return,
+                    trace: LatencyTrace::default(),
+                };
+                info!("{}: {order:?}", self.name());
+                self.order_sender
+                    .send(Arc::new(OrderRequest::Submit(PendingOrder::Market(order))))
+                    .unwrap();
+
+                self.progress.amount_traded += allocation.amount;
+                self.progress.value_traded += allocation.expected_return;
+                if self.tracks_fills {
+                    self.taken_orders.insert(
+                        self.next_order_id,
+                        TakenOrder {
+                            day: self.progress.day,
+                            amount: allocation.amount,
+                            value: allocation.expected_return,
+                        },
+                    );
                 }
             }
+            self.save_progress();
+            info!(
+                "{}: Traded today: {}. Value traded today: {}. Remaining today: {}",
+                self.name(),
+                self.progress.amount_traded,
+                self.progress.value_traded,
+                self.remaining_today()
+            );
+            self.check_daily_target(remaining_today);
+        } else {
+            info!("{}: No liquidity available within price limit", self.name());
+        }
+    }
+
+    // How far the amount traded today is behind a straight line from zero at midnight to the daily
+    // amount at the end of the day
+    fn shortfall(&self) -> Decimal {
+        let now = now_ms();
+        let expected = self.amount_per_day * fraction_of_day_elapsed(now);
+        let completed = self.amount_per_day - self.remaining_today();
+        expected - completed
+    }
 
-            let amount = amount.min(total_remaining);
-            total_return += amount * price;
-            total_remaining -= amount;
+    fn apply_parameters(&mut self, parameters: CashoutParameters) {
+        info!("{}: Applying parameters: {parameters:?}", self.name());
+        self.amount_per_day = parameters.amount_per_day;
+        self.price_limit = parameters.price_limit;
+        self.schedule
+            .update_amounts(parameters.amount_per_day, parameters.amount_per_iteration);
+    }
+
+    fn is_trading(&mut self) -> bool {
+        let now = now_ms();
+        if let Some(is_open) = self.calendar.transition(now) {
+            if is_open {
+                info!("{}: Trading window opened, resuming", self.name());
+            } else {
+                info!("{}: Trading window closed, pausing", self.name());
+            }
+            TRADING_WINDOW_OPEN
+                .with_label_values(&[self.strategy().as_str()])
+                .set(if is_open { 1.0 } else { 0.0 });
+        }
 
-            if total_remaining == Decimal::ZERO {
-                return Some(total_return);
+        // Pull the ladder whenever trading stops, whether the window closed or we were paused
+        let is_trading = self.calendar.is_open(now) && !self.pause.is_paused();
+        if self.was_trading && !is_trading {
+            if let Some(ladder) = &mut self.ladder {
+                let actions = ladder.cancel_all();
+                self.send_ladder_actions(actions, LatencyTrace::default());
             }
         }
+        self.was_trading = is_trading;
+        is_trading
+    }
+
+    fn refresh_ladder(&mut self, state: &OrderbookState, mut trace: LatencyTrace) {
+        let remaining_today = self.remaining_today();
+        let Some(ladder) = &mut self.ladder else {
+            return;
+        };
+
+        // The ladder rests on our own side of the book, the asks when selling or the bids when
+        // buying
+        let book = if self.direction.is_buy() {
+            &state.bids
+        } else {
+            &state.asks
+        };
+        let Some(reference_price) = ladder.reference_price(state.exchange, book, self.direction)
+        else {
+            return;
+        };
+
+        let available = (remaining_today - ladder.resting(self.target)).max(Decimal::ZERO);
+        let budget = match self.target {
+            CashoutTarget::Base => OrderSize::Base(available),
+            CashoutTarget::Quote => OrderSize::Quote(available),
+        };
+
+        let actions = ladder.refresh(
+            state.exchange,
+            reference_price,
+            self.direction,
+            self.price_limit,
+            budget,
+            Instant::now(),
+        );
+
+        if !actions.is_empty() {
+            trace.mark(Stage::Detect);
+            self.send_ladder_actions(actions, trace);
+        }
+    }
+
+    // The trace is carried by any new orders, moved orders are resubmitted with a fresh trace by
+    // the order executor
+    fn send_ladder_actions(&mut self, actions: Vec<LadderAction>, trace: LatencyTrace) {
+        for action in actions {
+            let request = match action {
+                LadderAction::Place {
+                    exchange,
+                    level,
+                    price,
+                    amount,
+                } => {
+                    self.next_order_id += 1;
+                    if let Some(ladder) = &mut self.ladder {
+                        ladder.insert(
+                            exchange,
+                            level,
+                            LadderOrder {
+                                id: self.next_order_id,
+                                price,
+                                remaining: amount,
+                                cancelling: false,
+                            },
+                        );
+                    }
+                    // Where post-only isn't supported, such as on Bitrue, the ladder relies on
+                    // resting on our own side of the book to avoid crossing the spread
+                    OrderRequest::Submit(PendingOrder::Limit(PendingLimitOrder {
+                        id: self.next_order_id,
+                        strategy: self.strategy(),
+                        exchange,
+                        direction: self.direction,
+                        amount,
+                        price,
+                        time_in_force: TimeInForce::GoodTillCancelled,
+                        post_only: self.post_only_exchanges.contains(&exchange),
+                        trace,
+                    }))
+                }
+                LadderAction::Move { id, price, amount } => OrderRequest::Replace(ReplaceOrder {
+                    strategy: self.strategy(),
+                    id,
+                    amount,
+                    price,
+                }),
+                LadderAction::Cancel { id } => OrderRequest::Cancel(CancelOrder {
+                    strategy: self.strategy(),
+                    id,
+                }),
+            };
+            trace!("{}: {request:?}", self.name());
+            // Sending only fails once the order executor has shut down, by which point it has
+            // cancelled the whole ladder itself
+            let _ = self.order_sender.send(Arc::new(request));
+        }
+    }
+
+    fn process_order_update(&mut self, update: &OrderUpdate) {
+        if update.strategy != self.strategy() {
+            return;
+        }
+        if let Some(taken) = self.taken_orders.remove(&update.id) {
+            self.settle_taken_order(taken, update);
+            return;
+        }
+        let Some(ladder) = &mut self.ladder else {
+            return;
+        };
+        if !ladder.apply_update(update) || update.filled_amount.is_zero() {
+            return;
+        }
+
+        let remaining_today = self.remaining_today();
+        self.progress.roll_over(now_ms());
+        self.progress.amount_traded += update.filled_amount;
+        self.progress.value_traded += update.filled_value;
+        self.save_progress();
+        info!(
+            "{}: Passive fill of {} on {:?}. Traded today: {}. Value traded today: {}",
+            self.name(),
+            update.filled_amount,
+            update.exchange,
+            self.progress.amount_traded,
+            self.progress.value_traded,
+        );
+        self.check_daily_target(remaining_today);
+    }
+
+    // Replaces the expected fill booked when the order was sent with the actual one, unless the day
+    // has rolled over since, in which case the actual fill counts towards the new day. When the
+    // order executor couldn't fetch the fills the expected fill stands, so the daily cap still holds.
+    fn settle_taken_order(&mut self, taken: TakenOrder, update: &OrderUpdate) {
+        if update.fills_unknown {
+            warn!(
+                "{}: Fills of order {} unknown, keeping its expected fill of {}",
+                self.name(),
+                update.id,
+                taken.amount
+            );
+            return;
+        }
+        let remaining_today = self.remaining_today();
+        self.progress.roll_over(now_ms());
+        if self.progress.day == taken.day {
+            self.progress.amount_traded += update.filled_amount - taken.amount;
+            self.progress.value_traded += update.filled_value - taken.value;
+        } else {
+            self.progress.amount_traded += update.filled_amount;
+            self.progress.value_traded += update.filled_value;
+        }
+        self.save_progress();
+        info!(
+            "{}: Filled {} of {} expected on {:?}. Traded today: {}. Value traded today: {}",
+            self.name(),
+            update.filled_amount,
+            taken.amount,
+            update.exchange,
+            self.progress.amount_traded,
+            self.progress.value_traded,
+        );
+        self.check_daily_target(remaining_today);
+    }
+
+    // Alerts when the latest fills used up what was left of today's target
+    fn check_daily_target(&self, remaining_before: Decimal) {
+        if remaining_before.is_zero() || !self.remaining_today().is_zero() {
+            return;
+        }
+        info!("{}: Daily target reached", self.name());
+        if let Some(sender) = &self.alerts {
+            // Sending only fails if there are no subscribers, in which case there is nothing to do
+            let _ = sender.send(Arc::new(Alert::DailyTargetReached {
+                strategy: self.strategy(),
+                amount: self.amount_per_day,
+            }));
+        }
+    }
+
+    fn schedule_next_iteration(&mut self) -> Instant {
+        let interval = self.schedule.next_interval();
+        trace!("{}: next interval: {interval:?}", self.name());
+        self.progress.next_iteration_timestamp_ms = Some(now_ms() + interval.as_millis() as u64);
+        self.save_progress();
+        Instant::now() + interval
+    }
+
+    fn save_progress(&self) {
+        if let Some(path) = &self.state_path {
+            self.progress.save(path);
+        }
+        self.record_progress();
+    }
 
-        None
+    fn record_progress(&self) {
+        let strategy = self.strategy().as_str();
+        CASHOUT_TRADED_TODAY
+            .with_label_values(&[strategy])
+            .set(self.completed_today().to_f64().unwrap_or_default());
+        CASHOUT_REMAINING_TODAY
+            .with_label_values(&[strategy])
+            .set(self.remaining_today().to_f64().unwrap_or_default());
+    }
+
+    // Picks the exchange offering the best average price for the given size
+    fn best_exchange(&self, size: OrderSize) -> Option<Allocation> {
+        let allocations = self.books_per_exchange.iter().filter_map(|(e, b)| {
+            calculate_fill(b, self.direction, size, self.price_limit).map(
+                |(amount, expected_return)| Allocation {
+                    exchange: *e,
+                    amount,
+                    expected_return,
+                },
+            )
+        });
+
+        if self.direction.is_buy() {
+            allocations.min_by_key(|a| a.expected_return / a.amount)
+        } else {
+            allocations.max_by_key(|a| a.expected_return / a.amount)
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        if self.direction.is_buy() {
+            "Accumulate"
+        } else {
+            "Cashout"
+        }
+    }
+
+    fn strategy(&self) -> Strategy {
+        if self.direction.is_buy() {
+            Strategy::Accumulate
+        } else {
+            Strategy::Cashout
+        }
+    }
+}
+
+// A market order executes against the book starting from the best price, ie. a sell starts from
+// the highest bid and works down, a buy starts from the lowest ask and works up.
+// Returns the amount to trade along with the expected return (or cost when buying).
+fn calculate_fill(
+    book: &BTreeMap<Decimal, Decimal>,
+    direction: Direction,
+    mut size: OrderSize,
+    price_limit: Option<Decimal>,
+) -> Option<(Decimal, Decimal)> {
+    let mut total_amount = Decimal::ZERO;
+    let mut total_return = Decimal::ZERO;
+
+    for (&price, &available) in levels_from_best(book, direction) {
+        if !within_limit(price, price_limit, direction) {
+            break;
+        }
+
+        let amount = size.take(price, available);
+        total_amount += amount;
+        total_return += amount * price;
+
+        if size.is_filled() {
+            return Some((total_amount, total_return));
+        }
+    }
+
+    None
+}
+
+async fn recv_order_update(
+    receiver: &mut Option<Receiver<Arc<OrderUpdate>>>,
+) -> Result<Arc<OrderUpdate>, RecvError> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
     }
 }
 
@@ -139,3 +776,311 @@ impl OrderbookStateProcessor for Cashout {
         tokio::spawn(self.run_async(updates, cancellation_token))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use test_case::test_case;
+    use tokio::sync::broadcast::channel;
+
+    const BITRUE: Exchange = Exchange::new("bitrue");
+    const LBANK: Exchange = Exchange::new("lbank");
+
+    // Snapshot of the top 10 bid levels of the LBank CHAT/USDT book
+    const LBANK_BIDS: [(&str, &str); 10] = [
+        ("0.3165", "1523.41"),
+        ("0.3164", "847.20"),
+        ("0.3161", "2934.77"),
+        ("0.3158", "412.05"),
+        ("0.3152", "6120.00"),
+        ("0.3150", "1800.33"),
+        ("0.3144", "975.12"),
+        ("0.3139", "3320.90"),
+        ("0.3131", "508.44"),
+        ("0.3120", "12044.18"),
+    ];
+
+    // Snapshot of the top 5 ask levels of the LBank CHAT/USDT book
+    const LBANK_ASKS: [(&str, &str); 5] = [
+        ("0.3176", "2000.00"),
+        ("0.3177", "500.00"),
+        ("0.3179", "1250.50"),
+        ("0.3185", "3400.00"),
+        ("0.3192", "900.10"),
+    ];
+
+    // Snapshot of the top 10 bid levels of the Bitrue CHAT/USDT book
+    const BITRUE_BIDS: [(&str, &str); 10] = [
+        ("0.3171", "120.50"),
+        ("0.3166", "310.00"),
+        ("0.3159", "2250.75"),
+        ("0.3155", "730.10"),
+        ("0.3148", "4100.00"),
+        ("0.3142", "95.60"),
+        ("0.3137", "1640.25"),
+        ("0.3130", "2875.00"),
+        ("0.3124", "660.80"),
+        ("0.3110", "8200.00"),
+    ];
+
+    #[test_case(LBANK_BIDS, "1000", None, Some("316.5"); "filled by best bid")]
+    #[test_case(LBANK_BIDS, "3000", None, Some("949.163524"); "walks down multiple levels")]
+    #[test_case(LBANK_BIDS, "3000", Some("0.3162"), None; "stops at min price")]
+    #[test_case(LBANK_BIDS, "2370.61", Some("0.3164"), Some("750.213345"); "min price inclusive")]
+    #[test_case(BITRUE_BIDS, "100000", None, None; "insufficient depth")]
+    fn calculate_fill_tests(
+        bids: [(&str, &str); 10],
+        amount: &str,
+        min_price: Option<&str>,
+        expected: Option<&str>,
+    ) {
+        let result = calculate_fill(
+            &to_book(&bids),
+            Direction::Sell,
+            OrderSize::Base(Decimal::from_str(amount).unwrap()),
+            min_price.map(|p| Decimal::from_str(p).unwrap()),
+        );
+
+        assert_eq!(
+            result.map(|(_, r)| r),
+            expected.map(|e| Decimal::from_str(e).unwrap())
+        );
+    }
+
+    #[test_case("1000", None, Some("317.6"); "buy filled by best ask")]
+    #[test_case("3000", None, Some("953"); "buy walks up multiple levels")]
+    #[test_case("3000", Some("0.3178"), None; "buy stops at max price")]
+    fn calculate_fill_buy_tests(amount: &str, max_price: Option<&str>, expected: Option<&str>) {
+        let result = calculate_fill(
+            &to_book(&LBANK_ASKS),
+            Direction::Buy,
+            OrderSize::Base(Decimal::from_str(amount).unwrap()),
+            max_price.map(|p| Decimal::from_str(p).unwrap()),
+        );
+
+        assert_eq!(
+            result.map(|(_, r)| r),
+            expected.map(|e| Decimal::from_str(e).unwrap())
+        );
+    }
+
+    #[test_case(LBANK_BIDS, "100", None, Some(("315.95576620", "100.000000002300")); "quote filled by best bid")]
+    #[test_case(LBANK_BIDS, "600", None, Some(("1895.85227244", "600.000000000016")); "quote walks down multiple levels")]
+    #[test_case(LBANK_BIDS, "1000", Some("0.3164"), None; "quote stops at min price")]
+    fn calculate_fill_quote_tests(
+        bids: [(&str, &str); 10],
+        target: &str,
+        min_price: Option<&str>,
+        expected: Option<(&str, &str)>,
+    ) {
+        let result = calculate_fill(
+            &to_book(&bids),
+            Direction::Sell,
+            OrderSize::Quote(Decimal::from_str(target).unwrap()),
+            min_price.map(|p| Decimal::from_str(p).unwrap()),
+        );
+
+        assert_eq!(
+            result,
+            expected.map(|(a, r)| (Decimal::from_str(a).unwrap(), Decimal::from_str(r).unwrap()))
+        );
+    }
+
+    #[test_case("100", None, Some(BITRUE); "small amount prefers highest best bid")]
+    #[test_case("2000", None, Some(LBANK); "large amount prefers deepest book")]
+    #[test_case("2000", Some("0.3160"), Some(LBANK); "min price excludes thin book")]
+    #[test_case("2000", Some("0.3170"), None; "min price excludes both books")]
+    fn best_exchange_tests(amount: &str, min_price: Option<&str>, expected: Option<Exchange>) {
+        let amount = Decimal::from_str(amount).unwrap();
+        let (sender, _) = channel(1);
+        let mut cashout = Cashout::new(
+            amount * Decimal::from(100),
+            amount,
+            min_price.map(|p| Decimal::from_str(p).unwrap()),
+            sender,
+        );
+        cashout
+            .books_per_exchange
+            .insert(LBANK, to_book(&LBANK_BIDS));
+        cashout
+            .books_per_exchange
+            .insert(BITRUE, to_book(&BITRUE_BIDS));
+
+        assert_eq!(
+            cashout
+                .best_exchange(OrderSize::Base(amount))
+                .map(|a| a.exchange),
+            expected
+        );
+    }
+
+    #[test_case("100", None, &[(BITRUE, "100", "31.71")]; "small amount uses single exchange")]
+    #[test_case("1000", None, &[(BITRUE, "430.5", "136.35655"), (LBANK, "569.5", "180.24675")]; "splits across exchanges")]
+    #[test_case("3000", Some("0.3160"), &[(BITRUE, "430.5", "136.35655"), (LBANK, "2569.5", "813.082474")]; "respects min price")]
+    fn split_across_exchanges_tests(
+        amount: &str,
+        min_price: Option<&str>,
+        expected: &[(Exchange, &str, &str)],
+    ) {
+        let bids_per_exchange = HashMap::from([
+            (LBANK, to_book(&LBANK_BIDS)),
+            (BITRUE, to_book(&BITRUE_BIDS)),
+        ]);
+
+        let result = split_across_exchanges(
+            &bids_per_exchange,
+            Direction::Sell,
+            OrderSize::Base(Decimal::from_str(amount).unwrap()),
+            min_price.map(|p| Decimal::from_str(p).unwrap()),
+        );
+
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(e, a, r)| Allocation {
+                exchange: *e,
+                amount: Decimal::from_str(a).unwrap(),
+                expected_return: Decimal::from_str(r).unwrap(),
+            })
+            .collect();
+
+        assert_eq!(result, Some(expected));
+    }
+
+    #[test]
+    fn books_actual_fills_of_taken_orders() {
+        let (sender, mut orders) = channel(16);
+        let (_, order_updates) = channel(1);
+        let mut cashout = Cashout::new(Decimal::from(1000), Decimal::from(100), None, sender)
+            .with_order_updates(order_updates);
+        cashout
+            .books_per_exchange
+            .insert(LBANK, to_book(&LBANK_BIDS));
+        // As when running, the receiver is no longer part of the strategy
+        cashout.order_updates.take();
+
+        cashout.run_iteration();
+        let OrderRequest::Submit(PendingOrder::Market(order)) = &*orders.try_recv().unwrap() else {
+            panic!("Expected a market order");
+        };
+        assert_eq!(order.amount, Decimal::from(100));
+        assert_eq!(cashout.progress.amount_traded, Decimal::from(100));
+        assert_eq!(cashout.progress.value_traded, order.expected_return);
+
+        cashout.process_order_update(&OrderUpdate {
+            strategy: Strategy::Cashout,
+            id: order.id,
+            exchange: LBANK,
+            direction: Direction::Sell,
+            filled_amount: Decimal::from(60),
+            filled_value: Decimal::from_str("19.5").unwrap(),
+            is_open: false,
+            fills_unknown: false,
+        });
+
+        assert_eq!(cashout.progress.amount_traded, Decimal::from(60));
+        assert_eq!(
+            cashout.progress.value_traded,
+            Decimal::from_str("19.5").unwrap()
+        );
+        assert!(cashout.taken_orders.is_empty());
+        assert_eq!(cashout.remaining_today(), Decimal::from(940));
+    }
+
+    #[test]
+    fn keeps_expected_fill_when_fills_unknown() {
+        let (sender, mut orders) = channel(16);
+        let (_, order_updates) = channel(1);
+        let mut cashout = Cashout::new(Decimal::from(1000), Decimal::from(100), None, sender)
+            .with_order_updates(order_updates);
+        cashout
+            .books_per_exchange
+            .insert(LBANK, to_book(&LBANK_BIDS));
+
+        cashout.run_iteration();
+        let OrderRequest::Submit(order) = &*orders.try_recv().unwrap() else {
+            panic!("Expected an order");
+        };
+        cashout.process_order_update(&OrderUpdate {
+            strategy: Strategy::Cashout,
+            id: order.id(),
+            exchange: LBANK,
+            direction: Direction::Sell,
+            filled_amount: Decimal::ZERO,
+            filled_value: Decimal::ZERO,
+            is_open: false,
+            fills_unknown: true,
+        });
+
+        assert_eq!(cashout.progress.amount_traded, Decimal::from(100));
+        assert!(cashout.taken_orders.is_empty());
+    }
+
+    #[test]
+    fn reports_trading_window() {
+        let (sender, _) = channel(1);
+        let mut cashout = Cashout::new(Decimal::from(1000), Decimal::from(100), None, sender)
+            .with_direction(Direction::Buy)
+            .with_calendar(
+                TradingCalendar::new()
+                    .with_exclusion("2000-01-01T00:00/2100-01-01T00:00".parse().unwrap()),
+            );
+        let window_open = TRADING_WINDOW_OPEN.with_label_values(&[Strategy::Accumulate.as_str()]);
+        window_open.set(1.0);
+
+        assert!(!cashout.is_trading());
+        assert_eq!(window_open.get(), 0.0);
+    }
+
+    #[test]
+    fn consumes_carried_forward_without_guards() {
+        let (sender, mut orders) = channel(16);
+        let mut cashout = Cashout::new(Decimal::from(1000), Decimal::from(100), None, sender);
+        cashout
+            .books_per_exchange
+            .insert(LBANK, to_book(&LBANK_BIDS));
+        cashout.progress.roll_over(now_ms());
+        // Held back by the guards of an earlier run
+        cashout.progress.carried_forward = Decimal::from(50);
+
+        for expected in [150, 100] {
+            cashout.run_iteration();
+            let OrderRequest::Submit(order) = &*orders.try_recv().unwrap() else {
+                panic!("Expected an order");
+            };
+            assert_eq!(order.amount(), Decimal::from(expected));
+            assert_eq!(cashout.progress.carried_forward, Decimal::ZERO);
+        }
+    }
+
+    #[test]
+    fn places_ladder_orders_post_only_where_supported() {
+        let (sender, mut orders) = channel(16);
+        let mut cashout = Cashout::new(Decimal::from(1000), Decimal::from(100), None, sender)
+            .with_post_only_exchanges(HashSet::from([LBANK]));
+        let place = |exchange| LadderAction::Place {
+            exchange,
+            level: 0,
+            price: Decimal::from_str("0.3176").unwrap(),
+            amount: Decimal::from(100),
+        };
+
+        cashout.send_ladder_actions(vec![place(LBANK), place(BITRUE)], LatencyTrace::default());
+
+        for (exchange, post_only) in [(LBANK, true), (BITRUE, false)] {
+            let OrderRequest::Submit(PendingOrder::Limit(order)) = &*orders.try_recv().unwrap()
+            else {
+                panic!("Expected a limit order");
+            };
+            assert_eq!(order.exchange, exchange);
+            assert_eq!(order.post_only, post_only);
+        }
+    }
+
+    fn to_book(levels: &[(&str, &str)]) -> BTreeMap<Decimal, Decimal> {
+        levels
+            .iter()
+            .map(|(p, a)| (Decimal::from_str(p).unwrap(), Decimal::from_str(a).unwrap()))
+            .collect()
+    }
+}
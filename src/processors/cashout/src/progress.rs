@@ -0,0 +1,127 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+const ONE_DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CashoutProgress {
+    // Days since the unix epoch, so each value covers exactly one UTC day
+    pub day: u64,
+    #[serde(alias = "amount_sold")]
+    pub amount_traded: Decimal,
+    // The value of the amount traded in the quote currency
+    #[serde(alias = "proceeds")]
+    pub value_traded: Decimal,
+    pub next_iteration_timestamp_ms: Option<u64>,
+    // The part of previous iterations held back by the market guards, which is added to the next
+    // iteration until the end of the day
+    #[serde(default)]
+    pub carried_forward: Decimal,
+}
+
+impl CashoutProgress {
+    // A missing file means starting from scratch. One which can't be read is an error rather than
+    // a fresh start, as that would reset the daily cap.
+    pub fn load(path: &Path) -> Result<Option<CashoutProgress>, String> {
+        let json = match fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(format!("Failed to read progress file {path:?}: {error}")),
+        };
+        serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|e| format!("Invalid progress file {path:?}, fix or remove it: {e}"))
+    }
+
+    pub fn save(&self, path: &Path) {
+        // Write to a temporary file first so that a crash mid-write can't corrupt the progress
+        let temp_path = path.with_extension("tmp");
+        let json = serde_json::to_string_pretty(self).unwrap();
+        if let Err(error) = fs::write(&temp_path, json).and_then(|_| fs::rename(&temp_path, path)) {
+            error!("Failed to save progress file {path:?}: {error}");
+        }
+    }
+
+    // Resets the daily totals if the UTC day has changed since they were last updated
+    pub fn roll_over(&mut self, now_ms: u64) {
+        let today = now_ms / ONE_DAY_MS;
+        if self.day != today {
+            self.day = today;
+            self.amount_traded = Decimal::ZERO;
+            self.value_traded = Decimal::ZERO;
+            self.carried_forward = Decimal::ZERO;
+        }
+    }
+}
+
+// The fraction of the current UTC day which has passed
+pub fn fraction_of_day_elapsed(now_ms: u64) -> Decimal {
+    Decimal::from(now_ms % ONE_DAY_MS) / Decimal::from(ONE_DAY_MS)
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    // 2024-08-15 12:00 UTC
+    const NOW_MS: u64 = 1_723_723_200_000;
+
+    #[test_case(NOW_MS / ONE_DAY_MS, "100"; "same day keeps totals")]
+    #[test_case(NOW_MS / ONE_DAY_MS - 1, "0"; "new day resets totals")]
+    fn roll_over_tests(day: u64, expected: &str) {
+        let mut progress = CashoutProgress {
+            day,
+            amount_traded: Decimal::from(100),
+            value_traded: Decimal::from(100),
+            carried_forward: Decimal::from(100),
+            ..Default::default()
+        };
+
+        progress.roll_over(NOW_MS);
+
+        let expected: Decimal = expected.parse().unwrap();
+        assert_eq!(progress.day, NOW_MS / ONE_DAY_MS);
+        assert_eq!(progress.amount_traded, expected);
+        assert_eq!(progress.value_traded, expected);
+        assert_eq!(progress.carried_forward, expected);
+    }
+
+    #[test]
+    fn saved_progress_loads_back() {
+        let path = std::env::temp_dir().join(format!("xb_progress_{}.json", std::process::id()));
+        assert_eq!(CashoutProgress::load(&path).map(|p| p.is_none()), Ok(true));
+
+        let progress = CashoutProgress {
+            day: NOW_MS / ONE_DAY_MS,
+            amount_traded: Decimal::from(250),
+            next_iteration_timestamp_ms: Some(NOW_MS),
+            ..Default::default()
+        };
+        progress.save(&path);
+        let loaded = CashoutProgress::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.day, progress.day);
+        assert_eq!(loaded.amount_traded, progress.amount_traded);
+        assert_eq!(
+            loaded.next_iteration_timestamp_ms,
+            progress.next_iteration_timestamp_ms
+        );
+
+        fs::write(&path, "{\"day\": ").unwrap();
+        let error = CashoutProgress::load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(error.starts_with("Invalid progress file"));
+    }
+}
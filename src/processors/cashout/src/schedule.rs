@@ -0,0 +1,407 @@
+use crate::progress::now_ms;
+use crate::CashoutTarget;
+use rand::random;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::time::Duration;
+use xb_types::{Exchange, OrderbookState};
+
+const ONE_HOUR: Duration = Duration::from_secs(60 * 60);
+const ONE_DAY: Duration = Duration::from_secs(24 * 60 * 60);
+// Iterations too small for the amount per window are run this often instead of back to back
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+pub trait CashoutSchedule: Debug + Send {
+    // Called on every orderbook update, allowing schedules to react to market activity
+    fn on_orderbook_update(&mut self, _state: &OrderbookState) {}
+
+    // The time to wait before the next iteration
+    fn next_interval(&mut self) -> Duration;
+
+    // The amount to trade in the iteration which is now due, selling or buying depending on the
+    // strategy, in the units of its target
+    fn next_amount(&mut self) -> Decimal;
+
+    // Applies new amounts when the parameters are reloaded, taking effect from the next iteration
+    fn update_amounts(&mut self, amount_per_day: Decimal, amount_per_iteration: Decimal);
+}
+
+// Iterations of a fixed size at random intervals which follow a Poisson distribution
+// (https://en.wikipedia.org/wiki/Poisson_distribution), where on average the desired amount will be
+// cashed out per day
+#[derive(Debug)]
+pub struct PoissonSchedule {
+    average_interval: Duration,
+    amount_per_iteration: Decimal,
+}
+
+impl PoissonSchedule {
+    pub fn new(amount_per_day: Decimal, amount_per_iteration: Decimal) -> PoissonSchedule {
+        PoissonSchedule {
+            average_interval: interval_for(ONE_DAY, amount_per_day, amount_per_iteration),
+            amount_per_iteration,
+        }
+    }
+}
+
+impl CashoutSchedule for PoissonSchedule {
+    fn next_interval(&mut self) -> Duration {
+        let rand = -random::<f64>().ln();
+        Duration::from_millis((rand * self.average_interval.as_millis() as f64) as u64)
+    }
+
+    fn next_amount(&mut self) -> Decimal {
+        self.amount_per_iteration
+    }
+
+    fn update_amounts(&mut self, amount_per_day: Decimal, amount_per_iteration: Decimal) {
+        *self = PoissonSchedule::new(amount_per_day, amount_per_iteration);
+    }
+}
+
+// Sells a target amount evenly over a daily window starting at a fixed time of day (UTC), in
+// slices of a fixed size at fixed intervals, waiting outside the window for the next one to start
+#[derive(Debug)]
+pub struct TwapSchedule {
+    start: Duration,
+    duration: Duration,
+    amount_per_window: Decimal,
+    interval: Duration,
+    amount_per_iteration: Decimal,
+}
+
+impl TwapSchedule {
+    pub fn new(
+        start: Duration,
+        duration: Duration,
+        amount_per_window: Decimal,
+        amount_per_iteration: Decimal,
+    ) -> TwapSchedule {
+        TwapSchedule {
+            start,
+            duration,
+            amount_per_window,
+            interval: interval_for(duration, amount_per_window, amount_per_iteration),
+            amount_per_iteration,
+        }
+    }
+
+    // How far into the current window, or since the last one started
+    fn elapsed(&self, now_ms: u64) -> Duration {
+        let day_ms = ONE_DAY.as_millis() as u64;
+        let start_ms = self.start.as_millis() as u64;
+        Duration::from_millis((now_ms % day_ms + day_ms - start_ms) % day_ms)
+    }
+
+    fn interval_at(&self, now_ms: u64) -> Duration {
+        let elapsed = self.elapsed(now_ms);
+        if elapsed + self.interval < self.duration {
+            self.interval
+        } else {
+            ONE_DAY - elapsed
+        }
+    }
+
+    fn amount_at(&self, now_ms: u64) -> Decimal {
+        if self.elapsed(now_ms) < self.duration {
+            self.amount_per_iteration
+        } else {
+            Decimal::ZERO
+        }
+    }
+}
+
+impl CashoutSchedule for TwapSchedule {
+    fn next_interval(&mut self) -> Duration {
+        self.interval_at(now_ms())
+    }
+
+    fn next_amount(&mut self) -> Decimal {
+        self.amount_at(now_ms())
+    }
+
+    // The target for the window is part of the schedule, so only the slice size is reloaded
+    fn update_amounts(&mut self, _amount_per_day: Decimal, amount_per_iteration: Decimal) {
+        *self = TwapSchedule::new(
+            self.start,
+            self.duration,
+            self.amount_per_window,
+            amount_per_iteration,
+        );
+    }
+}
+
+// Sells at fixed intervals, sizing each iteration according to the share of the daily volume which
+// historically trades during the current UTC hour
+#[derive(Debug)]
+pub struct VwapSchedule {
+    interval: Duration,
+    amount_per_day: Decimal,
+    hourly_weights: Vec<Decimal>,
+}
+
+impl VwapSchedule {
+    // The volume profile must contain 24 values, one per UTC hour, which are normalised so that
+    // their total is 1
+    pub fn new(
+        amount_per_day: Decimal,
+        amount_per_iteration: Decimal,
+        volume_profile: Vec<Decimal>,
+    ) -> Result<VwapSchedule, String> {
+        if volume_profile.len() != 24 {
+            return Err(format!(
+                "VWAP volume profile must have 24 hourly values, found {}",
+                volume_profile.len()
+            ));
+        }
+        let total: Decimal = volume_profile.iter().sum();
+        if total <= Decimal::ZERO || volume_profile.iter().any(|v| v.is_sign_negative()) {
+            return Err("VWAP volume profile values must be positive".to_string());
+        }
+
+        Ok(VwapSchedule {
+            interval: interval_for(ONE_DAY, amount_per_day, amount_per_iteration),
+            amount_per_day,
+            hourly_weights: volume_profile.into_iter().map(|v| v / total).collect(),
+        })
+    }
+}
+
+impl CashoutSchedule for VwapSchedule {
+    fn next_interval(&mut self) -> Duration {
+        self.interval
+    }
+
+    fn next_amount(&mut self) -> Decimal {
+        let hour = (now_ms() / ONE_HOUR.as_millis() as u64 % 24) as usize;
+        let iterations_per_hour =
+            Decimal::from(ONE_HOUR.as_millis()) / Decimal::from(self.interval.as_millis());
+
+        self.amount_per_day * self.hourly_weights[hour] / iterations_per_hour
+    }
+
+    fn update_amounts(&mut self, amount_per_day: Decimal, amount_per_iteration: Decimal) {
+        self.interval = interval_for(ONE_DAY, amount_per_day, amount_per_iteration);
+        self.amount_per_day = amount_per_day;
+    }
+}
+
+// Sells a fixed fraction of the volume observed since the previous iteration.
+// The subscribers only stream orderbook depth, not trades, so volume is estimated from the
+// liquidity which disappears from the top of each book between updates. That also counts cancelled
+// orders, so it is only an approximation of the traded volume.
+// The volume is measured in the target's currency, as that's what the amounts are in.
+#[derive(Debug)]
+pub struct PercentOfVolumeSchedule {
+    interval: Duration,
+    participation: Decimal,
+    max_amount_per_iteration: Decimal,
+    target: CashoutTarget,
+    observed_volume: Decimal,
+    previous_books: HashMap<Exchange, OrderbookState>,
+}
+
+impl PercentOfVolumeSchedule {
+    pub fn new(
+        participation: Decimal,
+        interval: Duration,
+        max_amount_per_iteration: Decimal,
+        target: CashoutTarget,
+    ) -> PercentOfVolumeSchedule {
+        PercentOfVolumeSchedule {
+            interval,
+            participation,
+            max_amount_per_iteration,
+            target,
+            observed_volume: Decimal::ZERO,
+            previous_books: HashMap::new(),
+        }
+    }
+}
+
+impl CashoutSchedule for PercentOfVolumeSchedule {
+    fn on_orderbook_update(&mut self, state: &OrderbookState) {
+        // Changes across a gap in the book weren't necessarily trades
+        if state.invalidated {
+            self.previous_books.remove(&state.exchange);
+            return;
+        }
+        if let Some(previous) = self.previous_books.get(&state.exchange) {
+            self.observed_volume +=
+                consumed_liquidity(previous.bids.iter().rev(), &state.bids, self.target)
+                    + consumed_liquidity(previous.asks.iter(), &state.asks, self.target);
+        }
+        self.previous_books.insert(state.exchange, state.clone());
+    }
+
+    fn next_interval(&mut self) -> Duration {
+        self.interval
+    }
+
+    fn next_amount(&mut self) -> Decimal {
+        let amount = (self.observed_volume * self.participation).min(self.max_amount_per_iteration);
+        self.observed_volume = Decimal::ZERO;
+        amount
+    }
+
+    fn update_amounts(&mut self, _amount_per_day: Decimal, amount_per_iteration: Decimal) {
+        self.max_amount_per_iteration = amount_per_iteration;
+    }
+}
+
+// Walks the previous levels from the top of the book, summing the amounts which are no longer
+// present, stopping at the first level which is unchanged or has grown
+fn consumed_liquidity<'a>(
+    previous: impl Iterator<Item = (&'a Decimal, &'a Decimal)>,
+    current: &BTreeMap<Decimal, Decimal>,
+    target: CashoutTarget,
+) -> Decimal {
+    let mut consumed = Decimal::ZERO;
+    for (price, amount) in previous {
+        let remaining = current.get(price).copied().unwrap_or_default();
+        if remaining >= *amount {
+            break;
+        }
+        consumed += match target {
+            CashoutTarget::Base => amount - remaining,
+            CashoutTarget::Quote => (amount - remaining) * price,
+        };
+    }
+    consumed
+}
+
+// With nothing to trade in the window there's at most one iteration per window
+fn interval_for(
+    window: Duration,
+    amount_per_window: Decimal,
+    amount_per_iteration: Decimal,
+) -> Duration {
+    if amount_per_window <= Decimal::ZERO || amount_per_iteration <= Decimal::ZERO {
+        return window;
+    }
+    let millis = Decimal::from(window.as_millis()) * amount_per_iteration / amount_per_window;
+    Duration::from_millis(millis.to_u64().unwrap_or(u64::MAX)).max(MIN_INTERVAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use test_case::test_case;
+    use xb_types::TimeOfDay;
+
+    #[test_case(&[("0.3165", "100"), ("0.3164", "200")], &[("0.3165", "100"), ("0.3164", "200")], CashoutTarget::Base, "0"; "unchanged")]
+    #[test_case(&[("0.3165", "100"), ("0.3164", "200")], &[("0.3165", "40"), ("0.3164", "200")], CashoutTarget::Base, "60"; "partially consumed")]
+    #[test_case(&[("0.3165", "100"), ("0.3164", "200")], &[("0.3164", "150")], CashoutTarget::Base, "150"; "level swept")]
+    #[test_case(&[("0.3165", "100"), ("0.3164", "200")], &[("0.3166", "500"), ("0.3165", "100"), ("0.3164", "200")], CashoutTarget::Base, "0"; "new level above")]
+    #[test_case(&[("0.3165", "100"), ("0.3164", "200")], &[("0.3164", "150")], CashoutTarget::Quote, "47.47"; "quote value of levels swept")]
+    fn consumed_liquidity_tests(
+        previous: &[(&str, &str)],
+        current: &[(&str, &str)],
+        target: CashoutTarget,
+        expected: &str,
+    ) {
+        let previous = to_book(previous);
+        let current = to_book(current);
+
+        let result = consumed_liquidity(previous.iter().rev(), &current, target);
+
+        assert_eq!(result, Decimal::from_str(expected).unwrap());
+    }
+
+    #[test_case("10000", "100", 864; "evenly spaced")]
+    #[test_case("0", "100", 86400; "nothing to sell")]
+    #[test_case("10000", "0", 86400; "empty iterations")]
+    #[test_case("10000000000", "0.01", 1; "tiny iterations")]
+    fn interval_for_tests(amount_per_window: &str, amount_per_iteration: &str, expected_secs: u64) {
+        let result = interval_for(
+            ONE_DAY,
+            Decimal::from_str(amount_per_window).unwrap(),
+            Decimal::from_str(amount_per_iteration).unwrap(),
+        );
+
+        assert_eq!(result, Duration::from_secs(expected_secs));
+    }
+
+    #[test_case(1, "01:30", 360; "one hour window")]
+    #[test_case(4, "01:30", 1_440; "four hour window")]
+    #[test_case(1, "01:54", 83_160; "waits for the next window after the last slice")]
+    #[test_case(1, "12:00", 46_800; "after the window")]
+    #[test_case(1, "00:30", 1_800; "before the window")]
+    fn twap_interval_tests(hours: u64, now: &str, expected_secs: u64) {
+        // 10 slices of 100 in a window starting at 01:00 UTC
+        let schedule = TwapSchedule::new(
+            ONE_HOUR,
+            ONE_HOUR * hours as u32,
+            Decimal::from(1000),
+            Decimal::from(100),
+        );
+        let now_ms = now
+            .parse::<TimeOfDay>()
+            .unwrap()
+            .since_midnight()
+            .as_millis() as u64;
+
+        assert_eq!(
+            schedule.interval_at(now_ms),
+            Duration::from_secs(expected_secs)
+        );
+    }
+
+    #[test_case("01:00", "100")]
+    #[test_case("01:59", "100")]
+    #[test_case("02:00", "0")]
+    fn twap_amount_tests(now: &str, expected: &str) {
+        let schedule =
+            TwapSchedule::new(ONE_HOUR, ONE_HOUR, Decimal::from(1000), Decimal::from(100));
+        let now_ms = now
+            .parse::<TimeOfDay>()
+            .unwrap()
+            .since_midnight()
+            .as_millis() as u64;
+
+        assert_eq!(
+            schedule.amount_at(now_ms),
+            Decimal::from_str(expected).unwrap()
+        );
+    }
+
+    #[test_case(vec![1; 24], true)]
+    #[test_case(vec![1; 23], false)]
+    #[test_case(vec![0; 24], false)]
+    fn vwap_profile_validation(profile: Vec<u32>, is_valid: bool) {
+        let result = VwapSchedule::new(
+            Decimal::from(10000),
+            Decimal::from(100),
+            profile.into_iter().map(Decimal::from).collect(),
+        );
+
+        assert_eq!(result.is_ok(), is_valid);
+    }
+
+    #[test]
+    fn vwap_runs_tiny_iterations_once_a_second() {
+        let mut schedule = VwapSchedule::new(
+            Decimal::from(10_000_000_000u64),
+            Decimal::new(1, 2),
+            vec![Decimal::ONE; 24],
+        )
+        .unwrap();
+
+        assert_eq!(schedule.next_interval(), MIN_INTERVAL);
+        // Each iteration makes up for running less often than the amounts would need
+        assert_eq!(
+            schedule.next_amount().round_dp(6),
+            (Decimal::from(10_000_000_000u64) / Decimal::from(86_400)).round_dp(6)
+        );
+    }
+
+    fn to_book(levels: &[(&str, &str)]) -> BTreeMap<Decimal, Decimal> {
+        levels
+            .iter()
+            .map(|(p, a)| (Decimal::from_str(p).unwrap(), Decimal::from_str(a).unwrap()))
+            .collect()
+    }
+}
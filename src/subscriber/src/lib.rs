@@ -1,38 +1,46 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
 use tokio::sync::broadcast::{channel, Receiver, Sender};
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
-use xb_exchanges_bitrue::BitrueSubscriber;
-use xb_exchanges_lbank::LBankSubscriber;
-use xb_types::{Exchange, ExchangeSubscriber, OrderbookState};
+use xb_types::metrics::BOOK_AGE;
+use xb_types::{Exchange, ExchangeRegistration, OrderbookState, ReconnectPolicy};
+
+const BOOK_AGE_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Subscriber {
-    exchanges: Vec<Exchange>,
+    exchanges: Vec<ExchangeRegistration>,
+    reconnect_policy: ReconnectPolicy,
 }
 
+// Owns the channel the exchange subscribers publish to, so it outlives any of them being restarted
 pub struct SubscriptionManager {
+    sender: Sender<Arc<OrderbookState>>,
     orderbook_state: Receiver<Arc<OrderbookState>>,
 }
 
 impl Subscriber {
-    pub fn new(exchanges: Vec<Exchange>) -> Subscriber {
-        Subscriber { exchanges }
+    pub fn new(exchanges: Vec<ExchangeRegistration>) -> Subscriber {
+        Subscriber {
+            exchanges,
+            reconnect_policy: ReconnectPolicy::default(),
+        }
+    }
+
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
     }
 
     pub fn run(
         self,
+        subscription_manager: &SubscriptionManager,
         cancellation_token: CancellationToken,
-    ) -> (SubscriptionManager, JoinHandle<()>) {
-        let (sender, receiver) = channel(1024);
-
-        let handle = tokio::spawn(self.run_async(sender, cancellation_token));
-
-        (
-            SubscriptionManager {
-                orderbook_state: receiver,
-            },
-            handle,
-        )
+    ) -> JoinHandle<()> {
+        tokio::spawn(self.run_async(subscription_manager.sender.clone(), cancellation_token))
     }
 
     async fn run_async(
@@ -40,28 +48,77 @@ impl Subscriber {
         sender: Sender<Arc<OrderbookState>>,
         cancellation_token: CancellationToken,
     ) {
-        let mut futures = Vec::new();
-        for exchange in self.exchanges {
-            match exchange {
-                Exchange::Bitrue => {
-                    let bitrue_service = BitrueSubscriber::default();
-                    futures
-                        .push(bitrue_service.run_async(sender.clone(), cancellation_token.clone()));
-                }
-                Exchange::LBank => {
-                    let lbank_service = LBankSubscriber::default();
-                    futures
-                        .push(lbank_service.run_async(sender.clone(), cancellation_token.clone()));
-                }
-            }
+        let book_age = track_book_age(
+            self.exchanges.iter().map(|e| e.exchange).collect(),
+            sender.subscribe(),
+            cancellation_token.clone(),
+        );
+
+        let futures = self.exchanges.iter().map(|e| {
+            (e.subscriber)(
+                self.reconnect_policy,
+                sender.clone(),
+                cancellation_token.clone(),
+            )
+        });
+
+        // Stops tracking as soon as the subscribers stop, as they'll be started afresh if restarted
+        select! {
+            _ = futures::future::join_all(futures) => {}
+            _ = book_age => {}
         }
+    }
+}
 
-        futures::future::join_all(futures).await;
+impl Default for SubscriptionManager {
+    fn default() -> Self {
+        SubscriptionManager::new()
     }
 }
 
 impl SubscriptionManager {
+    pub fn new() -> SubscriptionManager {
+        let (sender, orderbook_state) = channel(1024);
+        SubscriptionManager {
+            sender,
+            orderbook_state,
+        }
+    }
+
     pub fn subscribe_orderbook_state(&self) -> Receiver<Arc<OrderbookState>> {
         self.orderbook_state.resubscribe()
     }
 }
+
+// Keeps the book age metric up to date, so a feed which has gone quiet shows up even though nothing
+// is being received from it
+async fn track_book_age(
+    exchanges: Vec<Exchange>,
+    mut updates: Receiver<Arc<OrderbookState>>,
+    cancellation_token: CancellationToken,
+) {
+    let mut last_update = HashMap::new();
+    let mut interval = tokio::time::interval(BOOK_AGE_INTERVAL);
+
+    loop {
+        select! {
+            next = updates.recv() => {
+                match next {
+                    // The age keeps growing while the book is invalidated
+                    Ok(state) if exchanges.contains(&state.exchange) && !state.invalidated => {
+                        last_update.insert(state.exchange, Instant::now());
+                    }
+                    _ => {}
+                }
+            }
+            _ = interval.tick() => {
+                for (exchange, received) in &last_update {
+                    BOOK_AGE
+                        .with_label_values(&[exchange.as_str()])
+                        .set(received.elapsed().as_secs_f64());
+                }
+            }
+            _ = cancellation_token.cancelled() => break,
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{Direction, Exchange, Strategy};
+use rust_decimal::Decimal;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+// Something an operator should hear about. The components publish the events only they can see,
+// the app's alerting derives the rest from the orderbook and order update channels.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Alert {
+    OrderRejected {
+        exchange: Exchange,
+        strategy: Strategy,
+        reason: String,
+    },
+    // An order blocked by one of the configured risk limits
+    RiskLimit {
+        exchange: Exchange,
+        strategy: Strategy,
+        reason: String,
+    },
+    DailyTargetReached {
+        strategy: Strategy,
+        amount: Decimal,
+    },
+    // Restarted unless it's critical, in which case the service shuts down
+    ComponentFailed {
+        component: &'static str,
+        error: String,
+        critical: bool,
+    },
+    // No orderbook update received for a while, usually because the websocket has disconnected
+    ExchangeStale {
+        exchange: Exchange,
+        age: Duration,
+    },
+    ExchangeRecovered {
+        exchange: Exchange,
+    },
+    LargeArbFill {
+        exchange: Exchange,
+        direction: Direction,
+        amount: Decimal,
+        value: Decimal,
+    },
+    DailyPnlBelow {
+        pnl: Decimal,
+        threshold: Decimal,
+    },
+    DailyPnlAbove {
+        pnl: Decimal,
+        threshold: Decimal,
+    },
+}
+
+impl Alert {
+    pub const KINDS: &'static [&'static str] = &[
+        "order_rejected",
+        "risk_limit",
+        "daily_target_reached",
+        "component_failed",
+        "exchange_stale",
+        "exchange_recovered",
+        "large_arb_fill",
+        "daily_pnl_below",
+        "daily_pnl_above",
+    ];
+
+    // The rule the alert belongs to, used to mute whole kinds of alert
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Alert::OrderRejected { .. } => "order_rejected",
+            Alert::RiskLimit { .. } => "risk_limit",
+            Alert::DailyTargetReached { .. } => "daily_target_reached",
+            Alert::ComponentFailed { .. } => "component_failed",
+            Alert::ExchangeStale { .. } => "exchange_stale",
+            Alert::ExchangeRecovered { .. } => "exchange_recovered",
+            Alert::LargeArbFill { .. } => "large_arb_fill",
+            Alert::DailyPnlBelow { .. } => "daily_pnl_below",
+            Alert::DailyPnlAbove { .. } => "daily_pnl_above",
+        }
+    }
+
+    // Alerts with the same key are repeats of each other, whatever their details
+    pub fn key(&self) -> String {
+        match self {
+            Alert::OrderRejected {
+                exchange, strategy, ..
+            }
+            | Alert::RiskLimit {
+                exchange, strategy, ..
+            } => format!(
+                "{}:{}:{}",
+                self.kind(),
+                exchange.as_str(),
+                strategy.as_str()
+            ),
+            Alert::DailyTargetReached { strategy, .. } => {
+                format!("{}:{}", self.kind(), strategy.as_str())
+            }
+            Alert::ComponentFailed { component, .. } => format!("{}:{component}", self.kind()),
+            Alert::ExchangeStale { exchange, .. }
+            | Alert::ExchangeRecovered { exchange }
+            | Alert::LargeArbFill { exchange, .. } => {
+                format!("{}:{}", self.kind(), exchange.as_str())
+            }
+            Alert::DailyPnlBelow { .. } | Alert::DailyPnlAbove { .. } => self.kind().to_string(),
+        }
+    }
+}
+
+impl Display for Alert {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Alert::OrderRejected {
+                exchange,
+                strategy,
+                reason,
+            } => write!(
+                f,
+                "Order rejected on {exchange:?} for {strategy:?}: {reason}"
+            ),
+            Alert::RiskLimit {
+                exchange,
+                strategy,
+                reason,
+            } => write!(
+                f,
+                "Risk limit tripped on {exchange:?} for {strategy:?}: {reason}"
+            ),
+            Alert::DailyTargetReached { strategy, amount } => {
+                write!(f, "{strategy:?} reached its daily target of {amount}")
+            }
+            Alert::ComponentFailed {
+                component,
+                error,
+                critical: false,
+            } => write!(f, "{component} failed, restarting: {error}"),
+            Alert::ComponentFailed {
+                component,
+                error,
+                critical: true,
+            } => write!(f, "{component} failed, shutting down: {error}"),
+            Alert::ExchangeStale { exchange, age } => {
+                write!(f, "No {exchange:?} orderbook update for {}s", age.as_secs())
+            }
+            Alert::ExchangeRecovered { exchange } => {
+                write!(f, "{exchange:?} orderbook updates resumed")
+            }
+            Alert::LargeArbFill {
+                exchange,
+                direction,
+                amount,
+                value,
+            } => write!(
+                f,
+                "Arb filled on {exchange:?}: {direction:?} {amount} CHAT for {value} USDT"
+            ),
+            Alert::DailyPnlBelow { pnl, threshold } => {
+                write!(f, "Daily PnL of {pnl} USDT fell below {threshold}")
+            }
+            Alert::DailyPnlAbove { pnl, threshold } => {
+                write!(f, "Daily PnL of {pnl} USDT rose above {threshold}")
+            }
+        }
+    }
+}
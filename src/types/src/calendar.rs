@@ -0,0 +1,305 @@
+use serde::Deserialize;
+use std::str::FromStr;
+use std::time::Duration;
+
+const ONE_MINUTE_MS: u64 = 60 * 1000;
+const ONE_DAY_MS: u64 = 24 * 60 * ONE_MINUTE_MS;
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+// Restricts trading to recurring weekly windows, less any one-off exclusions such as announced
+// exchange maintenance. All times are UTC.
+#[derive(Clone, Debug, Default)]
+pub struct TradingCalendar {
+    // Trading is allowed at any time if no windows are configured
+    windows: Vec<TradingWindow>,
+    exclusions: Vec<Exclusion>,
+    was_open: Option<bool>,
+}
+
+// Eg. "mon-fri 08:00-20:00", "sat,sun 10:00-14:00" or "daily 22:00-02:00", where a window which
+// ends before it starts runs past midnight into the following day
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TradingWindow {
+    // Indexed from Monday
+    days: [bool; 7],
+    start_minute: u64,
+    end_minute: u64,
+}
+
+// Eg. "2024-08-14T02:00/2024-08-14T04:30"
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Exclusion {
+    start_ms: u64,
+    end_ms: u64,
+}
+
+// Eg. "08:30", in UTC
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay {
+    minute: u64,
+}
+
+impl TradingCalendar {
+    pub fn new() -> TradingCalendar {
+        TradingCalendar::default()
+    }
+
+    pub fn with_window(mut self, window: TradingWindow) -> Self {
+        self.windows.push(window);
+        self
+    }
+
+    pub fn with_exclusion(mut self, exclusion: Exclusion) -> Self {
+        self.exclusions.push(exclusion);
+        self
+    }
+
+    pub fn is_open(&self, now_ms: u64) -> bool {
+        let in_window = self.windows.is_empty() || self.windows.iter().any(|w| w.contains(now_ms));
+        let excluded = self
+            .exclusions
+            .iter()
+            .any(|e| e.start_ms <= now_ms && now_ms < e.end_ms);
+        in_window && !excluded
+    }
+
+    // Returns the new state if the calendar has opened or closed since the previous call, so
+    // callers can pause and resume at window boundaries. The first call always reports the state.
+    pub fn transition(&mut self, now_ms: u64) -> Option<bool> {
+        let is_open = self.is_open(now_ms);
+        if self.was_open == Some(is_open) {
+            return None;
+        }
+        self.was_open = Some(is_open);
+        Some(is_open)
+    }
+}
+
+impl TradingWindow {
+    fn contains(&self, now_ms: u64) -> bool {
+        let day = (now_ms / ONE_DAY_MS) as usize;
+        let minute = now_ms % ONE_DAY_MS / ONE_MINUTE_MS;
+
+        if self.start_minute <= self.end_minute {
+            self.days[weekday(day)] && self.start_minute <= minute && minute < self.end_minute
+        } else {
+            // Overnight windows belong to the day they start on
+            (self.days[weekday(day)] && minute >= self.start_minute)
+                || (self.days[weekday(day + 6)] && minute < self.end_minute)
+        }
+    }
+}
+
+impl FromStr for TradingWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Invalid trading window: {s}");
+
+        let (days, times) = s.trim().split_once(' ').ok_or_else(error)?;
+        let (start, end) = times.trim().split_once('-').ok_or_else(error)?;
+
+        Ok(TradingWindow {
+            days: parse_days(days).ok_or_else(error)?,
+            start_minute: parse_time(start).ok_or_else(error)?,
+            end_minute: parse_time(end).ok_or_else(error)?,
+        })
+    }
+}
+
+impl TryFrom<String> for TradingWindow {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl TimeOfDay {
+    pub fn since_midnight(&self) -> Duration {
+        Duration::from_millis(self.minute * ONE_MINUTE_MS)
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_time(s) {
+            Some(minute) if minute < 24 * 60 => Ok(TimeOfDay { minute }),
+            _ => Err(format!("Invalid time of day: {s}")),
+        }
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FromStr for Exclusion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Invalid exclusion: {s}");
+
+        let (start, end) = s.trim().split_once('/').ok_or_else(error)?;
+        let start_ms = parse_date_time(start).ok_or_else(error)?;
+        let end_ms = parse_date_time(end).ok_or_else(error)?;
+        if end_ms <= start_ms {
+            return Err(error());
+        }
+
+        Ok(Exclusion { start_ms, end_ms })
+    }
+}
+
+impl TryFrom<String> for Exclusion {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// The unix epoch was a Thursday
+fn weekday(day: usize) -> usize {
+    (day + 3) % 7
+}
+
+fn parse_days(s: &str) -> Option<[bool; 7]> {
+    let mut days = [false; 7];
+    if s.eq_ignore_ascii_case("daily") {
+        return Some([true; 7]);
+    }
+    for part in s.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+            None => (parse_day(part)?, parse_day(part)?),
+        };
+        if first > last {
+            return None;
+        }
+        days[first..=last].iter_mut().for_each(|d| *d = true);
+    }
+    Some(days)
+}
+
+fn parse_day(s: &str) -> Option<usize> {
+    DAY_NAMES
+        .iter()
+        .position(|d| d.eq_ignore_ascii_case(s.trim()))
+}
+
+// Minutes since midnight from "HH:MM", where "24:00" can be used for the end of the day
+fn parse_time(s: &str) -> Option<u64> {
+    let (hours, minutes) = s.trim().split_once(':')?;
+    let hours: u64 = hours.parse().ok()?;
+    let minutes: u64 = minutes.parse().ok()?;
+    if minutes >= 60 || hours * 60 + minutes > 24 * 60 {
+        return None;
+    }
+    Some(hours * 60 + minutes)
+}
+
+// Milliseconds since the unix epoch from "YYYY-MM-DDTHH:MM"
+fn parse_date_time(s: &str) -> Option<u64> {
+    let (date, time) = s.trim().split_once('T')?;
+    let mut parts = date.split('-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let minutes = parse_time(time)?;
+    u64::try_from(days)
+        .ok()
+        .map(|d| d * ONE_DAY_MS + minutes * ONE_MINUTE_MS)
+}
+
+// Days since the unix epoch for a date in the proleptic Gregorian calendar
+// (http://howardhinnant.github.io/date_algorithms.html#days_from_civil)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    // Thursday 2024-08-15 00:00 UTC
+    const THURSDAY: u64 = 1_723_680_000_000;
+    const SATURDAY: u64 = THURSDAY + 2 * ONE_DAY_MS;
+    const HOUR: u64 = 60 * ONE_MINUTE_MS;
+
+    #[test_case("mon-fri 08:00-20:00", THURSDAY + 8 * HOUR, true; "weekday window start inclusive")]
+    #[test_case("mon-fri 08:00-20:00", THURSDAY + 20 * HOUR, false; "weekday window end exclusive")]
+    #[test_case("mon-fri 08:00-20:00", SATURDAY + 12 * HOUR, false; "weekend excluded")]
+    #[test_case("sat,sun 00:00-24:00", SATURDAY + 23 * HOUR, true; "whole day")]
+    #[test_case("fri 22:00-02:00", SATURDAY + HOUR, true; "overnight window continues into next day")]
+    #[test_case("fri 22:00-02:00", THURSDAY + HOUR, false; "overnight window belongs to start day")]
+    fn window_tests(window: &str, now_ms: u64, expected: bool) {
+        let calendar = TradingCalendar::new().with_window(window.parse().unwrap());
+
+        assert_eq!(calendar.is_open(now_ms), expected);
+    }
+
+    #[test_case("weekdays 08:00-20:00"; "unknown day")]
+    #[test_case("mon-fri 08:00"; "missing end")]
+    #[test_case("mon-fri 08:60-20:00"; "invalid minutes")]
+    #[test_case("fri-mon 08:00-20:00"; "reversed day range")]
+    fn invalid_window_tests(window: &str) {
+        assert!(window.parse::<TradingWindow>().is_err());
+    }
+
+    #[test_case("08:30", Some(8 * HOUR + 30 * ONE_MINUTE_MS))]
+    #[test_case("00:00", Some(0))]
+    #[test_case("24:00", None)]
+    #[test_case("8", None)]
+    fn time_of_day_tests(time: &str, expected_ms: Option<u64>) {
+        let result = time.parse::<TimeOfDay>().ok();
+
+        assert_eq!(
+            result.map(|t| t.since_midnight()),
+            expected_ms.map(Duration::from_millis)
+        );
+    }
+
+    #[test]
+    fn exclusions_close_the_calendar() {
+        let mut calendar = TradingCalendar::new()
+            .with_window("daily 00:00-24:00".parse().unwrap())
+            .with_exclusion("2024-08-15T02:00/2024-08-15T04:30".parse().unwrap());
+
+        assert_eq!(calendar.transition(THURSDAY + HOUR), Some(true));
+        assert_eq!(calendar.transition(THURSDAY + 2 * HOUR), Some(false));
+        assert_eq!(calendar.transition(THURSDAY + 4 * HOUR), None);
+        assert_eq!(
+            calendar.transition(THURSDAY + 4 * HOUR + 30 * ONE_MINUTE_MS),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn invalid_exclusion() {
+        assert!("2024-08-15T04:00/2024-08-15T02:00"
+            .parse::<Exclusion>()
+            .is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use crate::metrics::LATENCY;
+use std::time::{Duration, Instant};
+use tracing::{info_span, Span};
+
+// The stages between a depth frame arriving and the order it triggers being sent, in order
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Stage {
+    // Parsing the frame into an orderbook state
+    Decode,
+    // Waiting in the broadcast channel until a processor picks the state up
+    Broadcast,
+    // Deciding to trade and creating the order
+    Detect,
+    // Waiting in the order channel until the executor sends the order
+    Dispatch,
+    // The round trip of the request submitting the order
+    Http,
+}
+
+const STAGE_COUNT: usize = 5;
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Decode => "decode",
+            Stage::Broadcast => "broadcast",
+            Stage::Detect => "detect",
+            Stage::Dispatch => "dispatch",
+            Stage::Http => "http",
+        }
+    }
+}
+
+// Carried from the websocket frame through the orderbook state, arb and order it leads to, timing
+// each stage as it completes. Orders which weren't triggered by a book update, such as scheduled
+// cashout iterations, have an empty trace and record nothing.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LatencyTrace {
+    started: Option<Instant>,
+    last: Option<Instant>,
+    durations: [Option<Duration>; STAGE_COUNT],
+}
+
+impl LatencyTrace {
+    // Starts timing from when the frame was received
+    pub fn start() -> LatencyTrace {
+        let now = Instant::now();
+        LatencyTrace {
+            started: Some(now),
+            last: Some(now),
+            durations: Default::default(),
+        }
+    }
+
+    // Records the time since the previous stage completed
+    pub fn mark(&mut self, stage: Stage) {
+        let Some(last) = self.last else {
+            return;
+        };
+        let now = Instant::now();
+        let duration = now - last;
+        self.durations[stage as usize] = Some(duration);
+        self.last = Some(now);
+        LATENCY
+            .with_label_values(&[stage.as_str()])
+            .observe(duration.as_secs_f64());
+
+        if stage == Stage::Http {
+            if let Some(started) = self.started {
+                LATENCY
+                    .with_label_values(&["total"])
+                    .observe((now - started).as_secs_f64());
+            }
+        }
+    }
+
+    pub fn duration(&self, stage: Stage) -> Option<Duration> {
+        self.durations[stage as usize]
+    }
+
+    // A span carrying the stage latencies so far in microseconds, so they appear on every event
+    // logged while submitting the order
+    pub fn span(&self) -> Span {
+        let micros = |stage| self.duration(stage).map(|d| d.as_micros() as u64);
+        info_span!(
+            "latency",
+            decode_us = micros(Stage::Decode),
+            broadcast_us = micros(Stage::Broadcast),
+            detect_us = micros(Stage::Detect),
+            dispatch_us = micros(Stage::Dispatch),
+            http_us = tracing::field::Empty,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_stages_in_order() {
+        let mut trace = LatencyTrace::start();
+        trace.mark(Stage::Decode);
+        trace.mark(Stage::Broadcast);
+
+        assert!(trace.duration(Stage::Decode).is_some());
+        assert!(trace.duration(Stage::Broadcast).is_some());
+        assert!(trace.duration(Stage::Detect).is_none());
+    }
+
+    #[test]
+    fn empty_trace_records_nothing() {
+        let mut trace = LatencyTrace::default();
+        trace.mark(Stage::Decode);
+
+        assert!(trace.duration(Stage::Decode).is_none());
+    }
+}
@@ -1,15 +1,87 @@
 use async_trait::async_trait;
 use rust_decimal::Decimal;
-use std::collections::BTreeMap;
-use std::sync::Arc;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{self, Debug, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
 use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 
-#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
-pub enum Exchange {
-    LBank,
-    Bitrue,
+mod alert;
+mod calendar;
+mod latency;
+pub mod metrics;
+mod reconnect;
+mod registry;
+
+pub use alert::Alert;
+pub use calendar::{Exclusion, TimeOfDay, TradingCalendar, TradingWindow};
+pub use latency::{LatencyTrace, Stage};
+pub use reconnect::ReconnectPolicy;
+pub use registry::{ExchangeRegistration, ExchangeRegistry, SubscriberFuture};
+
+// An exchange, identified by the name it is configured under, eg. "lbank". Each exchange crate
+// defines its own, so adding one doesn't need any changes here.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct Exchange(&'static str);
+
+impl Exchange {
+    pub const fn new(name: &'static str) -> Exchange {
+        Exchange(name)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl Debug for Exchange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+// Names are case insensitive, as older recordings have them capitalised. Whether an exchange is
+// supported is up to the registry.
+impl FromStr for Exchange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_lowercase();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("Invalid exchange: {s}"));
+        }
+        Ok(Exchange(intern(name)))
+    }
+}
+
+impl Serialize for Exchange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Exchange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(de::Error::custom)
+    }
+}
+
+// Names read at runtime are leaked, once each, so that exchanges stay Copy
+fn intern(name: String) -> &'static str {
+    static NAMES: LazyLock<Mutex<BTreeSet<&'static str>>> = LazyLock::new(Default::default);
+    let mut names = NAMES.lock().unwrap();
+    if let Some(interned) = names.get(name.as_str()) {
+        return interned;
+    }
+    let interned = Box::leak(name.into_boxed_str());
+    names.insert(interned);
+    interned
 }
 
 #[async_trait]
@@ -22,8 +94,28 @@ pub trait ExchangeSubscriber {
 }
 
 #[async_trait]
-pub trait ExchangeOrderExecutor: Send {
+pub trait ExchangeOrderExecutor: Send + Sync {
     async fn submit_order(&self, order: PendingOrder) -> Result<String, String>;
+
+    async fn cancel_order(&self, order_id: &str) -> Result<(), String>;
+
+    async fn get_order(&self, order_id: &str) -> Result<OrderStatus, String>;
+
+    // The free and locked amounts of each asset held on the exchange, keyed by asset
+    async fn get_balances(&self) -> Result<HashMap<String, Balance>, String> {
+        Err("Balances are not supported".to_string())
+    }
+
+    // Every order of ours resting on the book, including those placed outside of this service
+    async fn get_open_orders(&self) -> Result<Vec<OpenOrder>, String> {
+        Err("Listing open orders is not supported".to_string())
+    }
+
+    // Returns an error describing why the order can't be placed if the exchange has no native
+    // support for any of the options specified on the order
+    fn check_order_supported(&self, _order: &PendingOrder) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 pub trait OrderbookStateProcessor {
@@ -34,12 +126,18 @@ pub trait OrderbookStateProcessor {
     ) -> JoinHandle<()>;
 }
 
-#[derive(Clone, Debug)]
+// Serialized when recording the books, without the trace as replayed states start a fresh one
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OrderbookState {
     pub exchange: Exchange,
     pub timestamp_ms: u64,
     pub asks: BTreeMap<Decimal, Decimal>,
     pub bids: BTreeMap<Decimal, Decimal>,
+    // Set when the exchange's book can no longer be trusted, the levels are then empty
+    #[serde(default)]
+    pub invalidated: bool,
+    #[serde(skip)]
+    pub trace: LatencyTrace,
 }
 
 impl OrderbookState {
@@ -58,12 +156,30 @@ impl OrderbookState {
             amount: *a,
         })
     }
+
+    pub fn mid_price(&self) -> Option<Decimal> {
+        Some((self.best_bid()?.price + self.best_ask()?.price) / Decimal::TWO)
+    }
+
+    // Published when the exchange's book can no longer be trusted, eg. once its websocket has
+    // disconnected, so that what came before isn't mixed with the next snapshot
+    pub fn invalidated(exchange: Exchange, timestamp_ms: u64) -> OrderbookState {
+        OrderbookState {
+            exchange,
+            timestamp_ms,
+            asks: BTreeMap::new(),
+            bids: BTreeMap::new(),
+            invalidated: true,
+            trace: LatencyTrace::default(),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct ArbOpportunity {
     pub buy: Order,
     pub sell: Order,
+    pub trace: LatencyTrace,
 }
 
 #[derive(Clone, Debug)]
@@ -73,6 +189,35 @@ pub struct Order {
     pub amount: Decimal,
 }
 
+#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize)]
+pub enum Strategy {
+    ArbFinder,
+    Cashout,
+    Accumulate,
+    // Orders placed by hand from the command line
+    Manual,
+}
+
+impl Strategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Strategy::ArbFinder => "arb_finder",
+            Strategy::Cashout => "cashout",
+            Strategy::Accumulate => "accumulate",
+            Strategy::Manual => "manual",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum OrderRequest {
+    Submit(PendingOrder),
+    Cancel(CancelOrder),
+    Replace(ReplaceOrder),
+    // Cancels every order resting on the book, whichever strategy placed it
+    CancelAll,
+}
+
 #[derive(Clone, Debug)]
 pub enum PendingOrder {
     Limit(PendingLimitOrder),
@@ -80,6 +225,20 @@ pub enum PendingOrder {
 }
 
 impl PendingOrder {
+    pub fn id(&self) -> u64 {
+        match self {
+            PendingOrder::Limit(o) => o.id,
+            PendingOrder::Market(o) => o.id,
+        }
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        match self {
+            PendingOrder::Limit(o) => o.strategy,
+            PendingOrder::Market(o) => o.strategy,
+        }
+    }
+
     pub fn exchange(&self) -> Exchange {
         match self {
             PendingOrder::Limit(o) => o.exchange,
@@ -100,25 +259,103 @@ impl PendingOrder {
             PendingOrder::Market(o) => o.amount,
         }
     }
+
+    pub fn trace(&self) -> LatencyTrace {
+        match self {
+            PendingOrder::Limit(o) => o.trace,
+            PendingOrder::Market(o) => o.trace,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct PendingLimitOrder {
+    // Identifies the order within the strategy, allowing it to later be cancelled or replaced
+    pub id: u64,
+    pub strategy: Strategy,
     pub exchange: Exchange,
     pub direction: Direction,
     pub amount: Decimal,
     pub price: Decimal,
+    pub time_in_force: TimeInForce,
+    // If set, the order must only ever add liquidity to the book
+    pub post_only: bool,
+    pub trace: LatencyTrace,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum TimeInForce {
+    #[default]
+    GoodTillCancelled,
+    ImmediateOrCancel,
+    FillOrKill,
 }
 
 #[derive(Clone, Debug)]
 pub struct PendingMarketOrder {
+    pub id: u64,
+    pub strategy: Strategy,
     pub exchange: Exchange,
     pub direction: Direction,
     pub amount: Decimal,
     pub expected_return: Decimal,
+    pub trace: LatencyTrace,
+}
+
+#[derive(Clone, Debug)]
+pub struct OrderStatus {
+    pub filled_amount: Decimal,
+    // The total value of the fills in the quote currency
+    pub filled_value: Decimal,
+    pub is_open: bool,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Balance {
+    pub free: Decimal,
+    pub locked: Decimal,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OpenOrder {
+    pub exchange_order_id: String,
+    pub direction: Direction,
+    pub price: Decimal,
+    pub amount: Decimal,
+    pub filled_amount: Decimal,
+}
+
+// Published by the order executor as orders are filled or leave the book
+#[derive(Clone, Debug, Serialize)]
+pub struct OrderUpdate {
+    pub strategy: Strategy,
+    pub id: u64,
+    pub exchange: Exchange,
+    pub direction: Direction,
+    // The amount and value filled since the previous update for this order
+    pub filled_amount: Decimal,
+    pub filled_value: Decimal,
+    // False once the order is no longer on the book, either because it was filled or cancelled
+    pub is_open: bool,
+    // Set when the order has closed but its fills couldn't be fetched, the amounts are then zero
+    pub fills_unknown: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct CancelOrder {
+    pub strategy: Strategy,
+    pub id: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplaceOrder {
+    pub strategy: Strategy,
+    pub id: u64,
+    pub amount: Decimal,
+    pub price: Decimal,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
 pub enum Direction {
     Buy,
     Sell,
@@ -129,3 +366,50 @@ impl Direction {
         matches!(self, Direction::Buy)
     }
 }
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "buy" => Ok(Direction::Buy),
+            "sell" => Ok(Direction::Sell),
+            _ => Err(format!("Unknown direction: {s}")),
+        }
+    }
+}
+
+// A flag shared between a component and whatever controls it, such as the admin API. Paused
+// processors stop trading but keep tracking the books, a paused order executor rejects new orders.
+#[derive(Clone, Debug, Default)]
+pub struct PauseSwitch(Arc<AtomicBool>);
+
+impl PauseSwitch {
+    pub fn new() -> PauseSwitch {
+        PauseSwitch::default()
+    }
+
+    pub fn pause(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Waits for the next value published on an optional watch channel, used to deliver reloaded
+// parameters. Never completes if there is no channel or once its sender has been dropped.
+pub async fn next_parameters<T: Clone>(receiver: &mut Option<watch::Receiver<T>>) -> T {
+    if let Some(r) = receiver {
+        if r.changed().await.is_ok() {
+            return r.borrow_and_update().clone();
+        }
+    }
+    *receiver = None;
+    std::future::pending().await
+}
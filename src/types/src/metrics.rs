@@ -0,0 +1,189 @@
+use prometheus::{
+    register_counter_vec, register_gauge_vec, register_histogram_vec, register_int_counter_vec,
+    CounterVec, GaugeVec, HistogramVec, IntCounterVec, TextEncoder,
+};
+use std::sync::LazyLock;
+
+// The metrics shared by every component. Each is registered with the default prometheus registry
+// the first time it is used, and exported in text format by the app's metrics endpoint.
+
+pub static WEBSOCKET_MESSAGES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "xb_websocket_messages_total",
+        "Websocket messages received",
+        &["exchange"]
+    )
+    .unwrap()
+});
+
+pub static WEBSOCKET_RECONNECTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "xb_websocket_reconnects_total",
+        "Websocket disconnections and failed connection attempts, each followed by a reconnect",
+        &["exchange"]
+    )
+    .unwrap()
+});
+
+pub static MARKET_DATA_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "xb_market_data_errors_total",
+        "Websocket messages which couldn't be decoded or weren't recognised, by the kind of error",
+        &["exchange", "kind"]
+    )
+    .unwrap()
+});
+
+pub static EXCHANGE_UP: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "xb_exchange_up",
+        "1 while the exchange's websocket is delivering books, 0 once reconnecting has failed too many times in a row",
+        &["exchange"]
+    )
+    .unwrap()
+});
+
+pub static BOOK_AGE: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "xb_book_age_seconds",
+        "Time since the last orderbook update was received",
+        &["exchange"]
+    )
+    .unwrap()
+});
+
+pub static BROADCAST_LAGGED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "xb_broadcast_lagged_total",
+        "Messages dropped because a receiver fell too far behind its broadcast channel",
+        &["receiver"]
+    )
+    .unwrap()
+});
+
+pub static ARBS_DETECTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "xb_arbs_detected_total",
+        "Arbitrage opportunities found",
+        &["buy_exchange", "sell_exchange"]
+    )
+    .unwrap()
+});
+
+pub static ARB_THEORETICAL_PROFIT: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "xb_arb_theoretical_profit_total",
+        "Profit in USDT of the arbitrage opportunities found, had both legs filled in full",
+        &["buy_exchange", "sell_exchange"]
+    )
+    .unwrap()
+});
+
+pub static ORDERS_SUBMITTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "xb_orders_submitted_total",
+        "Orders accepted by the exchange",
+        &["exchange", "strategy"]
+    )
+    .unwrap()
+});
+
+pub static ORDERS_REJECTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "xb_orders_rejected_total",
+        "Orders rejected by the order executor or the exchange",
+        &["exchange", "strategy"]
+    )
+    .unwrap()
+});
+
+pub static ORDERS_FILLED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "xb_orders_filled_total",
+        "Fills, counting each partial fill separately",
+        &["exchange", "strategy"]
+    )
+    .unwrap()
+});
+
+pub static FILLED_AMOUNT: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "xb_filled_amount_total",
+        "Amount of CHAT filled",
+        &["exchange", "strategy"]
+    )
+    .unwrap()
+});
+
+pub static SLIPPAGE: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "xb_slippage_bps",
+        "How much worse than expected each filled market order executed, negative when it did better",
+        &["exchange", "strategy"],
+        vec![-100.0, -50.0, -25.0, -10.0, -5.0, 0.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0]
+    )
+    .unwrap()
+});
+
+pub static REST_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "xb_rest_request_duration_seconds",
+        "Round trip time of REST requests to the exchanges, including reading the response",
+        &["exchange", "path"],
+        vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+    )
+    .unwrap()
+});
+
+pub static LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "xb_latency_seconds",
+        "Time spent in each stage from receiving a depth frame to submitting the order it triggers",
+        &["stage"],
+        vec![0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+    )
+    .unwrap()
+});
+
+pub static COMPONENT_RESTARTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "xb_component_restarts_total",
+        "Components restarted by the supervisor after failing",
+        &["component"]
+    )
+    .unwrap()
+});
+
+pub static CASHOUT_TRADED_TODAY: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "xb_cashout_traded_today",
+        "Amount traded today towards the daily target, in the units of the target",
+        &["strategy"]
+    )
+    .unwrap()
+});
+
+pub static CASHOUT_REMAINING_TODAY: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "xb_cashout_remaining_today",
+        "Amount left to trade today, in the units of the target",
+        &["strategy"]
+    )
+    .unwrap()
+});
+
+pub static TRADING_WINDOW_OPEN: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "xb_trading_window_open",
+        "1 while the strategy's trading calendar is open, 0 while it is closed",
+        &["strategy"]
+    )
+    .unwrap()
+});
+
+// All registered metrics in the prometheus text format
+pub fn encode() -> String {
+    TextEncoder::new()
+        .encode_to_string(&prometheus::gather())
+        .unwrap_or_default()
+}
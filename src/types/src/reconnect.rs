@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+// How an exchange's websocket is reconnected. The delay doubles with each consecutive failure up
+// to the maximum, with up to half of it randomised so that reconnects don't all land at once.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    // Consecutive failures after which the exchange is marked as down
+    pub circuit_threshold: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+            circuit_threshold: 10,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    // The delay after the given number of consecutive failures, where jitter is between 0 and 1
+    pub fn delay(&self, failures: u32, jitter: f64) -> Duration {
+        let exponent = failures.saturating_sub(1).min(31);
+        let delay = self
+            .initial_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        delay.mul_f64(1.0 - jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(1, 0.0, 500)]
+    #[test_case(2, 0.0, 1000)]
+    #[test_case(4, 0.0, 4000)]
+    #[test_case(4, 1.0, 2000)]
+    #[test_case(8, 0.0, 60_000; "capped")]
+    #[test_case(100, 0.5, 45_000; "capped with jitter")]
+    fn backs_off(failures: u32, jitter: f64, expected_ms: u64) {
+        let policy = ReconnectPolicy::default();
+
+        assert_eq!(
+            policy.delay(failures, jitter),
+            Duration::from_millis(expected_ms)
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{Exchange, ExchangeOrderExecutor, OrderbookState, ReconnectPolicy};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::broadcast::Sender;
+use tokio_util::sync::CancellationToken;
+
+pub type SubscriberFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// Everything the service needs to subscribe to an exchange's book and trade on it. Each exchange
+// crate provides its own, so nothing outside the app needs to know which exchanges exist.
+#[derive(Copy, Clone)]
+pub struct ExchangeRegistration {
+    pub exchange: Exchange,
+    // The market traded, as the exchange names it
+    pub symbol: &'static str,
+    // The name the subscriber is supervised under
+    pub subscriber_name: &'static str,
+    // Whether limit orders can be made post-only, so they never take liquidity
+    pub supports_post_only: bool,
+    // Publishes the exchange's book until cancelled
+    pub subscriber:
+        fn(ReconnectPolicy, Sender<Arc<OrderbookState>>, CancellationToken) -> SubscriberFuture,
+    // Creates a client from an api key and secret key
+    pub client: fn(String, String) -> Box<dyn ExchangeOrderExecutor>,
+}
+
+#[derive(Clone, Default)]
+pub struct ExchangeRegistry {
+    registrations: BTreeMap<Exchange, ExchangeRegistration>,
+}
+
+impl ExchangeRegistry {
+    pub fn new() -> ExchangeRegistry {
+        ExchangeRegistry::default()
+    }
+
+    pub fn with_exchange(mut self, registration: ExchangeRegistration) -> Self {
+        self.registrations
+            .insert(registration.exchange, registration);
+        self
+    }
+
+    pub fn get(&self, exchange: Exchange) -> Result<&ExchangeRegistration, String> {
+        self.registrations
+            .get(&exchange)
+            .ok_or_else(|| format!("{exchange:?} is not a supported exchange"))
+    }
+
+    pub fn exchanges(&self) -> impl Iterator<Item = Exchange> + '_ {
+        self.registrations.keys().copied()
+    }
+
+    pub fn registrations(&self) -> impl Iterator<Item = &ExchangeRegistration> {
+        self.registrations.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OrderStatus, PendingOrder};
+    use async_trait::async_trait;
+
+    const BITRUE: Exchange = Exchange::new("bitrue");
+    const LBANK: Exchange = Exchange::new("lbank");
+
+    struct NoopClient;
+
+    #[async_trait]
+    impl ExchangeOrderExecutor for NoopClient {
+        async fn submit_order(&self, _order: PendingOrder) -> Result<String, String> {
+            Ok("1".to_string())
+        }
+
+        async fn cancel_order(&self, _order_id: &str) -> Result<(), String> {
+            Ok(())
+        }
+
+        async fn get_order(&self, _order_id: &str) -> Result<OrderStatus, String> {
+            Err("Not found".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn builds_registered_exchanges() {
+        let registry = ExchangeRegistry::new().with_exchange(ExchangeRegistration {
+            exchange: LBANK,
+            symbol: "chat_usdt",
+            subscriber_name: "lbank_subscriber",
+            supports_post_only: true,
+            subscriber: |_, _, token| Box::pin(async move { token.cancelled().await }),
+            client: |_, _| Box::new(NoopClient),
+        });
+
+        assert_eq!(registry.exchanges().collect::<Vec<_>>(), vec![LBANK]);
+        assert_eq!(
+            registry.get(BITRUE).err(),
+            Some("bitrue is not a supported exchange".to_string())
+        );
+
+        let registration = registry.get(LBANK).unwrap();
+        let client = (registration.client)("key".to_string(), "secret".to_string());
+        assert_eq!(client.cancel_order("1").await, Ok(()));
+
+        let (sender, _) = tokio::sync::broadcast::channel(1);
+        let token = CancellationToken::new();
+        let subscriber = tokio::spawn((registration.subscriber)(
+            ReconnectPolicy::default(),
+            sender,
+            token.clone(),
+        ));
+        token.cancel();
+        subscriber.await.unwrap();
+    }
+
+    #[test]
+    fn parses_exchange_names() {
+        assert_eq!("LBank".parse(), Ok(LBANK));
+        assert_eq!(" bitrue ".parse::<Exchange>().unwrap().as_str(), "bitrue");
+        assert_eq!(
+            "lbank spot".parse::<Exchange>(),
+            Err("Invalid exchange: lbank spot".to_string())
+        );
+    }
+}